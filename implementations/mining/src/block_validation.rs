@@ -0,0 +1,799 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use bitcoincore_rpc::bitcoin::{
+    Amount, Block, BlockHash, OutPoint, Script, ScriptBuf, Target, Transaction, TxMerkleNode,
+    TxOut, Txid, Wtxid,
+    script::{Builder, Instruction, read_scriptint},
+};
+
+use crate::{
+    MAX_BLOCK_WEIGHT, MAX_COINBASE_SCRIPT_SIZE, MIN_COINBASE_SCRIPT_SIZE, MempoolTransaction,
+    WITNESS_COMMITMENT_HEADER, block_subsidy, calculate_witness_commitment,
+    difficulty::Network,
+    interpreter::{self, ScriptError},
+    merkle,
+    sigops::{self, MAX_BLOCK_SIGOPS_COST},
+};
+
+// A consensus rule broken by a block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockViolation {
+    // The header hash does not meet the target encoded in its bits
+    HighHash {
+        hash: BlockHash,
+        target: Target,
+    },
+    // Below the version the soft forks active at the block's height require
+    BadVersion {
+        version: i32,
+        required: i32,
+    },
+    NoTransactions,
+    BadMerkleRoot {
+        header: TxMerkleNode,
+        computed: TxMerkleNode,
+    },
+    // The merkle tree pairs a node with an identical sibling (CVE-2012-2459)
+    MutatedMerkleTree,
+    FirstNotCoinbase,
+    ExtraCoinbase(Txid),
+    BadCoinbaseLength(usize),
+    // The coinbase scriptSig does not start with a BIP34 height push
+    MissingHeight,
+    CoinbaseOverpays {
+        paid: u64,
+        allowed: u64,
+    },
+    // Witness data without a commitment in the coinbase
+    UnexpectedWitness(Txid),
+    // The coinbase witness is not a single 32-byte reserved value
+    BadWitnessNonce,
+    BadWitnessCommitment,
+    Overweight(u64),
+    TooManySigops(u64),
+    DuplicateTxid(Txid),
+    // Spends an output of a transaction that comes later in the block
+    OutOfOrder {
+        txid: Txid,
+        parent: Txid,
+    },
+    DoubleSpend {
+        txid: Txid,
+        outpoint: OutPoint,
+    },
+    // The spent output is neither in the block nor in the mempool set
+    MissingPrevout {
+        txid: Txid,
+        outpoint: OutPoint,
+    },
+    ValueOutOfRange(Txid),
+    InsufficientInputValue {
+        txid: Txid,
+        input_value: u64,
+        output_value: u64,
+    },
+    BadScript {
+        txid: Txid,
+        input: usize,
+        error: ScriptError,
+    },
+}
+
+impl BlockViolation {
+    // Bitcoin Core's reject reason for the rule, as returned by submitblock
+    pub fn reason(&self) -> &'static str {
+        match self {
+            BlockViolation::HighHash { .. } => "high-hash",
+            BlockViolation::BadVersion { .. } => "bad-version",
+            BlockViolation::NoTransactions => "bad-blk-length",
+            BlockViolation::BadMerkleRoot { .. } => "bad-txnmrklroot",
+            BlockViolation::MutatedMerkleTree => "bad-txns-duplicate",
+            BlockViolation::FirstNotCoinbase => "bad-cb-missing",
+            BlockViolation::ExtraCoinbase(_) => "bad-cb-multiple",
+            BlockViolation::BadCoinbaseLength(_) => "bad-cb-length",
+            BlockViolation::MissingHeight => "bad-cb-height",
+            BlockViolation::CoinbaseOverpays { .. } => "bad-cb-amount",
+            BlockViolation::UnexpectedWitness(_) => "unexpected-witness",
+            BlockViolation::BadWitnessNonce => "bad-witness-nonce-size",
+            BlockViolation::BadWitnessCommitment => "bad-witness-merkle-match",
+            BlockViolation::Overweight(_) => "bad-blk-weight",
+            BlockViolation::TooManySigops(_) => "bad-blk-sigops",
+            BlockViolation::DuplicateTxid(_) => "bad-txns-duplicate",
+            BlockViolation::OutOfOrder { .. }
+            | BlockViolation::DoubleSpend { .. }
+            | BlockViolation::MissingPrevout { .. } => "bad-txns-inputs-missingorspent",
+            BlockViolation::ValueOutOfRange(_) => "bad-txns-inputvalues-outofrange",
+            BlockViolation::InsufficientInputValue { .. } => "bad-txns-in-belowout",
+            BlockViolation::BadScript { .. } => "mandatory-script-verify-flag-failed",
+        }
+    }
+}
+
+impl fmt::Display for BlockViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockViolation::HighHash { hash, target } => {
+                write!(f, "hash {} above target {:x}", hash, target)
+            }
+            BlockViolation::BadVersion { version, required } => write!(
+                f,
+                "block version 0x{:08x} is below the required {}",
+                version, required
+            ),
+            BlockViolation::NoTransactions => write!(f, "block has no transactions"),
+            BlockViolation::BadMerkleRoot { header, computed } => write!(
+                f,
+                "merkle root mismatch: header {}, computed {}",
+                header, computed
+            ),
+            BlockViolation::MutatedMerkleTree => {
+                write!(f, "merkle tree has identical sibling hashes")
+            }
+            BlockViolation::FirstNotCoinbase => write!(f, "first transaction is not a coinbase"),
+            BlockViolation::ExtraCoinbase(txid) => write!(f, "{} is a second coinbase", txid),
+            BlockViolation::BadCoinbaseLength(len) => write!(
+                f,
+                "coinbase scriptSig is {} bytes, must be {}-{}",
+                len, MIN_COINBASE_SCRIPT_SIZE, MAX_COINBASE_SCRIPT_SIZE
+            ),
+            BlockViolation::MissingHeight => {
+                write!(f, "coinbase scriptSig does not start with the block height")
+            }
+            BlockViolation::CoinbaseOverpays { paid, allowed } => write!(
+                f,
+                "coinbase pays {} sats, more than the {} sats allowed",
+                paid, allowed
+            ),
+            BlockViolation::UnexpectedWitness(txid) => {
+                write!(
+                    f,
+                    "{} has witness data but the block has no commitment",
+                    txid
+                )
+            }
+            BlockViolation::BadWitnessNonce => {
+                write!(f, "coinbase witness is not a single 32-byte reserved value")
+            }
+            BlockViolation::BadWitnessCommitment => write!(f, "witness commitment mismatch"),
+            BlockViolation::Overweight(weight) => {
+                write!(f, "block weight {} exceeds {}", weight, MAX_BLOCK_WEIGHT)
+            }
+            BlockViolation::TooManySigops(cost) => {
+                write!(f, "sigop cost {} exceeds {}", cost, MAX_BLOCK_SIGOPS_COST)
+            }
+            BlockViolation::DuplicateTxid(txid) => write!(f, "duplicate transaction {}", txid),
+            BlockViolation::OutOfOrder { txid, parent } => {
+                write!(
+                    f,
+                    "{} spends {} which comes later in the block",
+                    txid, parent
+                )
+            }
+            BlockViolation::DoubleSpend { txid, outpoint } => {
+                write!(f, "{} spends {} which is already spent", txid, outpoint)
+            }
+            BlockViolation::MissingPrevout { txid, outpoint } => {
+                write!(f, "{} spends unknown output {}", txid, outpoint)
+            }
+            BlockViolation::ValueOutOfRange(txid) => write!(f, "{} value out of range", txid),
+            BlockViolation::InsufficientInputValue {
+                txid,
+                input_value,
+                output_value,
+            } => write!(
+                f,
+                "{} outputs ({} sats) exceed inputs ({} sats)",
+                txid, output_value, input_value
+            ),
+            BlockViolation::BadScript { txid, input, error } => {
+                write!(f, "{} script failure on input {}: {}", txid, input, error)
+            }
+        }
+    }
+}
+
+// Outputs spent by the mempool set, keyed by outpoint, from the JSON prevouts
+pub fn mempool_prevouts(txs: &[MempoolTransaction]) -> HashMap<OutPoint, TxOut> {
+    let mut prevouts = HashMap::new();
+    for vin in txs.iter().flat_map(|tx| &tx.vin) {
+        let (Ok(txid), Some(prevout)) = (vin.txid.parse::<Txid>(), &vin.prevout) else {
+            continue;
+        };
+        let Ok(script_pubkey) = ScriptBuf::from_hex(&prevout.scriptpubkey) else {
+            continue;
+        };
+        prevouts.insert(
+            OutPoint::new(txid, vin.vout),
+            TxOut {
+                value: Amount::from_sat(prevout.value),
+                script_pubkey,
+            },
+        );
+    }
+    prevouts
+}
+
+// Check a block against the consensus rules that can be evaluated without a
+// UTXO set. Outputs spent by the block come from earlier transactions in it or
+// from `prevouts`. Returns every violation found; an empty list means valid.
+pub fn validate_block(
+    block: &Block,
+    prevouts: &HashMap<OutPoint, TxOut>,
+    network: Network,
+) -> Vec<BlockViolation> {
+    let mut violations = Vec::new();
+    let header = &block.header;
+
+    if header.validate_pow(header.target()).is_err() {
+        violations.push(BlockViolation::HighHash {
+            hash: header.block_hash(),
+            target: header.target(),
+        });
+    }
+
+    let Some(coinbase_tx) = block.txdata.first() else {
+        violations.push(BlockViolation::NoTransactions);
+        return violations;
+    };
+
+    let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+    let (computed, mutated) = merkle::compute_merkle_root(&txids);
+    if computed != header.merkle_root {
+        violations.push(BlockViolation::BadMerkleRoot {
+            header: header.merkle_root,
+            computed,
+        });
+    }
+    if mutated {
+        violations.push(BlockViolation::MutatedMerkleTree);
+    }
+
+    let mut seen = HashSet::new();
+    for txid in &txids {
+        if !seen.insert(txid) {
+            violations.push(BlockViolation::DuplicateTxid(*txid));
+        }
+    }
+
+    let weight = block.weight().to_wu();
+    if weight > MAX_BLOCK_WEIGHT as u64 {
+        violations.push(BlockViolation::Overweight(weight));
+    }
+
+    let height = check_coinbase(coinbase_tx, &mut violations);
+    if let Some(height) = height {
+        let required = network.min_block_version(height);
+        if header.version.to_consensus() < required {
+            violations.push(BlockViolation::BadVersion {
+                version: header.version.to_consensus(),
+                required,
+            });
+        }
+    }
+
+    // Walk the transactions in order, making each one's outputs available to
+    // the ones after it
+    let positions: HashMap<Txid, usize> = txids
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(position, txid)| (*txid, position))
+        .collect();
+    let mut created: HashMap<OutPoint, TxOut> = HashMap::new();
+    let mut spent: HashSet<OutPoint> = HashSet::new();
+    let mut fees = Some(Amount::ZERO);
+    let mut sigop_cost = sigops::legacy_sigop_cost(coinbase_tx);
+
+    for (position, tx) in block.txdata.iter().enumerate().skip(1) {
+        let txid = txids[position];
+        if tx.is_coinbase() {
+            violations.push(BlockViolation::ExtraCoinbase(txid));
+            continue;
+        }
+
+        let mut tx_prevouts = Vec::with_capacity(tx.input.len());
+        for input in &tx.input {
+            let outpoint = input.previous_output;
+            if !spent.insert(outpoint) {
+                violations.push(BlockViolation::DoubleSpend { txid, outpoint });
+            }
+            if positions
+                .get(&outpoint.txid)
+                .is_some_and(|parent| *parent >= position)
+            {
+                violations.push(BlockViolation::OutOfOrder {
+                    txid,
+                    parent: outpoint.txid,
+                });
+                continue;
+            }
+            match created.get(&outpoint).or_else(|| prevouts.get(&outpoint)) {
+                Some(prevout) => tx_prevouts.push(prevout.clone()),
+                None => violations.push(BlockViolation::MissingPrevout { txid, outpoint }),
+            }
+        }
+
+        if tx_prevouts.len() == tx.input.len() {
+            sigop_cost += sigops::transaction_sigop_cost(tx, &tx_prevouts);
+            let fee = check_transaction_value(txid, tx, &tx_prevouts, &mut violations);
+            fees = fees
+                .zip(fee)
+                .and_then(|(total, fee)| total.checked_add(fee));
+
+            let results = interpreter::verify_transaction(tx, &tx_prevouts);
+            if let Some((input, error)) = results
+                .into_iter()
+                .enumerate()
+                .find_map(|(input, result)| result.err().map(|error| (input, error)))
+            {
+                violations.push(BlockViolation::BadScript { txid, input, error });
+            }
+        } else {
+            sigop_cost += sigops::legacy_sigop_cost(tx);
+            fees = None;
+        }
+
+        for (vout, output) in tx.output.iter().enumerate() {
+            created.insert(OutPoint::new(txid, vout as u32), output.clone());
+        }
+    }
+
+    if sigop_cost > MAX_BLOCK_SIGOPS_COST {
+        violations.push(BlockViolation::TooManySigops(sigop_cost));
+    }
+
+    // The subsidy check needs the height and every fee
+    if let (Some(height), Some(fees)) = (height, fees) {
+        let paid = coinbase_tx
+            .output
+            .iter()
+            .try_fold(Amount::ZERO, |total, output| {
+                total.checked_add(output.value)
+            });
+        let allowed = block_subsidy(network, height) + fees;
+        match paid {
+            Some(paid) if paid <= allowed => {}
+            paid => violations.push(BlockViolation::CoinbaseOverpays {
+                paid: paid.map_or(u64::MAX, |paid| paid.to_sat()),
+                allowed: allowed.to_sat(),
+            }),
+        }
+    }
+
+    check_witness_commitment(block, &txids, &mut violations);
+
+    violations
+}
+
+// Check the coinbase scriptSig, returning the BIP34 height if it has one
+fn check_coinbase(coinbase_tx: &Transaction, violations: &mut Vec<BlockViolation>) -> Option<u32> {
+    if !coinbase_tx.is_coinbase() {
+        violations.push(BlockViolation::FirstNotCoinbase);
+        return None;
+    }
+
+    let script_sig = &coinbase_tx.input[0].script_sig;
+    if !(MIN_COINBASE_SCRIPT_SIZE..=MAX_COINBASE_SCRIPT_SIZE).contains(&script_sig.len()) {
+        violations.push(BlockViolation::BadCoinbaseLength(script_sig.len()));
+    }
+
+    let height = coinbase_height(script_sig);
+    if height.is_none() {
+        violations.push(BlockViolation::MissingHeight);
+    }
+    height
+}
+
+// Decode the BIP34 height, requiring the exact serialization `CScript() << height`
+pub fn coinbase_height(script_sig: &Script) -> Option<u32> {
+    let height = match script_sig.instructions().next()?.ok()? {
+        Instruction::PushBytes(bytes) => read_scriptint(bytes.as_bytes()).ok()?,
+        Instruction::Op(opcode) => {
+            let n = opcode.to_u8().checked_sub(0x50)?;
+            if !(1..=16).contains(&n) {
+                return None;
+            }
+            n as i64
+        }
+    };
+    let height = u32::try_from(height).ok()?;
+
+    let expected = Builder::new().push_int(height as i64).into_script();
+    script_sig
+        .as_bytes()
+        .starts_with(expected.as_bytes())
+        .then_some(height)
+}
+
+// Check output values against the spent outputs, returning the fee
+fn check_transaction_value(
+    txid: Txid,
+    tx: &Transaction,
+    prevouts: &[TxOut],
+    violations: &mut Vec<BlockViolation>,
+) -> Option<Amount> {
+    let input_value = sum_values(prevouts.iter().map(|prevout| prevout.value));
+    let output_value = sum_values(tx.output.iter().map(|output| output.value));
+    let (Some(input_value), Some(output_value)) = (input_value, output_value) else {
+        violations.push(BlockViolation::ValueOutOfRange(txid));
+        return None;
+    };
+
+    if output_value > input_value {
+        violations.push(BlockViolation::InsufficientInputValue {
+            txid,
+            input_value: input_value.to_sat(),
+            output_value: output_value.to_sat(),
+        });
+        return None;
+    }
+    Some(input_value - output_value)
+}
+
+// Sum amounts, failing on overflow or a total above MAX_MONEY
+fn sum_values(mut values: impl Iterator<Item = Amount>) -> Option<Amount> {
+    values
+        .try_fold(Amount::ZERO, |total, value| total.checked_add(value))
+        .filter(|total| *total <= Amount::MAX_MONEY)
+}
+
+// BIP141: the last output matching the commitment header commits to the
+// witness root; without one no transaction may carry witness data
+fn check_witness_commitment(block: &Block, txids: &[Txid], violations: &mut Vec<BlockViolation>) {
+    let coinbase_tx = &block.txdata[0];
+    let commitment = coinbase_tx.output.iter().rev().find_map(|output| {
+        let script = output.script_pubkey.as_bytes();
+        (script.len() >= 38 && script.starts_with(&WITNESS_COMMITMENT_HEADER))
+            .then(|| <[u8; 32]>::try_from(&script[6..38]).unwrap())
+    });
+
+    let Some(commitment) = commitment else {
+        for (tx, txid) in block.txdata.iter().zip(txids) {
+            if tx.input.iter().any(|input| !input.witness.is_empty()) {
+                violations.push(BlockViolation::UnexpectedWitness(*txid));
+            }
+        }
+        return;
+    };
+
+    let witness = &coinbase_tx.input[0].witness;
+    let reserved_value = match witness.iter().next() {
+        Some(item) if witness.len() == 1 => <[u8; 32]>::try_from(item).ok(),
+        _ => None,
+    };
+    let Some(reserved_value) = reserved_value else {
+        violations.push(BlockViolation::BadWitnessNonce);
+        return;
+    };
+
+    let wtxids: Vec<Wtxid> = block.txdata[1..]
+        .iter()
+        .map(|tx| tx.compute_wtxid())
+        .collect();
+    if calculate_witness_commitment(&wtxids, &reserved_value) != commitment {
+        violations.push(BlockViolation::BadWitnessCommitment);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        CompactTarget, Sequence, TxIn, Witness, absolute::LockTime, block, hashes::Hash,
+        transaction,
+    };
+
+    use super::*;
+
+    // Regtest halves the subsidy every 150 blocks, so this pays 25 BTC
+    const HEIGHT: u32 = 200;
+
+    const CONFIRMED_VALUE: u64 = 100_000;
+
+    fn op_true() -> ScriptBuf {
+        ScriptBuf::from_bytes(vec![0x51])
+    }
+
+    fn op_true_output(value: u64) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: ScriptBuf::new_p2wsh(&op_true().wscript_hash()),
+        }
+    }
+
+    // Outputs outside the block that anyone can spend with an OP_TRUE witness
+    fn confirmed(n: u8) -> OutPoint {
+        OutPoint::new(Txid::from_byte_array([n; 32]), 0)
+    }
+
+    fn confirmed_outputs() -> HashMap<OutPoint, TxOut> {
+        (1..=10)
+            .map(|n| (confirmed(n), op_true_output(CONFIRMED_VALUE)))
+            .collect()
+    }
+
+    fn spend(inputs: &[OutPoint], outputs: &[u64]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|previous_output| TxIn {
+                    previous_output: *previous_output,
+                    sequence: Sequence::MAX,
+                    witness: Witness::from_slice(&[op_true().into_bytes()]),
+                    ..TxIn::default()
+                })
+                .collect(),
+            output: outputs.iter().map(|value| op_true_output(*value)).collect(),
+        }
+    }
+
+    fn coinbase(script_sig: ScriptBuf, value: Amount) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig,
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[[0u8; 32]]),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: ScriptBuf::new_p2wsh(&op_true().wscript_hash()),
+            }],
+        }
+    }
+
+    fn height_script(height: u32) -> ScriptBuf {
+        Builder::new().push_int(height as i64).into_script()
+    }
+
+    // Coinbase claiming the subsidy at HEIGHT plus `fees`
+    fn claiming(fees: u64) -> Transaction {
+        let value = block_subsidy(Network::Regtest, HEIGHT) + Amount::from_sat(fees);
+        coinbase(height_script(HEIGHT), value)
+    }
+
+    // Assemble a block with a witness commitment and a matching merkle root,
+    // then grind the nonce until it meets the regtest target
+    fn assemble(mut coinbase_tx: Transaction, txs: Vec<Transaction>) -> Block {
+        let wtxids: Vec<Wtxid> = txs.iter().map(|tx| tx.compute_wtxid()).collect();
+        let commitment = calculate_witness_commitment(&wtxids, &[0u8; 32]);
+        let mut script = WITNESS_COMMITMENT_HEADER.to_vec();
+        script.extend_from_slice(&commitment);
+        coinbase_tx.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::from_bytes(script),
+        });
+
+        let txdata: Vec<Transaction> = std::iter::once(coinbase_tx).chain(txs).collect();
+        let txids: Vec<Txid> = txdata.iter().map(|tx| tx.compute_txid()).collect();
+        let mut block = Block {
+            header: block::Header {
+                version: block::Version::from_consensus(4),
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: merkle::compute_merkle_root(&txids).0,
+                time: 1_700_000_000,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata,
+        };
+        grind(&mut block);
+        block
+    }
+
+    fn grind(block: &mut Block) {
+        while block.header.validate_pow(block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+    }
+
+    fn validate(block: &Block) -> Vec<BlockViolation> {
+        validate_block(block, &confirmed_outputs(), Network::Regtest)
+    }
+
+    #[test]
+    fn accepts_a_valid_block() {
+        let parent = spend(&[confirmed(1)], &[90_000]);
+        let child = spend(&[OutPoint::new(parent.compute_txid(), 0)], &[85_000]);
+        let block = assemble(claiming(15_000), vec![parent, child]);
+
+        assert_eq!(validate(&block), vec![]);
+    }
+
+    #[test]
+    fn rejects_a_bad_merkle_root() {
+        let mut block = assemble(claiming(10_000), vec![spend(&[confirmed(1)], &[90_000])]);
+        let computed = block.header.merkle_root;
+        block.header.merkle_root = TxMerkleNode::all_zeros();
+        grind(&mut block);
+
+        assert_eq!(
+            validate(&block),
+            vec![BlockViolation::BadMerkleRoot {
+                header: TxMerkleNode::all_zeros(),
+                computed,
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_a_duplicated_tail() {
+        // Three transactions hash to the same root as the same three with the
+        // last one repeated
+        let tx1 = spend(&[confirmed(1)], &[90_000]);
+        let tx2 = spend(&[confirmed(2)], &[90_000]);
+        let mut block = assemble(claiming(20_000), vec![tx1, tx2.clone()]);
+        block.txdata.push(tx2.clone());
+
+        let violations = validate(&block);
+        assert!(violations.contains(&BlockViolation::MutatedMerkleTree));
+        assert!(violations.contains(&BlockViolation::DuplicateTxid(tx2.compute_txid())));
+        assert!(
+            !violations
+                .iter()
+                .any(|violation| matches!(violation, BlockViolation::BadMerkleRoot { .. }))
+        );
+    }
+
+    #[test]
+    fn rejects_a_missing_or_non_minimal_height() {
+        // OP_NOP OP_NOP, then 200 padded to three bytes
+        let scripts = [vec![0x61, 0x61], vec![0x03, 0xc8, 0x00, 0x00]];
+        for script in scripts {
+            let value = block_subsidy(Network::Regtest, HEIGHT);
+            let block = assemble(coinbase(ScriptBuf::from_bytes(script), value), vec![]);
+            assert_eq!(validate(&block), vec![BlockViolation::MissingHeight]);
+        }
+    }
+
+    #[test]
+    fn decodes_coinbase_heights() {
+        assert_eq!(coinbase_height(&height_script(1)), Some(1));
+        assert_eq!(coinbase_height(&height_script(16)), Some(16));
+        assert_eq!(coinbase_height(&height_script(HEIGHT)), Some(HEIGHT));
+        assert_eq!(coinbase_height(&height_script(834_639)), Some(834_639));
+        // A negative number is no height
+        assert_eq!(coinbase_height(&ScriptBuf::from_bytes(vec![0x4f])), None);
+        assert_eq!(coinbase_height(&ScriptBuf::new()), None);
+    }
+
+    #[test]
+    fn subsidy_follows_the_coinbase_height() {
+        // 50 BTC is allowed before the first regtest halving but not after it
+        let fifty = Amount::from_int_btc(50);
+        let before = assemble(coinbase(height_script(149), fifty), vec![]);
+        assert_eq!(validate(&before), vec![]);
+
+        let after = assemble(coinbase(height_script(150), fifty), vec![]);
+        assert_eq!(
+            validate(&after),
+            vec![BlockViolation::CoinbaseOverpays {
+                paid: fifty.to_sat(),
+                allowed: Amount::from_int_btc(25).to_sat(),
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_a_coinbase_paying_more_than_subsidy_and_fees() {
+        let block = assemble(claiming(10_001), vec![spend(&[confirmed(1)], &[90_000])]);
+        let allowed = block_subsidy(Network::Regtest, HEIGHT).to_sat() + 10_000;
+
+        assert_eq!(
+            validate(&block),
+            vec![BlockViolation::CoinbaseOverpays {
+                paid: allowed + 1,
+                allowed,
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_a_bad_witness_commitment() {
+        let mut block = assemble(claiming(10_000), vec![spend(&[confirmed(1)], &[90_000])]);
+        let commitment = block.txdata[0].output.last_mut().unwrap();
+        let mut script = commitment.script_pubkey.to_bytes();
+        script[37] ^= 1;
+        commitment.script_pubkey = ScriptBuf::from_bytes(script);
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+        block.header.merkle_root = merkle::compute_merkle_root(&txids).0;
+        grind(&mut block);
+
+        assert_eq!(validate(&block), vec![BlockViolation::BadWitnessCommitment]);
+    }
+
+    #[test]
+    fn rejects_a_bad_witness_nonce() {
+        // The coinbase witness is not part of the txid, so the root still matches
+        for witness in [Witness::new(), Witness::from_slice(&[[0u8; 31]])] {
+            let mut block = assemble(claiming(10_000), vec![spend(&[confirmed(1)], &[90_000])]);
+            block.txdata[0].input[0].witness = witness;
+
+            assert_eq!(validate(&block), vec![BlockViolation::BadWitnessNonce]);
+        }
+    }
+
+    #[test]
+    fn rejects_witness_data_without_a_commitment() {
+        let tx = spend(&[confirmed(1)], &[90_000]);
+        let mut block = assemble(claiming(10_000), vec![tx.clone()]);
+        block.txdata[0].output.pop();
+        let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+        block.header.merkle_root = merkle::compute_merkle_root(&txids).0;
+        grind(&mut block);
+
+        assert_eq!(
+            validate(&block),
+            vec![
+                BlockViolation::UnexpectedWitness(txids[0]),
+                BlockViolation::UnexpectedWitness(tx.compute_txid()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_a_child_before_its_parent() {
+        let parent = spend(&[confirmed(1)], &[90_000]);
+        let child = spend(&[OutPoint::new(parent.compute_txid(), 0)], &[85_000]);
+        let block = assemble(claiming(15_000), vec![child.clone(), parent.clone()]);
+
+        assert_eq!(
+            validate(&block),
+            vec![BlockViolation::OutOfOrder {
+                txid: child.compute_txid(),
+                parent: parent.compute_txid(),
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_a_double_spend() {
+        let first = spend(&[confirmed(1)], &[90_000]);
+        let second = spend(&[confirmed(1)], &[80_000]);
+        let block = assemble(claiming(30_000), vec![first, second.clone()]);
+
+        assert_eq!(
+            validate(&block),
+            vec![BlockViolation::DoubleSpend {
+                txid: second.compute_txid(),
+                outpoint: confirmed(1),
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_a_missing_prevout() {
+        let tx = spend(&[confirmed(99)], &[90_000]);
+        let block = assemble(claiming(0), vec![tx.clone()]);
+
+        assert_eq!(
+            validate(&block),
+            vec![BlockViolation::MissingPrevout {
+                txid: tx.compute_txid(),
+                outpoint: confirmed(99),
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_too_many_sigops() {
+        // Each bare OP_CHECKMULTISIG counts 20 sigops, at four times the cost
+        let mut tx = spend(&[confirmed(1)], &[90_000]);
+        tx.output.push(TxOut {
+            value: Amount::ZERO,
+            script_pubkey: ScriptBuf::from_bytes(vec![0xae; 1001]),
+        });
+        let block = assemble(claiming(10_000), vec![tx]);
+
+        assert_eq!(
+            validate(&block),
+            vec![BlockViolation::TooManySigops(1001 * 20 * 4)]
+        );
+    }
+}
@@ -0,0 +1,508 @@
+use std::{
+    cell::OnceCell,
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bitcoincore_rpc::{
+    Client, RpcApi,
+    bitcoin::{
+        self, Block, BlockHash, CompactTarget, OutPoint, TxOut, Work, block::Header, consensus,
+    },
+};
+
+use crate::{
+    BlockParams, BlockTemplate, ValidTransactions,
+    block_validation::{self, BlockViolation},
+    build_block_template, difficulty, finality,
+    selection::SelectionStrategy,
+};
+
+// Headers whose median time a new block's time must exceed
+const MEDIAN_TIME_SPAN: usize = 11;
+
+// How far ahead of the current time a block's time may be
+pub const MAX_FUTURE_BLOCK_TIME: u32 = 2 * 60 * 60;
+
+// Bits for the block after the tip of a header chain, given the block's time
+pub type NextBits<'a> = &'a (dyn Fn(&HeaderChain, u32) -> Result<CompactTarget, String> + Sync);
+
+// Headers the miner builds on, oldest first, with their cumulative work. A
+// chain opened from a store file appends every header pushed onto it to the
+// file, whose first line is the height of its first header and every line
+// after that one hex-encoded header. The transactions of blocks pushed onto a
+// store go to a `.txs` file beside it, so a resumed miner does not include
+// them again: a `tx <txid> <height> <median time past>` line for each
+// transaction and a `spent <txid>:<vout>` line for each output spent.
+pub struct HeaderChain {
+    pub start_height: u32,
+    pub headers: Vec<Header>,
+    pub chainwork: Work,
+    // What the blocks already in the store confirmed, as read when opening it
+    pub included: HashMap<String, finality::Confirmation>,
+    pub spent: HashSet<OutPoint>,
+    store: Option<PathBuf>,
+}
+
+impl HeaderChain {
+    // Headers held in memory only, the first of them at `start_height`
+    pub fn new(start_height: u32, headers: Vec<Header>) -> HeaderChain {
+        let chainwork = headers
+            .iter()
+            .fold(Work::from_be_bytes([0; 32]), |work, header| {
+                work + header.work()
+            });
+        HeaderChain {
+            start_height,
+            headers,
+            chainwork,
+            included: HashMap::new(),
+            spent: HashSet::new(),
+            store: None,
+        }
+    }
+
+    // Load the store at `path`, creating an empty one whose first header will
+    // be at `start_height` if there is no file yet
+    pub fn open(path: &Path, start_height: u32) -> Result<HeaderChain, String> {
+        if !path.exists() {
+            std::fs::write(path, format!("{}\n", start_height))
+                .map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+            return Ok(HeaderChain {
+                store: Some(path.to_path_buf()),
+                ..HeaderChain::new(start_height, Vec::new())
+            });
+        }
+
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        let mut lines = contents.lines().enumerate();
+        let start_height = lines
+            .next()
+            .and_then(|(_, line)| line.trim().parse().ok())
+            .ok_or_else(|| format!("{} does not start with a height", path.display()))?;
+        let headers =
+            difficulty::parse_headers(lines).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut chain = HeaderChain {
+            store: Some(path.to_path_buf()),
+            ..HeaderChain::new(start_height, headers)
+        };
+        chain.read_transactions()?;
+        Ok(chain)
+    }
+
+    // The `.txs` file beside the store
+    fn transactions_path(store: &Path) -> PathBuf {
+        let mut name = OsString::from(store.as_os_str());
+        name.push(".txs");
+        PathBuf::from(name)
+    }
+
+    fn read_transactions(&mut self) -> Result<(), String> {
+        let Some(store) = &self.store else {
+            return Ok(());
+        };
+        let path = HeaderChain::transactions_path(store);
+        if !path.exists() {
+            return Ok(());
+        }
+        let contents = std::fs::read_to_string(&path)
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        for (line_number, line) in contents.lines().enumerate() {
+            let invalid = || format!("{}: Invalid line {}", path.display(), line_number + 1);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                ["tx", txid, height, median_time_past] => {
+                    let confirmation = finality::Confirmation {
+                        height: height.parse().map_err(|_| invalid())?,
+                        median_time_past: median_time_past.parse().map_err(|_| invalid())?,
+                    };
+                    self.included.insert(txid.to_string(), confirmation);
+                }
+                ["spent", outpoint] => {
+                    self.spent.insert(outpoint.parse().map_err(|_| invalid())?);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn tip(&self) -> Option<&Header> {
+        self.headers.last()
+    }
+
+    // Height of the block that would extend the tip
+    pub fn next_height(&self) -> u32 {
+        self.start_height + self.headers.len() as u32
+    }
+
+    // Median time of the last 11 headers, or None before there are any
+    pub fn median_time_past(&self) -> Option<u32> {
+        let start = self.headers.len().saturating_sub(MEDIAN_TIME_SPAN);
+        let mut times: Vec<u32> = self.headers[start..]
+            .iter()
+            .map(|header| header.time)
+            .collect();
+        times.sort_unstable();
+        times.get(times.len() / 2).copied()
+    }
+
+    // Time for the next block: now, unless that is not past the median time past
+    pub fn next_block_time(&self, now: u32) -> u32 {
+        self.median_time_past()
+            .map_or(now, |median| now.max(median + 1))
+    }
+
+    // Check the time of a header extending the tip, returning Bitcoin Core's
+    // reject reason if it is not past the median time past or more than two
+    // hours ahead of `now`
+    pub fn check_time(&self, time: u32, now: u32) -> Result<(), &'static str> {
+        if self.median_time_past().is_some_and(|median| time <= median) {
+            return Err("time-too-old");
+        }
+        if time as u64 > now as u64 + MAX_FUTURE_BLOCK_TIME as u64 {
+            return Err("time-too-new");
+        }
+        Ok(())
+    }
+
+    // Extend the chain with a block that builds on the tip, recording its
+    // transactions beside the store before its header so a block is never
+    // stored without them
+    pub fn push_block(
+        &mut self,
+        block: &Block,
+        confirmation: finality::Confirmation,
+    ) -> Result<(), String> {
+        self.check_extends(&block.header)?;
+        if let Some(store) = &self.store {
+            let mut lines = String::new();
+            for tx in &block.txdata {
+                lines += &format!(
+                    "tx {} {} {}\n",
+                    tx.compute_txid(),
+                    confirmation.height,
+                    confirmation.median_time_past
+                );
+                if !tx.is_coinbase() {
+                    for input in &tx.input {
+                        lines += &format!("spent {}\n", input.previous_output);
+                    }
+                }
+            }
+            let path = HeaderChain::transactions_path(store);
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .and_then(|mut file| file.write_all(lines.as_bytes()))
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        }
+        self.push(block.header)
+    }
+
+    // Extend the chain with a header that builds on the tip
+    pub fn push(&mut self, header: Header) -> Result<(), String> {
+        self.check_extends(&header)?;
+        if let Some(path) = &self.store {
+            OpenOptions::new()
+                .append(true)
+                .open(path)
+                .and_then(|mut file| {
+                    writeln!(file, "{}", hex::encode(consensus::serialize(&header)))
+                })
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        }
+        self.chainwork = self.chainwork + header.work();
+        self.headers.push(header);
+        Ok(())
+    }
+
+    fn check_extends(&self, header: &Header) -> Result<(), String> {
+        match self.tip() {
+            Some(tip) if header.prev_blockhash != tip.block_hash() => Err(format!(
+                "Header {} does not build on tip {}",
+                header.block_hash(),
+                tip.block_hash()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+// The network a node runs, from getblockchaininfo
+pub fn node_network(client: &Client) -> Result<difficulty::Network, String> {
+    let chain = client
+        .get_blockchain_info()
+        .map_err(|e| format!("getblockchaininfo failed: {}", e))?
+        .chain;
+    match chain {
+        bitcoin::Network::Bitcoin => Ok(difficulty::Network::Mainnet),
+        bitcoin::Network::Testnet => Ok(difficulty::Network::Testnet),
+        bitcoin::Network::Signet => Ok(difficulty::Network::Signet),
+        bitcoin::Network::Regtest => Ok(difficulty::Network::Regtest),
+        chain => Err(format!("Unsupported network {}", chain)),
+    }
+}
+
+// The node's active chain up to its tip: back to the start of the tip's
+// retarget period, and at least MEDIAN_TIME_SPAN headers, which is all the
+// next block's bits and median time past depend on
+pub fn node_headers(client: &Client) -> Result<HeaderChain, String> {
+    let info = client
+        .get_blockchain_info()
+        .map_err(|e| format!("getblockchaininfo failed: {}", e))?;
+    let tip_height = info.blocks as u32;
+    let count = (tip_height % difficulty::RETARGET_INTERVAL + 1)
+        .max(MEDIAN_TIME_SPAN as u32)
+        .min(tip_height + 1);
+
+    let mut headers = Vec::with_capacity(count as usize);
+    let mut hash = info.best_block_hash;
+    for _ in 0..count {
+        let header = client
+            .get_block_header(&hash)
+            .map_err(|e| format!("getblockheader {} failed: {}", hash, e))?;
+        hash = header.prev_blockhash;
+        headers.push(header);
+    }
+    headers.reverse();
+    Ok(HeaderChain::new(tip_height + 1 - count, headers))
+}
+
+// The tip new blocks build on and the transactions still waiting for a block.
+// Accepting a block makes it the tip and drops its transactions from the pool;
+// the next template is built when it is first asked for.
+pub struct ChainState<'a> {
+    pub valid_txs: Vec<ValidTransactions>,
+    // Outputs still unspent on the current tip
+    pub prevouts: HashMap<OutPoint, TxOut>,
+    pub params: BlockParams<'a>,
+    template: OnceCell<BlockTemplate>,
+    pub headers: HeaderChain,
+    strategy: SelectionStrategy,
+    next_bits: NextBits<'a>,
+    accepted: HashSet<BlockHash>,
+}
+
+impl<'a> ChainState<'a> {
+    // Transactions and outputs the header store's blocks already confirmed
+    // are left out
+    pub fn new(
+        mut valid_txs: Vec<ValidTransactions>,
+        mut prevouts: HashMap<OutPoint, TxOut>,
+        params: BlockParams<'a>,
+        headers: HeaderChain,
+        strategy: SelectionStrategy,
+        next_bits: NextBits<'a>,
+    ) -> Result<ChainState<'a>, String> {
+        let count = valid_txs.len();
+        remove_confirmed(
+            &mut valid_txs,
+            &mut prevouts,
+            &headers.included,
+            &headers.spent,
+        );
+        if valid_txs.len() < count {
+            println!(
+                "msg: Leaving out {} transactions already in the header store's blocks",
+                count - valid_txs.len()
+            );
+        }
+        let template = OnceCell::from(build_block_template(&valid_txs, &params, strategy)?);
+        Ok(ChainState {
+            valid_txs,
+            prevouts,
+            params,
+            template,
+            headers,
+            strategy,
+            next_bits,
+            accepted: HashSet::new(),
+        })
+    }
+
+    // The template for the next block, built on first use after the tip moves
+    pub fn template(&self) -> Result<&BlockTemplate, String> {
+        if let Some(template) = self.template.get() {
+            return Ok(template);
+        }
+        let template = build_block_template(&self.valid_txs, &self.params, self.strategy)?;
+        Ok(self.template.get_or_init(|| template))
+    }
+
+    // Check a block against the current tip, returning Bitcoin Core's reject
+    // reason for the first rule it breaks
+    pub fn check_block(&self, block: &Block, check_pow: bool) -> Result<(), &'static str> {
+        if self.accepted.contains(&block.block_hash()) {
+            return Err("duplicate");
+        }
+        if block.header.prev_blockhash != self.params.previous_hash {
+            return Err("bad-prevblk");
+        }
+        if block.header.bits != self.params.bits {
+            return Err("bad-diffbits");
+        }
+        self.headers.check_time(block.header.time, unix_time())?;
+
+        let violations =
+            block_validation::validate_block(block, &self.prevouts, self.params.network);
+        if let Some(violation) = violations
+            .iter()
+            .find(|violation| check_pow || !matches!(violation, BlockViolation::HighHash { .. }))
+        {
+            return Err(violation.reason());
+        }
+
+        let height = block_validation::coinbase_height(&block.txdata[0].input[0].script_sig);
+        if height != Some(self.params.height) {
+            return Err("bad-cb-height");
+        }
+        Ok(())
+    }
+
+    // Extend the chain with a block that passed check_block, leaving the
+    // next template to be built from what is left of the pool
+    pub fn accept_block(&mut self, block: &Block) {
+        let hash = block.block_hash();
+        // Outputs of this block now age towards the relative locks spending them
+        let confirmation = finality::Confirmation {
+            height: self.params.height,
+            median_time_past: self.params.median_time_past,
+        };
+        if let Err(e) = self.headers.push_block(block, confirmation) {
+            println!("msg: Failed to store block: {}", e);
+        }
+        println!(
+            "msg: Accepted block {} at height {} ({} transactions, chainwork {:x})",
+            hash,
+            self.params.height,
+            block.txdata.len(),
+            self.headers.chainwork
+        );
+
+        let included: HashMap<String, finality::Confirmation> = block
+            .txdata
+            .iter()
+            .map(|tx| (tx.compute_txid().to_string(), confirmation))
+            .collect();
+        let spent: HashSet<OutPoint> = block
+            .txdata
+            .iter()
+            .flat_map(|tx| &tx.input)
+            .map(|input| input.previous_output)
+            .collect();
+        remove_confirmed(&mut self.valid_txs, &mut self.prevouts, &included, &spent);
+        self.params.previous_hash = hash;
+        self.params.height += 1;
+        self.params.time = self.headers.next_block_time(unix_time());
+        self.params.median_time_past = self.headers.median_time_past().unwrap_or(self.params.time);
+        match (self.next_bits)(&self.headers, self.params.time) {
+            Ok(bits) => self.params.bits = bits,
+            Err(e) => println!(
+                "msg: Keeping bits {:08x}: {}",
+                self.params.bits.to_consensus(),
+                e
+            ),
+        }
+        self.accepted.insert(hash);
+        self.template = OnceCell::new();
+    }
+}
+
+// Drop the transactions a block confirmed and the outputs it spent, and date
+// the inputs that spend the block's outputs
+fn remove_confirmed(
+    valid_txs: &mut Vec<ValidTransactions>,
+    prevouts: &mut HashMap<OutPoint, TxOut>,
+    included: &HashMap<String, finality::Confirmation>,
+    spent: &HashSet<OutPoint>,
+) {
+    valid_txs.retain(|tx| !included.contains_key(&tx.id));
+    for tx in valid_txs {
+        for (parent, prevout) in tx.parents.iter().zip(&mut tx.prevout_confirmations) {
+            if let Some(confirmation) = included.get(parent) {
+                *prevout = Some(*confirmation);
+            }
+        }
+    }
+    prevouts.retain(|outpoint, _| !spent.contains(outpoint));
+}
+
+// Seconds since the Unix epoch
+pub fn unix_time() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        Transaction, TxIn, TxMerkleNode, Txid, absolute::LockTime, block::Version, hashes::Hash,
+        transaction,
+    };
+
+    use super::*;
+
+    fn transaction(previous_output: OutPoint) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                ..TxIn::default()
+            }],
+            output: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn store_remembers_confirmed_transactions() {
+        let path = std::env::temp_dir().join(format!("chain-test-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(HeaderChain::transactions_path(&path));
+
+        let spent = OutPoint::new(Txid::from_byte_array([1; 32]), 3);
+        let block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata: vec![transaction(OutPoint::null()), transaction(spent)],
+        };
+        let confirmation = finality::Confirmation {
+            height: 7,
+            median_time_past: 1_700_000_000,
+        };
+
+        let mut chain = HeaderChain::open(&path, 7).unwrap();
+        chain.push_block(&block, confirmation).unwrap();
+        assert!(chain.included.is_empty());
+
+        let chain = HeaderChain::open(&path, 0).unwrap();
+        assert_eq!(chain.next_height(), 8);
+        assert_eq!(chain.headers, vec![block.header]);
+        let included: HashMap<String, finality::Confirmation> = block
+            .txdata
+            .iter()
+            .map(|tx| (tx.compute_txid().to_string(), confirmation))
+            .collect();
+        assert_eq!(chain.included, included);
+        // The coinbase spends nothing
+        assert_eq!(chain.spent, HashSet::from([spent]));
+
+        std::fs::remove_file(HeaderChain::transactions_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
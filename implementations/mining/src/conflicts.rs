@@ -0,0 +1,286 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt,
+    str::FromStr,
+};
+
+use bitcoincore_rpc::bitcoin::{OutPoint, Transaction};
+
+// How to pick which of several conflicting transactions stays in the set
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictRule {
+    // Keep the transaction paying the highest fee per weight unit
+    HighestFeeRate,
+    // Keep the transaction listed first in mempool.json
+    FirstSeen,
+}
+
+impl FromStr for ConflictRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fee-rate" => Ok(ConflictRule::HighestFeeRate),
+            "first-seen" => Ok(ConflictRule::FirstSeen),
+            _ => Err(format!(
+                "Unknown conflict rule '{}' (expected fee-rate or first-seen)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConflictRule::HighestFeeRate => write!(f, "fee-rate"),
+            ConflictRule::FirstSeen => write!(f, "first-seen"),
+        }
+    }
+}
+
+// A candidate for conflict resolution: the outpoints it spends and the numbers
+// the rules rank it by. `position` is its first-seen order.
+pub struct Candidate {
+    pub position: usize,
+    pub fee: u64,
+    pub weight: u64,
+    pub outpoints: Vec<OutPoint>,
+}
+
+// A connected set of transactions linked by shared outpoints
+#[derive(Debug)]
+pub struct ConflictGroup {
+    // Indices into the candidate slice, ascending
+    pub members: Vec<usize>,
+    // Members kept after resolution; more than one can survive when the group
+    // is a chain of pairwise conflicts
+    pub winners: Vec<usize>,
+    // Rejected members with the outpoint they lost and the winner that spends it
+    pub losers: Vec<(usize, OutPoint, usize)>,
+}
+
+// Group candidates that spend a common outpoint and resolve each group with
+// `rule`. Only groups with an actual conflict are returned.
+pub fn resolve_conflicts(candidates: &[Candidate], rule: ConflictRule) -> Vec<ConflictGroup> {
+    let mut spenders: HashMap<OutPoint, Vec<usize>> = HashMap::new();
+    for (index, candidate) in candidates.iter().enumerate() {
+        for outpoint in &candidate.outpoints {
+            spenders.entry(*outpoint).or_default().push(index);
+        }
+    }
+
+    // Union-find over candidates sharing an outpoint
+    let mut parent: Vec<usize> = (0..candidates.len()).collect();
+    for indices in spenders.values().filter(|indices| indices.len() > 1) {
+        for pair in indices.windows(2) {
+            let (a, b) = (find(&mut parent, pair[0]), find(&mut parent, pair[1]));
+            if a != b {
+                parent[a.max(b)] = a.min(b);
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+    for indices in spenders.values().filter(|indices| indices.len() > 1) {
+        for index in indices {
+            let root = find(&mut parent, *index);
+            groups.entry(root).or_default().insert(*index);
+        }
+    }
+
+    let mut resolved: Vec<ConflictGroup> = groups
+        .into_values()
+        .map(|members| resolve_group(candidates, members.into_iter().collect(), rule))
+        .collect();
+    resolved.sort_by_key(|group| group.members[0]);
+    resolved
+}
+
+fn find(parent: &mut [usize], mut index: usize) -> usize {
+    while parent[index] != index {
+        parent[index] = parent[parent[index]];
+        index = parent[index];
+    }
+    index
+}
+
+fn resolve_group(
+    candidates: &[Candidate],
+    members: Vec<usize>,
+    rule: ConflictRule,
+) -> ConflictGroup {
+    let mut ranked = members.clone();
+    ranked.sort_by(|a, b| {
+        let (a, b) = (&candidates[*a], &candidates[*b]);
+        let by_rule = match rule {
+            ConflictRule::HighestFeeRate => {
+                let lhs = b.fee as u128 * a.weight as u128;
+                let rhs = a.fee as u128 * b.weight as u128;
+                lhs.cmp(&rhs)
+            }
+            ConflictRule::FirstSeen => Ordering::Equal,
+        };
+        by_rule.then(a.position.cmp(&b.position))
+    });
+
+    // Accept in rank order anything that does not spend an already-claimed outpoint
+    let mut claimed: HashMap<OutPoint, usize> = HashMap::new();
+    let mut winners = Vec::new();
+    let mut losers = Vec::new();
+    for index in ranked {
+        let candidate = &candidates[index];
+        if let Some((outpoint, winner)) = candidate
+            .outpoints
+            .iter()
+            .find_map(|outpoint| claimed.get(outpoint).map(|winner| (*outpoint, *winner)))
+        {
+            losers.push((index, outpoint, winner));
+            continue;
+        }
+        for outpoint in &candidate.outpoints {
+            claimed.insert(*outpoint, index);
+        }
+        winners.push(index);
+    }
+    winners.sort_unstable();
+    losers.sort_unstable_by_key(|(index, _, _)| *index);
+
+    ConflictGroup {
+        members,
+        winners,
+        losers,
+    }
+}
+
+// Final guard on an assembled block: no outpoint may be spent twice
+pub fn check_no_double_spends(txs: &[Transaction]) -> Result<(), OutPoint> {
+    let mut spent = HashSet::new();
+    for tx in txs.iter().filter(|tx| !tx.is_coinbase()) {
+        for input in &tx.input {
+            if !spent.insert(input.previous_output) {
+                return Err(input.previous_output);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{Txid, hashes::Hash};
+
+    use super::*;
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint::new(Txid::from_byte_array([n; 32]), 0)
+    }
+
+    fn candidate(position: usize, fee: u64, weight: u64, outpoints: &[u8]) -> Candidate {
+        Candidate {
+            position,
+            fee,
+            weight,
+            outpoints: outpoints.iter().map(|n| outpoint(*n)).collect(),
+        }
+    }
+
+    #[test]
+    fn fee_rate_beats_absolute_fee() {
+        // 2.5 sat/WU against 1.875 sat/WU for a larger fee
+        let candidates = [
+            candidate(0, 1_500, 800, &[1]),
+            candidate(1, 1_000, 400, &[1]),
+        ];
+        let groups = resolve_conflicts(&candidates, ConflictRule::HighestFeeRate);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].members, vec![0, 1]);
+        assert_eq!(groups[0].winners, vec![1]);
+        assert_eq!(groups[0].losers, vec![(0, outpoint(1), 1)]);
+    }
+
+    #[test]
+    fn fee_rate_ties_go_to_first_seen() {
+        let candidates = [
+            candidate(1, 2_000, 800, &[1]),
+            candidate(0, 1_000, 400, &[1]),
+        ];
+        let groups = resolve_conflicts(&candidates, ConflictRule::HighestFeeRate);
+        assert_eq!(groups[0].winners, vec![1]);
+        assert_eq!(groups[0].losers, vec![(0, outpoint(1), 1)]);
+    }
+
+    #[test]
+    fn first_seen_ignores_fees() {
+        // Positions, not slice order, decide
+        let candidates = [
+            candidate(2, 9_000, 400, &[1]),
+            candidate(0, 100, 400, &[1]),
+            candidate(1, 5_000, 400, &[1]),
+        ];
+        let groups = resolve_conflicts(&candidates, ConflictRule::FirstSeen);
+        assert_eq!(groups[0].winners, vec![1]);
+        assert_eq!(
+            groups[0].losers,
+            vec![(0, outpoint(1), 1), (2, outpoint(1), 1)]
+        );
+    }
+
+    #[test]
+    fn transitive_group_with_middle_winner() {
+        // 0 and 2 do not conflict directly but both conflict with 1
+        let candidates = [
+            candidate(0, 400, 400, &[1]),
+            candidate(1, 4_000, 400, &[1, 2]),
+            candidate(2, 400, 400, &[2, 3]),
+        ];
+        let groups = resolve_conflicts(&candidates, ConflictRule::HighestFeeRate);
+        assert_eq!(groups.len(), 1);
+        assert_eq!(groups[0].members, vec![0, 1, 2]);
+        assert_eq!(groups[0].winners, vec![1]);
+        assert_eq!(
+            groups[0].losers,
+            vec![(0, outpoint(1), 1), (2, outpoint(2), 1)]
+        );
+    }
+
+    #[test]
+    fn transitive_group_with_two_winners() {
+        // Rejecting the middle of the chain lets both ends survive
+        let candidates = [
+            candidate(0, 4_000, 400, &[1]),
+            candidate(1, 400, 400, &[1, 2]),
+            candidate(2, 4_000, 400, &[2]),
+        ];
+        let groups = resolve_conflicts(&candidates, ConflictRule::HighestFeeRate);
+        assert_eq!(groups[0].members, vec![0, 1, 2]);
+        assert_eq!(groups[0].winners, vec![0, 2]);
+        assert_eq!(groups[0].losers, vec![(1, outpoint(1), 0)]);
+
+        let groups = resolve_conflicts(&candidates, ConflictRule::FirstSeen);
+        assert_eq!(groups[0].winners, vec![0, 2]);
+        assert_eq!(groups[0].losers, vec![(1, outpoint(1), 0)]);
+    }
+
+    #[test]
+    fn separate_groups_and_no_conflicts() {
+        let candidates = [
+            candidate(0, 100, 400, &[5]),
+            candidate(1, 100, 400, &[1]),
+            candidate(2, 200, 400, &[6]),
+            candidate(3, 200, 400, &[1]),
+            candidate(4, 300, 400, &[5]),
+            candidate(5, 300, 400, &[7]),
+        ];
+        let groups = resolve_conflicts(&candidates, ConflictRule::HighestFeeRate);
+        assert_eq!(groups.len(), 2);
+        assert_eq!(groups[0].members, vec![0, 4]);
+        assert_eq!(groups[0].winners, vec![4]);
+        assert_eq!(groups[1].members, vec![1, 3]);
+        assert_eq!(groups[1].winners, vec![3]);
+
+        let unrelated = [candidate(0, 100, 400, &[1]), candidate(1, 100, 400, &[2])];
+        assert!(resolve_conflicts(&unrelated, ConflictRule::FirstSeen).is_empty());
+    }
+}
@@ -0,0 +1,398 @@
+use std::{fmt, path::Path, str::FromStr};
+
+use bitcoincore_rpc::bitcoin::{CompactTarget, Target, block::Header, consensus};
+
+// Blocks between difficulty adjustments
+pub const RETARGET_INTERVAL: u32 = 2016;
+
+// Expected time for one retarget window: two weeks of ten-minute blocks
+const TARGET_SPACING: u32 = 10 * 60;
+const TARGET_TIMESPAN: u32 = RETARGET_INTERVAL * TARGET_SPACING;
+
+// Consensus parameters that decide the proof-of-work limit and retarget rules
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Network {
+    Mainnet,
+    Testnet,
+    Regtest,
+    Signet,
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            "signet" => Ok(Network::Signet),
+            _ => Err(format!(
+                "Unknown network '{}' (expected mainnet, testnet, regtest or signet)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Regtest => write!(f, "regtest"),
+            Network::Signet => write!(f, "signet"),
+        }
+    }
+}
+
+impl Network {
+    // Easiest target a block may have (Core's consensus.powLimit)
+    pub fn pow_limit(self) -> Target {
+        let limit = match self {
+            Network::Mainnet | Network::Testnet => {
+                "00000000ffffffffffffffffffffffffffffffffffffffffffffffffffffffff"
+            }
+            Network::Regtest => "7fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            Network::Signet => "00000377ae000000000000000000000000000000000000000000000000000000",
+        };
+        parse_target(limit).expect("msg: Invalid proof-of-work limit")
+    }
+
+    // Blocks between subsidy halvings (Core's consensus.nSubsidyHalvingInterval)
+    pub fn subsidy_halving_interval(self) -> u32 {
+        match self {
+            Network::Regtest => 150,
+            Network::Mainnet | Network::Testnet | Network::Signet => 210_000,
+        }
+    }
+
+    // Lowest block version accepted at `height`: BIP34, BIP66 and BIP65 each
+    // rejected the versions below theirs from their activation height
+    pub fn min_block_version(self, height: u32) -> i32 {
+        let (bip34, bip66, bip65) = match self {
+            Network::Mainnet => (227_931, 363_725, 388_381),
+            Network::Testnet => (21_111, 330_776, 581_885),
+            Network::Regtest | Network::Signet => (1, 1, 1),
+        };
+        if height >= bip65 {
+            4
+        } else if height >= bip66 {
+            3
+        } else if height >= bip34 {
+            2
+        } else {
+            1
+        }
+    }
+
+    // Testnet and regtest accept a minimum-difficulty block after 20 minutes
+    fn allow_min_difficulty_blocks(self) -> bool {
+        matches!(self, Network::Testnet | Network::Regtest)
+    }
+
+    fn no_retargeting(self) -> bool {
+        matches!(self, Network::Regtest)
+    }
+}
+
+// Parse compact bits written as hex, e.g. `1d00ffff`
+pub fn parse_bits(s: &str) -> Result<CompactTarget, String> {
+    CompactTarget::from_unprefixed_hex(s).map_err(|e| format!("Invalid bits '{}': {}", s, e))
+}
+
+// Parse a full 256-bit target written as 64 big-endian hex digits
+pub fn parse_target(s: &str) -> Result<Target, String> {
+    let bytes: [u8; 32] = hex::decode(s)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("Invalid target '{}': expected 64 hex digits", s))?;
+    Ok(Target::from_be_bytes(bytes))
+}
+
+// Read consecutive block headers, one hex-encoded header per line, oldest first
+pub fn read_headers(path: &Path) -> Result<Vec<Header>, String> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| format!("Failed to read headers file {}: {}", path.display(), e))?;
+    parse_headers(contents.lines().enumerate())
+}
+
+// Parse numbered lines of hex-encoded headers, checking that each builds on the last
+pub fn parse_headers<'a>(
+    lines: impl Iterator<Item = (usize, &'a str)>,
+) -> Result<Vec<Header>, String> {
+    let mut headers: Vec<Header> = Vec::new();
+    for (line_number, line) in lines {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let header: Header = hex::decode(line)
+            .ok()
+            .and_then(|bytes| consensus::deserialize(&bytes).ok())
+            .ok_or_else(|| format!("Invalid header on line {}", line_number + 1))?;
+        if let Some(previous) = headers.last()
+            && header.prev_blockhash != previous.block_hash()
+        {
+            return Err(format!(
+                "Header on line {} does not build on the one before it",
+                line_number + 1
+            ));
+        }
+        headers.push(header);
+    }
+
+    Ok(headers)
+}
+
+// Bits required for the block after `headers`, following Core's
+// GetNextWorkRequired. `headers` is a window of consecutive headers ending at
+// the tip, which sits at `tip_height`.
+pub fn next_work_required(
+    network: Network,
+    headers: &[Header],
+    tip_height: u32,
+    new_block_time: u32,
+) -> Result<CompactTarget, String> {
+    let last = headers
+        .last()
+        .ok_or("No previous headers to retarget from")?;
+    let window_start = (tip_height + 1)
+        .checked_sub(headers.len() as u32)
+        .ok_or("More headers than the tip height allows")?;
+    let header_at = |height: u32| -> Result<&Header, String> {
+        height
+            .checked_sub(window_start)
+            .and_then(|index| headers.get(index as usize))
+            .ok_or_else(|| format!("Header window does not reach back to height {}", height))
+    };
+
+    let pow_limit_bits = network.pow_limit().to_compact_lossy();
+
+    // Only change once per difficulty adjustment interval
+    if !(tip_height + 1).is_multiple_of(RETARGET_INTERVAL) {
+        if network.allow_min_difficulty_blocks() {
+            // A block more than twice the target spacing after the tip may be
+            // mined at minimum difficulty
+            if new_block_time as i64 > last.time as i64 + 2 * TARGET_SPACING as i64 {
+                return Ok(pow_limit_bits);
+            }
+            // Otherwise use the bits of the last block that was not a
+            // minimum-difficulty exception, or of the first header in the
+            // window if they all are
+            let mut height = tip_height;
+            let mut header = last;
+            while height > window_start
+                && !height.is_multiple_of(RETARGET_INTERVAL)
+                && header.bits == pow_limit_bits
+            {
+                height -= 1;
+                header = header_at(height)?;
+            }
+            return Ok(header.bits);
+        }
+        return Ok(last.bits);
+    }
+
+    // Go back the full period unless it's the first retarget after genesis
+    let first = header_at(tip_height - (RETARGET_INTERVAL - 1))?;
+    Ok(calculate_next_work_required(network, last, first.time))
+}
+
+fn calculate_next_work_required(
+    network: Network,
+    last: &Header,
+    first_block_time: u32,
+) -> CompactTarget {
+    if network.no_retargeting() {
+        return last.bits;
+    }
+
+    // Limit the adjustment step to a factor of four either way
+    let actual_timespan = (last.time as i64 - first_block_time as i64)
+        .clamp(TARGET_TIMESPAN as i64 / 4, TARGET_TIMESPAN as i64 * 4)
+        as u32;
+
+    let target = scale_target(
+        Target::from_compact(last.bits),
+        actual_timespan,
+        TARGET_TIMESPAN,
+    );
+    match target {
+        Some(target) if target <= network.pow_limit() => target.to_compact_lossy(),
+        _ => network.pow_limit().to_compact_lossy(),
+    }
+}
+
+// Compute `target * numerator / denominator` in 288-bit arithmetic, returning
+// None if the result no longer fits in 256 bits
+fn scale_target(target: Target, numerator: u32, denominator: u32) -> Option<Target> {
+    let bytes = target.to_be_bytes();
+
+    // Big-endian 32-bit limbs, with an extra limb on top for the carry
+    let mut limbs = [0u32; 9];
+    let mut carry = 0u64;
+    for index in (0..8).rev() {
+        let limb = u32::from_be_bytes(bytes[index * 4..index * 4 + 4].try_into().unwrap());
+        let product = limb as u64 * numerator as u64 + carry;
+        limbs[index + 1] = product as u32;
+        carry = product >> 32;
+    }
+    limbs[0] = carry as u32;
+
+    let mut remainder = 0u64;
+    for limb in limbs.iter_mut() {
+        let dividend = (remainder << 32) | *limb as u64;
+        *limb = (dividend / denominator as u64) as u32;
+        remainder = dividend % denominator as u64;
+    }
+
+    if limbs[0] != 0 {
+        return None;
+    }
+    let mut scaled = [0u8; 32];
+    for (index, limb) in limbs[1..].iter().enumerate() {
+        scaled[index * 4..index * 4 + 4].copy_from_slice(&limb.to_be_bytes());
+    }
+    Some(Target::from_be_bytes(scaled))
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{BlockHash, TxMerkleNode, block::Version, hashes::Hash};
+
+    use super::*;
+
+    fn header(time: u32, bits: u32) -> Header {
+        Header {
+            version: Version::ONE,
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time,
+            bits: CompactTarget::from_consensus(bits),
+            nonce: 0,
+        }
+    }
+
+    // A retarget window of RETARGET_INTERVAL headers from `first_time` to
+    // `last_time`, all with `bits`
+    fn window(first_time: u32, last_time: u32, bits: u32) -> Vec<Header> {
+        let mut headers = vec![header(first_time, bits); RETARGET_INTERVAL as usize];
+        headers[RETARGET_INTERVAL as usize - 1] = header(last_time, bits);
+        headers
+    }
+
+    // Mainnet retargets from Bitcoin Core's pow_tests.cpp
+    #[test]
+    fn mainnet_retargets() {
+        // Block 32256, the first retarget that raised the difficulty
+        let headers = window(1261130161, 1262152739, 0x1d00ffff);
+        let bits = next_work_required(Network::Mainnet, &headers, 32255, 1262153464);
+        assert_eq!(bits, Ok(CompactTarget::from_consensus(0x1d00d86a)));
+
+        // Block 2016: slower than two weeks, but already at the limit
+        let headers = window(1231006505, 1233061996, 0x1d00ffff);
+        let bits = next_work_required(Network::Mainnet, &headers, 2015, 1233063531);
+        assert_eq!(bits, Ok(CompactTarget::from_consensus(0x1d00ffff)));
+    }
+
+    #[test]
+    fn retarget_step_is_clamped() {
+        // Blocks 66528 to 68543 took under a quarter of two weeks
+        let last = header(1279297671, 0x1c05a3f4);
+        assert_eq!(
+            calculate_next_work_required(Network::Mainnet, &last, 1279008237),
+            CompactTarget::from_consensus(0x1c0168fd)
+        );
+        // More than four times two weeks, from a made-up first block time
+        let last = header(1269211443, 0x1c387f6f);
+        assert_eq!(
+            calculate_next_work_required(Network::Mainnet, &last, 1263163443),
+            CompactTarget::from_consensus(0x1d00e1fd)
+        );
+    }
+
+    #[test]
+    fn bits_only_change_at_retarget_heights() {
+        let headers = window(1261130161, 1262152739, 0x1c05a3f4);
+        for tip_height in [32254, 32256, 34270] {
+            assert_eq!(
+                next_work_required(Network::Mainnet, &headers, tip_height, 1262153464),
+                Ok(CompactTarget::from_consensus(0x1c05a3f4))
+            );
+        }
+        // The window has to reach back to the start of the period
+        assert!(next_work_required(Network::Mainnet, &headers[1..], 32255, 1262153464).is_err());
+    }
+
+    #[test]
+    fn regtest_never_retargets() {
+        let headers = window(1_700_000_000, 1_700_000_100, 0x207fffff);
+        assert_eq!(
+            next_work_required(Network::Regtest, &headers, 2015, 1_700_000_200),
+            Ok(CompactTarget::from_consensus(0x207fffff))
+        );
+    }
+
+    #[test]
+    fn testnet_min_difficulty_walk() {
+        let real_bits = 0x1b00ffff;
+        let min_bits = 0x1d00ffff;
+        // Height 4032 retargeted to real_bits; 4033 is real, 4034-4035 are
+        // minimum-difficulty exceptions
+        let start = 1_600_000_000;
+        let headers = vec![
+            header(start, real_bits),
+            header(start + 600, real_bits),
+            header(start + 2_000, min_bits),
+            header(start + 3_400, min_bits),
+        ];
+        let tip_time = start + 3_400;
+
+        // More than 20 minutes after the tip: minimum difficulty
+        assert_eq!(
+            next_work_required(Network::Testnet, &headers, 4035, tip_time + 1_201),
+            Ok(CompactTarget::from_consensus(min_bits))
+        );
+        // Exactly 20 minutes is not enough; walk back past the exceptions
+        assert_eq!(
+            next_work_required(Network::Testnet, &headers, 4035, tip_time + 1_200),
+            Ok(CompactTarget::from_consensus(real_bits))
+        );
+        // Mainnet has no exception
+        assert_eq!(
+            next_work_required(Network::Mainnet, &headers, 4035, tip_time + 1_201),
+            Ok(CompactTarget::from_consensus(min_bits))
+        );
+
+        // The walk stops at the retarget block even when it has minimum bits
+        let headers = vec![header(start, min_bits), header(start + 2_000, min_bits)];
+        assert_eq!(
+            next_work_required(Network::Testnet, &headers, 4033, start + 2_100),
+            Ok(CompactTarget::from_consensus(min_bits))
+        );
+        // And at the first header of the window, which is all it knows
+        let headers = vec![header(start, real_bits), header(start + 2_000, min_bits)];
+        assert_eq!(
+            next_work_required(Network::Testnet, &headers[1..], 4034, start + 2_100),
+            Ok(CompactTarget::from_consensus(min_bits))
+        );
+        assert_eq!(
+            next_work_required(Network::Testnet, &headers, 4034, start + 2_100),
+            Ok(CompactTarget::from_consensus(real_bits))
+        );
+    }
+
+    #[test]
+    fn target_scaling_overflow() {
+        let limit = Network::Regtest.pow_limit();
+        assert_eq!(scale_target(limit, 1, 1), Some(limit));
+        assert_eq!(scale_target(limit, 4, 1), None);
+        // A slow regtest-like chain on mainnet rules is capped at the limit
+        let last = header(TARGET_TIMESPAN * 4, 0x1d00ffff);
+        assert_eq!(
+            calculate_next_work_required(Network::Mainnet, &last, 0),
+            Network::Mainnet.pow_limit().to_compact_lossy()
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::ValidTransactions;
+
+// Locktimes below this are block heights, the rest Unix times
+const LOCKTIME_THRESHOLD: u32 = 500_000_000;
+
+// An input with this sequence opts out of the transaction's locktime
+const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+
+// BIP68 sequence fields: the disable and type flags and the lock value, in
+// blocks or in units of 512 seconds
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: u32 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: u32 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: u32 = 0x0000_ffff;
+const SEQUENCE_LOCKTIME_GRANULARITY: u32 = 9;
+
+// Where a prevout confirmed: the block height and the median time past of the
+// block before it, which BIP68 measures time-based locks from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub struct Confirmation {
+    pub height: u32,
+    pub median_time_past: u32,
+}
+
+// Stands in for a prevout whose confirmation is unknown, which check_final
+// takes as old enough for any relative lock. Nothing real confirms at height 0:
+// the genesis coinbase cannot be spent.
+pub const MATURE: Confirmation = Confirmation {
+    height: 0,
+    median_time_past: 0,
+};
+
+// Why a transaction cannot go in the block yet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NonFinal {
+    // nLockTime is not yet past the height or median time past (BIP65/113)
+    LockTime,
+    // A relative locktime on an input has not matured (BIP68)
+    SequenceLock,
+}
+
+// Check that a transaction may go in the block at `height`, whose parent has
+// `median_time_past`, as Bitcoin Core's IsFinalTx and sequence locks do.
+// `prevouts[i]` is where input i's prevout confirmed: None for an unconfirmed
+// one, which would confirm in this block, or MATURE when it is not known.
+pub fn check_final(
+    tx: &ValidTransactions,
+    prevouts: &[Option<Confirmation>],
+    height: u32,
+    median_time_past: u32,
+) -> Result<(), NonFinal> {
+    // BIP113 compares time locks against the median time past, not the block time
+    let cutoff = if tx.lock_time < LOCKTIME_THRESHOLD {
+        height
+    } else {
+        median_time_past
+    };
+    if tx.lock_time != 0
+        && tx.lock_time >= cutoff
+        && tx
+            .sequences
+            .iter()
+            .any(|sequence| *sequence != SEQUENCE_FINAL)
+    {
+        return Err(NonFinal::LockTime);
+    }
+
+    // Relative locks only apply from version 2, compared unsigned as Core does
+    if (tx.version as u32) < 2 {
+        return Ok(());
+    }
+    for (sequence, prevout) in tx.sequences.iter().zip(prevouts) {
+        if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 || *prevout == Some(MATURE) {
+            continue;
+        }
+        let coin = prevout.unwrap_or(Confirmation {
+            height,
+            median_time_past,
+        });
+        let value = (sequence & SEQUENCE_LOCKTIME_MASK) as i64;
+        // The lock is met once the last block it excludes has passed
+        let matured = if sequence & SEQUENCE_LOCKTIME_TYPE_FLAG != 0 {
+            coin.median_time_past as i64 + (value << SEQUENCE_LOCKTIME_GRANULARITY) - 1
+                < median_time_past as i64
+        } else {
+            coin.height as i64 + value - 1 < height as i64
+        };
+        if !matured {
+            return Err(NonFinal::SequenceLock);
+        }
+    }
+    Ok(())
+}
+
+// Which of `valid_txs` may go in the block at `height`: those that are final
+// and whose in-mempool ancestors all are. Prints how many are held back.
+pub fn final_transactions(
+    valid_txs: &[ValidTransactions],
+    height: u32,
+    median_time_past: u32,
+) -> Vec<bool> {
+    let index_by_id: HashMap<&str, usize> = valid_txs
+        .iter()
+        .enumerate()
+        .map(|(index, tx)| (tx.id.as_str(), index))
+        .collect();
+
+    let mut lock_time_count = 0;
+    let mut sequence_lock_count = 0;
+    let mut eligible: Vec<bool> = valid_txs
+        .iter()
+        .map(|tx| {
+            // Parents still in the mempool would confirm in this block
+            let prevouts: Vec<Option<Confirmation>> = tx
+                .parents
+                .iter()
+                .zip(&tx.prevout_confirmations)
+                .map(|(parent, confirmation)| {
+                    if index_by_id.contains_key(parent.as_str()) {
+                        None
+                    } else {
+                        *confirmation
+                    }
+                })
+                .collect();
+            match check_final(tx, &prevouts, height, median_time_past) {
+                Ok(()) => true,
+                Err(NonFinal::LockTime) => {
+                    lock_time_count += 1;
+                    false
+                }
+                Err(NonFinal::SequenceLock) => {
+                    sequence_lock_count += 1;
+                    false
+                }
+            }
+        })
+        .collect();
+
+    // Hold back descendants of non-final transactions until nothing changes
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for (index, tx) in valid_txs.iter().enumerate() {
+            if eligible[index]
+                && tx.parents.iter().any(|parent| {
+                    index_by_id
+                        .get(parent.as_str())
+                        .is_some_and(|parent| !eligible[*parent])
+                })
+            {
+                eligible[index] = false;
+                changed = true;
+            }
+        }
+    }
+
+    let held_back = eligible.iter().filter(|eligible| !**eligible).count();
+    if held_back > 0 {
+        println!(
+            "msg: Holding back {} transactions not final at height {}: {} by locktime, {} by relative locktime, {} descendants",
+            held_back,
+            height,
+            lock_time_count,
+            sequence_lock_count,
+            held_back - lock_time_count - sequence_lock_count
+        );
+    }
+    eligible
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEIGHT: u32 = 1_000;
+    const MEDIAN_TIME_PAST: u32 = 1_600_000_000;
+
+    fn transaction(version: i32, lock_time: u32, sequences: &[u32]) -> ValidTransactions {
+        ValidTransactions {
+            id: String::new(),
+            hex: String::new(),
+            weight: 0,
+            fee: 0,
+            parents: Vec::new(),
+            sigop_cost: 0,
+            version,
+            lock_time,
+            sequences: sequences.to_vec(),
+            prevout_confirmations: Vec::new(),
+        }
+    }
+
+    struct Case {
+        name: &'static str,
+        version: i32,
+        lock_time: u32,
+        sequences: Vec<u32>,
+        prevouts: Vec<Option<Confirmation>>,
+        expected: Result<(), NonFinal>,
+    }
+
+    fn at(height: u32, median_time_past: u32) -> Option<Confirmation> {
+        Some(Confirmation {
+            height,
+            median_time_past,
+        })
+    }
+
+    #[test]
+    fn check_final_cases() {
+        use NonFinal::{LockTime, SequenceLock};
+        let time_lock = |units: u32| SEQUENCE_LOCKTIME_TYPE_FLAG | units;
+        let cases = [
+            Case {
+                name: "no locktime",
+                version: 1,
+                lock_time: 0,
+                sequences: vec![0],
+                prevouts: vec![None],
+                expected: Ok(()),
+            },
+            Case {
+                name: "height lock below the height",
+                version: 1,
+                lock_time: HEIGHT - 1,
+                sequences: vec![0xffff_fffe],
+                prevouts: vec![None],
+                expected: Ok(()),
+            },
+            Case {
+                name: "height lock at the height",
+                version: 1,
+                lock_time: HEIGHT,
+                sequences: vec![0xffff_fffe],
+                prevouts: vec![None],
+                expected: Err(LockTime),
+            },
+            Case {
+                name: "height lock above the height",
+                version: 1,
+                lock_time: HEIGHT + 500,
+                sequences: vec![0xffff_fffe],
+                prevouts: vec![None],
+                expected: Err(LockTime),
+            },
+            Case {
+                name: "largest height lock",
+                version: 1,
+                lock_time: LOCKTIME_THRESHOLD - 1,
+                sequences: vec![0],
+                prevouts: vec![None],
+                expected: Err(LockTime),
+            },
+            Case {
+                name: "time lock before the median time past",
+                version: 1,
+                lock_time: MEDIAN_TIME_PAST - 1,
+                sequences: vec![0],
+                prevouts: vec![None],
+                expected: Ok(()),
+            },
+            Case {
+                name: "time lock at the median time past",
+                version: 1,
+                lock_time: MEDIAN_TIME_PAST,
+                sequences: vec![0],
+                prevouts: vec![None],
+                expected: Err(LockTime),
+            },
+            Case {
+                name: "smallest time lock",
+                version: 1,
+                lock_time: LOCKTIME_THRESHOLD,
+                sequences: vec![0],
+                prevouts: vec![None],
+                expected: Ok(()),
+            },
+            Case {
+                name: "final sequences skip the locktime",
+                version: 1,
+                lock_time: HEIGHT + 500,
+                sequences: vec![SEQUENCE_FINAL, SEQUENCE_FINAL],
+                prevouts: vec![None, None],
+                expected: Ok(()),
+            },
+            Case {
+                name: "one non-final sequence keeps the locktime",
+                version: 1,
+                lock_time: HEIGHT + 500,
+                sequences: vec![SEQUENCE_FINAL, 0xffff_fffe],
+                prevouts: vec![None, None],
+                expected: Err(LockTime),
+            },
+            Case {
+                name: "height relative lock matured",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![10],
+                prevouts: vec![at(HEIGHT - 10, 0)],
+                expected: Ok(()),
+            },
+            Case {
+                name: "height relative lock one block short",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![10],
+                prevouts: vec![at(HEIGHT - 9, 0)],
+                expected: Err(SequenceLock),
+            },
+            Case {
+                name: "zero relative lock on an unconfirmed parent",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![0],
+                prevouts: vec![None],
+                expected: Ok(()),
+            },
+            Case {
+                name: "relative lock on an unconfirmed parent",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![1],
+                prevouts: vec![None],
+                expected: Err(SequenceLock),
+            },
+            Case {
+                name: "bits above the mask are ignored",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![(1 << 16) | 10],
+                prevouts: vec![at(HEIGHT - 10, 0)],
+                expected: Ok(()),
+            },
+            Case {
+                name: "disable flag",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![SEQUENCE_LOCKTIME_DISABLE_FLAG | 100],
+                prevouts: vec![None],
+                expected: Ok(()),
+            },
+            Case {
+                name: "final sequence disables relative locks",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![SEQUENCE_FINAL],
+                prevouts: vec![None],
+                expected: Ok(()),
+            },
+            Case {
+                name: "time relative lock matured",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![time_lock(2)],
+                prevouts: vec![at(HEIGHT, MEDIAN_TIME_PAST - 1024)],
+                expected: Ok(()),
+            },
+            Case {
+                name: "time relative lock one second short",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![time_lock(2)],
+                prevouts: vec![at(HEIGHT, MEDIAN_TIME_PAST - 1023)],
+                expected: Err(SequenceLock),
+            },
+            Case {
+                name: "time relative lock ignores heights",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![time_lock(2)],
+                prevouts: vec![at(1, MEDIAN_TIME_PAST - 1000)],
+                expected: Err(SequenceLock),
+            },
+            Case {
+                name: "unknown confirmations meet any lock",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![0xffff, time_lock(0xffff)],
+                prevouts: vec![Some(MATURE), Some(MATURE)],
+                expected: Ok(()),
+            },
+            Case {
+                name: "every input must mature",
+                version: 2,
+                lock_time: 0,
+                sequences: vec![10, 10],
+                prevouts: vec![at(HEIGHT - 10, 0), at(HEIGHT - 9, 0)],
+                expected: Err(SequenceLock),
+            },
+            Case {
+                name: "negative versions count as above 2",
+                version: -1,
+                lock_time: 0,
+                sequences: vec![1],
+                prevouts: vec![None],
+                expected: Err(SequenceLock),
+            },
+            Case {
+                name: "version 1 skips relative locks",
+                version: 1,
+                lock_time: 0,
+                sequences: vec![100],
+                prevouts: vec![None],
+                expected: Ok(()),
+            },
+        ];
+        for case in cases {
+            let tx = transaction(case.version, case.lock_time, &case.sequences);
+            assert_eq!(
+                check_final(&tx, &case.prevouts, HEIGHT, MEDIAN_TIME_PAST),
+                case.expected,
+                "{}",
+                case.name
+            );
+        }
+    }
+
+    #[test]
+    fn descendants_of_non_final_are_held_back() {
+        let mut locked = transaction(2, HEIGHT + 1, &[0]);
+        locked.id = "locked".to_string();
+        let mut child = transaction(2, 0, &[SEQUENCE_FINAL]);
+        child.id = "child".to_string();
+        child.parents = vec!["locked".to_string()];
+        child.prevout_confirmations = vec![None];
+        // The parent is in the set, so its recorded confirmation is ignored and
+        // the one-block lock cannot be met in this block
+        let mut relative = transaction(2, 0, &[1]);
+        relative.id = "relative".to_string();
+        relative.parents = vec!["unlocked".to_string()];
+        relative.prevout_confirmations = vec![at(HEIGHT - 5, 0)];
+        let mut unlocked = transaction(2, 0, &[SEQUENCE_FINAL]);
+        unlocked.id = "unlocked".to_string();
+
+        let txs = [locked, child, relative, unlocked];
+        assert_eq!(
+            final_transactions(&txs, HEIGHT, MEDIAN_TIME_PAST),
+            vec![false, false, false, true]
+        );
+        assert_eq!(
+            final_transactions(&txs[2..3], HEIGHT, MEDIAN_TIME_PAST),
+            vec![true]
+        );
+    }
+}
@@ -0,0 +1,402 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Mutex,
+    thread,
+};
+
+use bitcoincore_rpc::{
+    Client, RpcApi,
+    bitcoin::{Block, Target, consensus},
+};
+use serde_json::{Value, json};
+
+use crate::{
+    MAX_BLOCK_WEIGHT, WITNESS_COMMITMENT_HEADER,
+    chain::{self, ChainState},
+    difficulty::Network,
+    sigops::{self, MAX_BLOCK_SIGOPS_COST},
+};
+
+// JSON-RPC error codes used by Bitcoin Core
+const RPC_MISC_ERROR: i64 = -1;
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+const RPC_INVALID_PARAMETER: i64 = -8;
+const RPC_DESERIALIZATION_ERROR: i64 = -22;
+
+// Largest request body read: a block of MAX_BLOCK_WEIGHT bytes in hex, which
+// no valid block exceeds, plus room for the JSON-RPC envelope
+const MAX_REQUEST_BODY_SIZE: usize = 2 * MAX_BLOCK_WEIGHT as usize + 1_000_000;
+
+// Longest request or header line read, terminator included
+const MAX_HEADER_LINE_SIZE: usize = 8_192;
+
+// A getblocktemplate (BIP22/23) and submitblock server over the selector.
+// Submitted blocks are checked against the mempool prevouts and relayed to
+// `node` when there is one, and an accepted block becomes the new tip.
+pub struct TemplateServer<'a> {
+    state: Mutex<ChainState<'a>>,
+    node: Option<Client>,
+}
+
+impl<'a> TemplateServer<'a> {
+    pub fn new(chain: ChainState<'a>, node: Option<Client>) -> TemplateServer<'a> {
+        TemplateServer {
+            state: Mutex::new(chain),
+            node,
+        }
+    }
+
+    // Accept connections on `addr` until the process exits, one thread per client
+    pub fn serve(&self, addr: &str) -> Result<(), String> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("Failed to bind {}: {}", addr, e))?;
+        println!(
+            "msg: Serving getblocktemplate and submitblock on {}",
+            listener.local_addr().map_err(|e| e.to_string())?
+        );
+
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        scope.spawn(move || {
+                            if let Err(e) = self.handle_connection(stream) {
+                                println!("msg: Connection error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => println!("msg: Failed to accept connection: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Answer HTTP POSTs on one connection until the client closes it
+    fn handle_connection(&self, stream: TcpStream) -> Result<(), String> {
+        let mut reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        let mut writer = stream;
+        while let Some((body, keep_alive)) = read_http_request(&mut reader)? {
+            let response = match serde_json::from_slice::<Value>(&body) {
+                Ok(Value::Array(requests)) => {
+                    Value::Array(requests.iter().map(|request| self.call(request)).collect())
+                }
+                Ok(request) => self.call(&request),
+                Err(e) => json!({
+                    "result": null,
+                    "error": { "code": -32700, "message": format!("Parse error: {}", e) },
+                    "id": null,
+                }),
+            };
+            let body = response.to_string();
+            write!(
+                writer,
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            )
+            .map_err(|e| e.to_string())?;
+            if !keep_alive {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Dispatch one JSON-RPC request
+    fn call(&self, request: &Value) -> Value {
+        let params = request["params"].as_array().cloned().unwrap_or_default();
+        let result = match request["method"].as_str().unwrap_or_default() {
+            "getblocktemplate" => self.get_block_template(params.first()),
+            "submitblock" => self.submit_block(params.first()),
+            method => Err((
+                RPC_METHOD_NOT_FOUND,
+                format!("Method not found: {}", method),
+            )),
+        };
+        match result {
+            Ok(result) => json!({ "result": result, "error": null, "id": request["id"] }),
+            Err((code, message)) => json!({
+                "result": null,
+                "error": { "code": code, "message": message },
+                "id": request["id"],
+            }),
+        }
+    }
+
+    // BIP22 template mode, or BIP23 proposal mode when the request carries a block
+    fn get_block_template(&self, request: Option<&Value>) -> Result<Value, (i64, String)> {
+        let mode = request
+            .and_then(|request| request["mode"].as_str())
+            .unwrap_or("template");
+        let state = self.state.lock().unwrap();
+        match mode {
+            "template" => template_json(&state).map_err(|e| (RPC_MISC_ERROR, e)),
+            "proposal" => {
+                let block = decode_block(request.and_then(|request| request.get("data")))?;
+                // Proposals are checked without proof of work
+                Ok(state
+                    .check_block(&block, false)
+                    .err()
+                    .map_or(Value::Null, Value::from))
+            }
+            _ => Err((RPC_INVALID_PARAMETER, format!("Invalid mode: {}", mode))),
+        }
+    }
+
+    // Returns null when the block is accepted, otherwise the reject reason.
+    // With a node, its submitblock result is returned and the block only
+    // becomes the local tip once the node has accepted it. The chain is not
+    // locked while the node is busy, so a block that no longer builds on the
+    // tip by then is reported as inconclusive, as Core does for a valid block
+    // off its best chain.
+    fn submit_block(&self, data: Option<&Value>) -> Result<Value, (i64, String)> {
+        let block = decode_block(data)?;
+        if let Err(reason) = self.state.lock().unwrap().check_block(&block, true) {
+            println!("msg: Rejected block {}: {}", block.block_hash(), reason);
+            return Ok(Value::from(reason));
+        }
+
+        if let Some(node) = &self.node {
+            let result: Value = node
+                .call(
+                    "submitblock",
+                    &[Value::from(consensus::encode::serialize_hex(&block))],
+                )
+                .map_err(|e| (RPC_MISC_ERROR, format!("Failed to relay block: {}", e)))?;
+            if !result.is_null() {
+                println!(
+                    "msg: Node rejected block {}: {}",
+                    block.block_hash(),
+                    result
+                );
+                return Ok(result);
+            }
+            println!("msg: Relayed block {} to the node", block.block_hash());
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if block.header.prev_blockhash != state.params.previous_hash {
+            println!(
+                "msg: Tip moved before block {} was accepted",
+                block.block_hash()
+            );
+            return Ok(Value::from("inconclusive"));
+        }
+        state.accept_block(&block);
+        Ok(Value::Null)
+    }
+}
+
+// The getblocktemplate result for the current template, as Bitcoin Core lays
+// it out. `depends` lists 1-based positions of in-template parents.
+fn template_json(state: &ChainState) -> Result<Value, String> {
+    let template = state.template()?;
+    let block = &template.block;
+    let position: HashMap<&str, usize> = template
+        .selected
+        .iter()
+        .enumerate()
+        .map(|(position, index)| (state.valid_txs[*index].id.as_str(), position + 1))
+        .collect();
+
+    let transactions: Vec<Value> = block.txdata[1..]
+        .iter()
+        .zip(&template.selected)
+        .map(|(tx, index)| {
+            let tx_data = &state.valid_txs[*index];
+            let depends: BTreeSet<usize> = tx_data
+                .parents
+                .iter()
+                .filter_map(|parent| position.get(parent.as_str()).copied())
+                .collect();
+            json!({
+                "data": tx_data.hex,
+                "txid": tx.compute_txid().to_string(),
+                "hash": tx.compute_wtxid().to_string(),
+                "depends": depends,
+                "fee": tx_data.fee,
+                "sigops": tx_data.sigop_cost,
+                "weight": tx_data.weight,
+            })
+        })
+        .collect();
+
+    let coinbase_tx = &block.txdata[0];
+    let curtime = chain::unix_time().max(state.params.time);
+
+    // Active deployments in Bitcoin Core's order; `!` marks the ones a client
+    // must understand to use the template. No versionbits deployment is
+    // signalling, so the version carries only the BIP9 top bits and
+    // vbavailable is empty.
+    let mut rules = vec!["csv", "!segwit"];
+    if state.params.network == Network::Signet {
+        rules.push("!signet");
+    }
+    rules.push("taproot");
+
+    let mut result = json!({
+        "capabilities": ["proposal"],
+        "version": block.header.version.to_consensus(),
+        "rules": rules,
+        "vbavailable": {},
+        "vbrequired": 0,
+        "previousblockhash": state.params.previous_hash.to_string(),
+        "transactions": transactions,
+        "coinbaseaux": {},
+        "coinbasevalue": (template.subsidy + template.fees).to_sat(),
+        "coinbasetxn": {
+            "data": consensus::encode::serialize_hex(coinbase_tx),
+            "txid": coinbase_tx.compute_txid().to_string(),
+            "hash": coinbase_tx.compute_wtxid().to_string(),
+            "depends": [],
+            "fee": 0,
+            "sigops": sigops::legacy_sigop_cost(coinbase_tx),
+            "weight": coinbase_tx.weight().to_wu(),
+        },
+        "target": format!("{:x}", Target::from_compact(block.header.bits)),
+        "mintime": state
+            .headers
+            .median_time_past()
+            .map_or(state.params.time, |median| median + 1),
+        "mutable": ["time", "transactions", "prevblock"],
+        "noncerange": "00000000ffffffff",
+        "sigoplimit": MAX_BLOCK_SIGOPS_COST,
+        "sizelimit": MAX_BLOCK_WEIGHT,
+        "weightlimit": MAX_BLOCK_WEIGHT,
+        "curtime": curtime,
+        "bits": format!("{:08x}", block.header.bits.to_consensus()),
+        "height": template.height,
+    });
+    if let Some(commitment) = template.witness_commitment {
+        let mut script = WITNESS_COMMITMENT_HEADER.to_vec();
+        script.extend_from_slice(&commitment);
+        result["default_witness_commitment"] = Value::from(hex::encode(script));
+    }
+    Ok(result)
+}
+
+fn decode_block(data: Option<&Value>) -> Result<Block, (i64, String)> {
+    let data = data
+        .and_then(Value::as_str)
+        .ok_or((RPC_INVALID_PARAMETER, "Missing block data".to_string()))?;
+    hex::decode(data)
+        .ok()
+        .and_then(|bytes| consensus::deserialize(&bytes).ok())
+        .ok_or((RPC_DESERIALIZATION_ERROR, "Block decode failed".to_string()))
+}
+
+// Read one HTTP request, returning its body and whether the connection stays
+// open, or None once the client has closed it
+fn read_http_request(reader: &mut impl BufRead) -> Result<Option<(Vec<u8>, bool)>, String> {
+    let mut request_line = String::new();
+    if read_line(reader, &mut request_line)? == 0 {
+        return Ok(None);
+    }
+    let mut keep_alive = !request_line.trim_end().ends_with("HTTP/1.0");
+
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        if read_line(reader, &mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = value
+                .parse()
+                .map_err(|_| format!("Invalid Content-Length: {}", value))?;
+        } else if name.eq_ignore_ascii_case("connection") {
+            keep_alive = value.eq_ignore_ascii_case("keep-alive")
+                || (keep_alive && !value.eq_ignore_ascii_case("close"));
+        }
+    }
+
+    if content_length > MAX_REQUEST_BODY_SIZE {
+        return Err(format!(
+            "Request body of {} bytes exceeds {}",
+            content_length, MAX_REQUEST_BODY_SIZE
+        ));
+    }
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body).map_err(|e| e.to_string())?;
+    Ok(Some((body, keep_alive)))
+}
+
+// read_line, failing on a line longer than MAX_HEADER_LINE_SIZE instead of
+// buffering it
+fn read_line(reader: &mut impl BufRead, line: &mut String) -> Result<usize, String> {
+    let read = Read::take(&mut *reader, MAX_HEADER_LINE_SIZE as u64)
+        .read_line(line)
+        .map_err(|e| e.to_string())?;
+    if read == MAX_HEADER_LINE_SIZE && !line.ends_with('\n') {
+        return Err(format!(
+            "Header line exceeds {} bytes",
+            MAX_HEADER_LINE_SIZE
+        ));
+    }
+    Ok(read)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn reads_request_body() {
+        let mut reader = Cursor::new(
+            "POST / HTTP/1.1\r\nContent-Length: 4\r\n\r\nabcdPOST / HTTP/1.0\r\ncontent-length: 2\r\n\r\nef"
+                .as_bytes(),
+        );
+        assert_eq!(
+            read_http_request(&mut reader),
+            Ok(Some((b"abcd".to_vec(), true)))
+        );
+        assert_eq!(
+            read_http_request(&mut reader),
+            Ok(Some((b"ef".to_vec(), false)))
+        );
+        assert_eq!(read_http_request(&mut reader), Ok(None));
+    }
+
+    #[test]
+    fn rejects_oversized_body() {
+        let request = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_REQUEST_BODY_SIZE + 1
+        );
+        assert!(read_http_request(&mut Cursor::new(request.as_bytes())).is_err());
+        // A length that could never be allocated fails the same way
+        let request = format!("POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        assert!(read_http_request(&mut Cursor::new(request.as_bytes())).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_header_line() {
+        let request = format!(
+            "POST / HTTP/1.1\r\nX-Padding: {}\r\nContent-Length: 2\r\n\r\nab",
+            "a".repeat(MAX_HEADER_LINE_SIZE)
+        );
+        assert!(read_http_request(&mut Cursor::new(request.as_bytes())).is_err());
+        // A line that fits exactly is read
+        let request = format!(
+            "POST / HTTP/1.1\r\nX-Padding: {}\r\nContent-Length: 2\r\n\r\nab",
+            "a".repeat(MAX_HEADER_LINE_SIZE - "X-Padding: \r\n".len())
+        );
+        assert_eq!(
+            read_http_request(&mut Cursor::new(request.as_bytes())),
+            Ok(Some((b"ab".to_vec(), true)))
+        );
+    }
+}
@@ -0,0 +1,1981 @@
+use std::fmt;
+
+use bitcoincore_rpc::bitcoin::{
+    Script, Transaction, TxOut, Witness, XOnlyPublicKey,
+    consensus::Encodable,
+    hashes::{Hash, HashEngine, hash160, ripemd160, sha1, sha256, sha256d},
+    key::{Parity, Secp256k1},
+    secp256k1::{self, Message, VerifyOnly, ecdsa, schnorr},
+    sighash::{Annex, Prevouts, SighashCache, TapSighash, TapSighashType},
+    taproot::{TapLeafHash, TapNodeHash, TapTweakHash},
+};
+
+// Consensus limits, as in Bitcoin Core's script/script.h
+pub const MAX_SCRIPT_SIZE: usize = 10_000;
+const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+const MAX_OPS_PER_SCRIPT: usize = 201;
+const MAX_STACK_SIZE: usize = 1_000;
+const MAX_PUBKEYS_PER_MULTISIG: i64 = 20;
+const LOCKTIME_THRESHOLD: i64 = 500_000_000;
+
+// BIP342 signature operation budget
+const VALIDATION_WEIGHT_PER_SIGOP_PASSED: i64 = 50;
+const VALIDATION_WEIGHT_OFFSET: i64 = 50;
+
+// BIP341 constants
+pub const TAPROOT_LEAF_MASK: u8 = 0xfe;
+pub const TAPROOT_LEAF_TAPSCRIPT: u8 = 0xc0;
+const TAPROOT_CONTROL_BASE_SIZE: usize = 33;
+const TAPROOT_CONTROL_NODE_SIZE: usize = 32;
+const TAPROOT_CONTROL_MAX_NODE_COUNT: usize = 128;
+pub const ANNEX_TAG: u8 = 0x50;
+
+// BIP68/BIP112 sequence number fields
+const SEQUENCE_FINAL: u32 = 0xffff_ffff;
+const SEQUENCE_LOCKTIME_DISABLE_FLAG: i64 = 1 << 31;
+const SEQUENCE_LOCKTIME_TYPE_FLAG: i64 = 1 << 22;
+const SEQUENCE_LOCKTIME_MASK: i64 = 0x0000_ffff;
+
+// Opcodes the interpreter dispatches on
+const OP_0: u8 = 0x00;
+const OP_PUSHDATA1: u8 = 0x4c;
+const OP_PUSHDATA2: u8 = 0x4d;
+const OP_PUSHDATA4: u8 = 0x4e;
+const OP_1NEGATE: u8 = 0x4f;
+const OP_1: u8 = 0x51;
+const OP_16: u8 = 0x60;
+const OP_NOP: u8 = 0x61;
+const OP_IF: u8 = 0x63;
+const OP_NOTIF: u8 = 0x64;
+const OP_ELSE: u8 = 0x67;
+const OP_ENDIF: u8 = 0x68;
+const OP_VERIFY: u8 = 0x69;
+const OP_RETURN: u8 = 0x6a;
+const OP_TOALTSTACK: u8 = 0x6b;
+const OP_FROMALTSTACK: u8 = 0x6c;
+const OP_2DROP: u8 = 0x6d;
+const OP_2DUP: u8 = 0x6e;
+const OP_3DUP: u8 = 0x6f;
+const OP_2OVER: u8 = 0x70;
+const OP_2ROT: u8 = 0x71;
+const OP_2SWAP: u8 = 0x72;
+const OP_IFDUP: u8 = 0x73;
+const OP_DEPTH: u8 = 0x74;
+const OP_DROP: u8 = 0x75;
+const OP_DUP: u8 = 0x76;
+const OP_NIP: u8 = 0x77;
+const OP_OVER: u8 = 0x78;
+const OP_PICK: u8 = 0x79;
+const OP_ROLL: u8 = 0x7a;
+const OP_ROT: u8 = 0x7b;
+const OP_SWAP: u8 = 0x7c;
+const OP_TUCK: u8 = 0x7d;
+const OP_CAT: u8 = 0x7e;
+const OP_SUBSTR: u8 = 0x7f;
+const OP_LEFT: u8 = 0x80;
+const OP_RIGHT: u8 = 0x81;
+const OP_SIZE: u8 = 0x82;
+const OP_INVERT: u8 = 0x83;
+const OP_AND: u8 = 0x84;
+const OP_OR: u8 = 0x85;
+const OP_XOR: u8 = 0x86;
+const OP_EQUAL: u8 = 0x87;
+const OP_EQUALVERIFY: u8 = 0x88;
+const OP_1ADD: u8 = 0x8b;
+const OP_1SUB: u8 = 0x8c;
+const OP_2MUL: u8 = 0x8d;
+const OP_2DIV: u8 = 0x8e;
+const OP_NEGATE: u8 = 0x8f;
+const OP_ABS: u8 = 0x90;
+const OP_NOT: u8 = 0x91;
+const OP_0NOTEQUAL: u8 = 0x92;
+const OP_ADD: u8 = 0x93;
+const OP_SUB: u8 = 0x94;
+const OP_MUL: u8 = 0x95;
+const OP_DIV: u8 = 0x96;
+const OP_MOD: u8 = 0x97;
+const OP_LSHIFT: u8 = 0x98;
+const OP_RSHIFT: u8 = 0x99;
+const OP_BOOLAND: u8 = 0x9a;
+const OP_BOOLOR: u8 = 0x9b;
+const OP_NUMEQUAL: u8 = 0x9c;
+const OP_NUMEQUALVERIFY: u8 = 0x9d;
+const OP_NUMNOTEQUAL: u8 = 0x9e;
+const OP_LESSTHAN: u8 = 0x9f;
+const OP_GREATERTHAN: u8 = 0xa0;
+const OP_LESSTHANOREQUAL: u8 = 0xa1;
+const OP_GREATERTHANOREQUAL: u8 = 0xa2;
+const OP_MIN: u8 = 0xa3;
+const OP_MAX: u8 = 0xa4;
+const OP_WITHIN: u8 = 0xa5;
+const OP_RIPEMD160: u8 = 0xa6;
+const OP_SHA1: u8 = 0xa7;
+const OP_SHA256: u8 = 0xa8;
+const OP_HASH160: u8 = 0xa9;
+const OP_HASH256: u8 = 0xaa;
+const OP_CODESEPARATOR: u8 = 0xab;
+const OP_CHECKSIG: u8 = 0xac;
+const OP_CHECKSIGVERIFY: u8 = 0xad;
+const OP_CHECKMULTISIG: u8 = 0xae;
+const OP_CHECKMULTISIGVERIFY: u8 = 0xaf;
+const OP_NOP1: u8 = 0xb0;
+const OP_CHECKLOCKTIMEVERIFY: u8 = 0xb1;
+const OP_CHECKSEQUENCEVERIFY: u8 = 0xb2;
+const OP_NOP4: u8 = 0xb3;
+const OP_NOP10: u8 = 0xb9;
+const OP_CHECKSIGADD: u8 = 0xba;
+
+// Precise reason a script failed, mirroring Bitcoin Core's ScriptError_t
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScriptError {
+    EvalFalse,
+    OpReturn,
+    ScriptSize,
+    PushSize,
+    OpCount,
+    StackSize,
+    SigCount,
+    PubkeyCount,
+    Verify,
+    EqualVerify,
+    CheckMultisigVerify,
+    CheckSigVerify,
+    NumEqualVerify,
+    BadOpcode,
+    DisabledOpcode,
+    InvalidStackOperation,
+    InvalidAltstackOperation,
+    UnbalancedConditional,
+    NumOverflow,
+    NegativeLocktime,
+    UnsatisfiedLocktime,
+    SigDer,
+    SigPushOnly,
+    SigNullDummy,
+    PubkeyType,
+    CleanStack,
+    WitnessProgramWrongLength,
+    WitnessProgramWitnessEmpty,
+    WitnessProgramMismatch,
+    WitnessMalleated,
+    WitnessMalleatedP2sh,
+    WitnessUnexpected,
+    SchnorrSigSize,
+    SchnorrSigHashtype,
+    SchnorrSig,
+    TaprootWrongControlSize,
+    TapscriptValidationWeight,
+    TapscriptCheckMultisig,
+    TapscriptMinimalIf,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason = match self {
+            ScriptError::EvalFalse => {
+                "script evaluated without error but finished with a false/empty top stack element"
+            }
+            ScriptError::OpReturn => "OP_RETURN was encountered",
+            ScriptError::ScriptSize => "script is too big",
+            ScriptError::PushSize => "push value size limit exceeded",
+            ScriptError::OpCount => "operation limit exceeded",
+            ScriptError::StackSize => "stack size limit exceeded",
+            ScriptError::SigCount => "signature count negative or greater than pubkey count",
+            ScriptError::PubkeyCount => "pubkey count negative or limit exceeded",
+            ScriptError::Verify => "script failed an OP_VERIFY operation",
+            ScriptError::EqualVerify => "script failed an OP_EQUALVERIFY operation",
+            ScriptError::CheckMultisigVerify => "script failed an OP_CHECKMULTISIGVERIFY operation",
+            ScriptError::CheckSigVerify => "script failed an OP_CHECKSIGVERIFY operation",
+            ScriptError::NumEqualVerify => "script failed an OP_NUMEQUALVERIFY operation",
+            ScriptError::BadOpcode => "opcode missing or not understood",
+            ScriptError::DisabledOpcode => "attempted to use a disabled opcode",
+            ScriptError::InvalidStackOperation => "operation not valid with the current stack size",
+            ScriptError::InvalidAltstackOperation => {
+                "operation not valid with the current altstack size"
+            }
+            ScriptError::UnbalancedConditional => "invalid OP_IF construction",
+            ScriptError::NumOverflow => "script number overflow",
+            ScriptError::NegativeLocktime => "negative locktime",
+            ScriptError::UnsatisfiedLocktime => "locktime requirement not satisfied",
+            ScriptError::SigDer => "non-canonical DER signature",
+            ScriptError::SigPushOnly => "only push operators allowed in signatures",
+            ScriptError::SigNullDummy => "dummy CHECKMULTISIG argument must be zero",
+            ScriptError::PubkeyType => "public key is neither compressed or uncompressed",
+            ScriptError::CleanStack => "stack size must be exactly one after execution",
+            ScriptError::WitnessProgramWrongLength => "witness program has incorrect length",
+            ScriptError::WitnessProgramWitnessEmpty => {
+                "witness program was passed an empty witness"
+            }
+            ScriptError::WitnessProgramMismatch => "witness program hash mismatch",
+            ScriptError::WitnessMalleated => "witness requires empty scriptSig",
+            ScriptError::WitnessMalleatedP2sh => "witness requires only-redeemscript scriptSig",
+            ScriptError::WitnessUnexpected => "witness provided for non-witness script",
+            ScriptError::SchnorrSigSize => "invalid Schnorr signature size",
+            ScriptError::SchnorrSigHashtype => "invalid Schnorr signature hash type",
+            ScriptError::SchnorrSig => "invalid Schnorr signature",
+            ScriptError::TaprootWrongControlSize => "invalid Taproot control block size",
+            ScriptError::TapscriptValidationWeight => {
+                "too much signature validation relative to witness weight"
+            }
+            ScriptError::TapscriptCheckMultisig => {
+                "OP_CHECKMULTISIG(VERIFY) is not available in tapscript"
+            }
+            ScriptError::TapscriptMinimalIf => "OP_IF/NOTIF argument must be minimal in tapscript",
+        };
+        f.write_str(reason)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SigVersion {
+    Base,
+    WitnessV0,
+    Taproot,
+    Tapscript,
+}
+
+// Per-input execution state shared between the script and signature checks
+struct ExecData {
+    annex: Option<Vec<u8>>,
+    tapleaf_hash: Option<TapLeafHash>,
+    codeseparator_pos: u32,
+    validation_weight_left: i64,
+}
+
+impl ExecData {
+    fn new() -> Self {
+        ExecData {
+            annex: None,
+            tapleaf_hash: None,
+            codeseparator_pos: u32::MAX,
+            validation_weight_left: 0,
+        }
+    }
+}
+
+// Signature, locktime and sequence checks against the spending transaction
+struct Checker<'a> {
+    secp: &'a Secp256k1<VerifyOnly>,
+    tx: &'a Transaction,
+    index: usize,
+    prevouts: &'a [TxOut],
+    cache: SighashCache<&'a Transaction>,
+}
+
+type Stack = Vec<Vec<u8>>;
+
+// Verify every input of `tx` against the outputs it spends, returning one
+// result per input
+pub fn verify_transaction(tx: &Transaction, prevouts: &[TxOut]) -> Vec<Result<(), ScriptError>> {
+    let secp = Secp256k1::verification_only();
+    (0..tx.input.len())
+        .map(|index| {
+            let mut checker = Checker {
+                secp: &secp,
+                tx,
+                index,
+                prevouts,
+                cache: SighashCache::new(tx),
+            };
+            verify_input(&mut checker)
+        })
+        .collect()
+}
+
+// Bitcoin Core's VerifyScript with the consensus flags active on mainnet today
+fn verify_input(checker: &mut Checker) -> Result<(), ScriptError> {
+    let input = &checker.tx.input[checker.index];
+    let script_sig = input.script_sig.as_bytes();
+    let script_pubkey = checker.prevouts[checker.index].script_pubkey.as_bytes();
+    let witness: Stack = input.witness.to_vec();
+
+    let mut stack = Stack::new();
+    eval_script(
+        &mut stack,
+        script_sig,
+        SigVersion::Base,
+        checker,
+        &mut ExecData::new(),
+    )?;
+    let stack_copy = stack.clone();
+    eval_script(
+        &mut stack,
+        script_pubkey,
+        SigVersion::Base,
+        checker,
+        &mut ExecData::new(),
+    )?;
+    if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+        return Err(ScriptError::EvalFalse);
+    }
+
+    let mut had_witness = false;
+    if let Some((version, program)) = witness_program(script_pubkey) {
+        had_witness = true;
+        if !script_sig.is_empty() {
+            return Err(ScriptError::WitnessMalleated);
+        }
+        verify_witness_program(&witness, version, program, false, checker)?;
+    }
+
+    if is_p2sh(script_pubkey) {
+        if !is_push_only(script_sig) {
+            return Err(ScriptError::SigPushOnly);
+        }
+        stack = stack_copy;
+        // The scriptSig evaluated to true above, so the stack cannot be empty
+        let redeem_script = stack.pop().ok_or(ScriptError::EvalFalse)?;
+        eval_script(
+            &mut stack,
+            &redeem_script,
+            SigVersion::Base,
+            checker,
+            &mut ExecData::new(),
+        )?;
+        if !stack.last().is_some_and(|top| cast_to_bool(top)) {
+            return Err(ScriptError::EvalFalse);
+        }
+
+        if let Some((version, program)) = witness_program(&redeem_script) {
+            had_witness = true;
+            if script_sig != push_data(&redeem_script).as_slice() {
+                return Err(ScriptError::WitnessMalleatedP2sh);
+            }
+            verify_witness_program(&witness, version, program, true, checker)?;
+        }
+    }
+
+    if !had_witness && !witness.is_empty() {
+        return Err(ScriptError::WitnessUnexpected);
+    }
+
+    Ok(())
+}
+
+fn verify_witness_program(
+    witness: &Stack,
+    version: u8,
+    program: &[u8],
+    is_p2sh: bool,
+    checker: &mut Checker,
+) -> Result<(), ScriptError> {
+    let mut stack = witness.clone();
+
+    if version == 0 {
+        if program.len() == 32 {
+            // P2WSH: the last witness item is the script, committed to by SHA256
+            let script = stack.pop().ok_or(ScriptError::WitnessProgramWitnessEmpty)?;
+            if sha256::Hash::hash(&script).as_byte_array() != program {
+                return Err(ScriptError::WitnessProgramMismatch);
+            }
+            return execute_witness_script(
+                stack,
+                &script,
+                SigVersion::WitnessV0,
+                checker,
+                &mut ExecData::new(),
+            );
+        }
+        if program.len() == 20 {
+            // P2WPKH: exactly <sig> <pubkey> against the implied P2PKH script
+            if stack.len() != 2 {
+                return Err(ScriptError::WitnessProgramMismatch);
+            }
+            let mut script = vec![OP_DUP, OP_HASH160, 20];
+            script.extend_from_slice(program);
+            script.extend_from_slice(&[OP_EQUALVERIFY, OP_CHECKSIG]);
+            return execute_witness_script(
+                stack,
+                &script,
+                SigVersion::WitnessV0,
+                checker,
+                &mut ExecData::new(),
+            );
+        }
+        return Err(ScriptError::WitnessProgramWrongLength);
+    }
+
+    if version == 1 && program.len() == 32 && !is_p2sh {
+        if stack.is_empty() {
+            return Err(ScriptError::WitnessProgramWitnessEmpty);
+        }
+        let mut exec = ExecData::new();
+        if stack.len() >= 2
+            && stack
+                .last()
+                .is_some_and(|item| item.first() == Some(&ANNEX_TAG))
+        {
+            exec.annex = stack.pop();
+        }
+
+        if stack.len() == 1 {
+            // Key path spend
+            return check_schnorr_signature(
+                &stack[0],
+                program,
+                SigVersion::Taproot,
+                &exec,
+                checker,
+            );
+        }
+
+        // Script path spend
+        let control = stack.pop().unwrap();
+        let script = stack.pop().unwrap();
+        if control.len() < TAPROOT_CONTROL_BASE_SIZE
+            || control.len()
+                > TAPROOT_CONTROL_BASE_SIZE
+                    + TAPROOT_CONTROL_NODE_SIZE * TAPROOT_CONTROL_MAX_NODE_COUNT
+            || !(control.len() - TAPROOT_CONTROL_BASE_SIZE)
+                .is_multiple_of(TAPROOT_CONTROL_NODE_SIZE)
+        {
+            return Err(ScriptError::TaprootWrongControlSize);
+        }
+        let leaf_version = control[0] & TAPROOT_LEAF_MASK;
+        let tapleaf_hash = tapleaf_hash(leaf_version, &script);
+        if !verify_taproot_commitment(&control, program, tapleaf_hash, checker.secp) {
+            return Err(ScriptError::WitnessProgramMismatch);
+        }
+        if leaf_version != TAPROOT_LEAF_TAPSCRIPT {
+            // Unknown leaf versions are reserved for future soft forks and always succeed
+            return Ok(());
+        }
+
+        // Any OP_SUCCESSx makes the whole script succeed, provided it parses up to that point
+        let mut pc = 0;
+        while pc < script.len() {
+            let (opcode, _) = read_op(&script, &mut pc)?;
+            if is_op_success(opcode) {
+                return Ok(());
+            }
+        }
+
+        exec.tapleaf_hash = Some(tapleaf_hash);
+        exec.validation_weight_left =
+            checker.tx.input[checker.index].witness.size() as i64 + VALIDATION_WEIGHT_OFFSET;
+        return execute_witness_script(stack, &script, SigVersion::Tapscript, checker, &mut exec);
+    }
+
+    // Higher versions and other program lengths are left for future soft forks
+    Ok(())
+}
+
+fn execute_witness_script(
+    mut stack: Stack,
+    script: &[u8],
+    sig_version: SigVersion,
+    checker: &mut Checker,
+    exec: &mut ExecData,
+) -> Result<(), ScriptError> {
+    if sig_version == SigVersion::Tapscript && stack.len() > MAX_STACK_SIZE {
+        return Err(ScriptError::StackSize);
+    }
+    if stack
+        .iter()
+        .any(|item| item.len() > MAX_SCRIPT_ELEMENT_SIZE)
+    {
+        return Err(ScriptError::PushSize);
+    }
+
+    eval_script(&mut stack, script, sig_version, checker, exec)?;
+
+    // Witness scripts implicitly require a clean stack
+    if stack.len() != 1 {
+        return Err(ScriptError::CleanStack);
+    }
+    if !cast_to_bool(&stack[0]) {
+        return Err(ScriptError::EvalFalse);
+    }
+    Ok(())
+}
+
+// Bitcoin Core's EvalScript
+fn eval_script(
+    stack: &mut Stack,
+    script: &[u8],
+    sig_version: SigVersion,
+    checker: &mut Checker,
+    exec: &mut ExecData,
+) -> Result<(), ScriptError> {
+    let is_tapscript = sig_version == SigVersion::Tapscript;
+    if !is_tapscript && script.len() > MAX_SCRIPT_SIZE {
+        return Err(ScriptError::ScriptSize);
+    }
+
+    let mut pc = 0;
+    let mut begin_code = 0;
+    let mut exec_stack: Vec<bool> = Vec::new();
+    let mut alt_stack = Stack::new();
+    let mut op_count = 0;
+    let mut opcode_pos: u32 = 0;
+
+    while pc < script.len() {
+        let executing = exec_stack.iter().all(|branch| *branch);
+        let (opcode, push) = read_op(script, &mut pc)?;
+        let this_opcode_pos = opcode_pos;
+        opcode_pos += 1;
+
+        if push.is_some_and(|data| data.len() > MAX_SCRIPT_ELEMENT_SIZE) {
+            return Err(ScriptError::PushSize);
+        }
+        if !is_tapscript && opcode > OP_16 {
+            op_count += 1;
+            if op_count > MAX_OPS_PER_SCRIPT {
+                return Err(ScriptError::OpCount);
+            }
+        }
+        // Disabled opcodes fail even in an unexecuted branch
+        if matches!(
+            opcode,
+            OP_CAT
+                | OP_SUBSTR
+                | OP_LEFT
+                | OP_RIGHT
+                | OP_INVERT
+                | OP_AND
+                | OP_OR
+                | OP_XOR
+                | OP_2MUL
+                | OP_2DIV
+                | OP_MUL
+                | OP_DIV
+                | OP_MOD
+                | OP_LSHIFT
+                | OP_RSHIFT
+        ) {
+            return Err(ScriptError::DisabledOpcode);
+        }
+
+        if let Some(data) = push {
+            if executing {
+                stack.push(data.to_vec());
+            }
+        } else if executing || (OP_IF..=OP_ENDIF).contains(&opcode) {
+            match opcode {
+                OP_1NEGATE | OP_1..=OP_16 => {
+                    stack.push(encode_num(opcode as i64 - (OP_1 as i64 - 1)));
+                }
+                OP_NOP | OP_NOP1 | OP_NOP4..=OP_NOP10 => {}
+                OP_CHECKLOCKTIMEVERIFY => {
+                    let lock_time = decode_num(top(stack, 1)?, 5)?;
+                    if lock_time < 0 {
+                        return Err(ScriptError::NegativeLocktime);
+                    }
+                    if !checker.check_lock_time(lock_time) {
+                        return Err(ScriptError::UnsatisfiedLocktime);
+                    }
+                }
+                OP_CHECKSEQUENCEVERIFY => {
+                    let sequence = decode_num(top(stack, 1)?, 5)?;
+                    if sequence < 0 {
+                        return Err(ScriptError::NegativeLocktime);
+                    }
+                    if sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG == 0
+                        && !checker.check_sequence(sequence)
+                    {
+                        return Err(ScriptError::UnsatisfiedLocktime);
+                    }
+                }
+                OP_IF | OP_NOTIF => {
+                    let mut value = false;
+                    if executing {
+                        let condition = stack.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                        if is_tapscript
+                            && (condition.len() > 1 || (condition.len() == 1 && condition[0] != 1))
+                        {
+                            return Err(ScriptError::TapscriptMinimalIf);
+                        }
+                        value = cast_to_bool(&condition);
+                        if opcode == OP_NOTIF {
+                            value = !value;
+                        }
+                    }
+                    exec_stack.push(value);
+                }
+                OP_ELSE => {
+                    let branch = exec_stack
+                        .last_mut()
+                        .ok_or(ScriptError::UnbalancedConditional)?;
+                    *branch = !*branch;
+                }
+                OP_ENDIF => {
+                    exec_stack.pop().ok_or(ScriptError::UnbalancedConditional)?;
+                }
+                OP_VERIFY => {
+                    if !cast_to_bool(top(stack, 1)?) {
+                        return Err(ScriptError::Verify);
+                    }
+                    stack.pop();
+                }
+                OP_RETURN => return Err(ScriptError::OpReturn),
+
+                OP_TOALTSTACK => {
+                    let item = stack.pop().ok_or(ScriptError::InvalidStackOperation)?;
+                    alt_stack.push(item);
+                }
+                OP_FROMALTSTACK => {
+                    let item = alt_stack
+                        .pop()
+                        .ok_or(ScriptError::InvalidAltstackOperation)?;
+                    stack.push(item);
+                }
+                OP_2DROP => {
+                    require(stack, 2)?;
+                    stack.truncate(stack.len() - 2);
+                }
+                OP_2DUP => {
+                    require(stack, 2)?;
+                    stack.extend_from_within(stack.len() - 2..);
+                }
+                OP_3DUP => {
+                    require(stack, 3)?;
+                    stack.extend_from_within(stack.len() - 3..);
+                }
+                OP_2OVER => {
+                    require(stack, 4)?;
+                    stack.extend_from_within(stack.len() - 4..stack.len() - 2);
+                }
+                OP_2ROT => {
+                    require(stack, 6)?;
+                    let moved: Vec<_> = stack.drain(stack.len() - 6..stack.len() - 4).collect();
+                    stack.extend(moved);
+                }
+                OP_2SWAP => {
+                    require(stack, 4)?;
+                    let len = stack.len();
+                    stack[len - 4..].rotate_left(2);
+                }
+                OP_IFDUP => {
+                    let item = top(stack, 1)?.clone();
+                    if cast_to_bool(&item) {
+                        stack.push(item);
+                    }
+                }
+                OP_DEPTH => stack.push(encode_num(stack.len() as i64)),
+                OP_DROP => {
+                    stack.pop().ok_or(ScriptError::InvalidStackOperation)?;
+                }
+                OP_DUP => stack.push(top(stack, 1)?.clone()),
+                OP_NIP => {
+                    require(stack, 2)?;
+                    stack.remove(stack.len() - 2);
+                }
+                OP_OVER => stack.push(top(stack, 2)?.clone()),
+                OP_PICK | OP_ROLL => {
+                    let n = decode_num(top(stack, 1)?, 4)?;
+                    stack.pop();
+                    if n < 0 || n as usize >= stack.len() {
+                        return Err(ScriptError::InvalidStackOperation);
+                    }
+                    let position = stack.len() - 1 - n as usize;
+                    let item = if opcode == OP_ROLL {
+                        stack.remove(position)
+                    } else {
+                        stack[position].clone()
+                    };
+                    stack.push(item);
+                }
+                OP_ROT => {
+                    require(stack, 3)?;
+                    let len = stack.len();
+                    stack[len - 3..].rotate_left(1);
+                }
+                OP_SWAP => {
+                    require(stack, 2)?;
+                    let len = stack.len();
+                    stack.swap(len - 2, len - 1);
+                }
+                OP_TUCK => {
+                    require(stack, 2)?;
+                    let item = stack[stack.len() - 1].clone();
+                    stack.insert(stack.len() - 2, item);
+                }
+                OP_SIZE => stack.push(encode_num(top(stack, 1)?.len() as i64)),
+
+                OP_EQUAL | OP_EQUALVERIFY => {
+                    require(stack, 2)?;
+                    let b = stack.pop().unwrap();
+                    let a = stack.pop().unwrap();
+                    let equal = a == b;
+                    if opcode == OP_EQUALVERIFY {
+                        if !equal {
+                            return Err(ScriptError::EqualVerify);
+                        }
+                    } else {
+                        stack.push(encode_bool(equal));
+                    }
+                }
+
+                OP_1ADD | OP_1SUB | OP_NEGATE | OP_ABS | OP_NOT | OP_0NOTEQUAL => {
+                    let n = decode_num(top(stack, 1)?, 4)?;
+                    stack.pop();
+                    let result = match opcode {
+                        OP_1ADD => n + 1,
+                        OP_1SUB => n - 1,
+                        OP_NEGATE => -n,
+                        OP_ABS => n.abs(),
+                        OP_NOT => (n == 0) as i64,
+                        _ => (n != 0) as i64,
+                    };
+                    stack.push(encode_num(result));
+                }
+                OP_ADD
+                | OP_SUB
+                | OP_BOOLAND
+                | OP_BOOLOR
+                | OP_NUMEQUAL
+                | OP_NUMEQUALVERIFY
+                | OP_NUMNOTEQUAL
+                | OP_LESSTHAN
+                | OP_GREATERTHAN
+                | OP_LESSTHANOREQUAL
+                | OP_GREATERTHANOREQUAL
+                | OP_MIN
+                | OP_MAX => {
+                    let a = decode_num(top(stack, 2)?, 4)?;
+                    let b = decode_num(top(stack, 1)?, 4)?;
+                    stack.truncate(stack.len() - 2);
+                    let result = match opcode {
+                        OP_ADD => a + b,
+                        OP_SUB => a - b,
+                        OP_BOOLAND => (a != 0 && b != 0) as i64,
+                        OP_BOOLOR => (a != 0 || b != 0) as i64,
+                        OP_NUMEQUAL | OP_NUMEQUALVERIFY => (a == b) as i64,
+                        OP_NUMNOTEQUAL => (a != b) as i64,
+                        OP_LESSTHAN => (a < b) as i64,
+                        OP_GREATERTHAN => (a > b) as i64,
+                        OP_LESSTHANOREQUAL => (a <= b) as i64,
+                        OP_GREATERTHANOREQUAL => (a >= b) as i64,
+                        OP_MIN => a.min(b),
+                        _ => a.max(b),
+                    };
+                    if opcode == OP_NUMEQUALVERIFY {
+                        if result == 0 {
+                            return Err(ScriptError::NumEqualVerify);
+                        }
+                    } else {
+                        stack.push(encode_num(result));
+                    }
+                }
+                OP_WITHIN => {
+                    let x = decode_num(top(stack, 3)?, 4)?;
+                    let min = decode_num(top(stack, 2)?, 4)?;
+                    let max = decode_num(top(stack, 1)?, 4)?;
+                    stack.truncate(stack.len() - 3);
+                    stack.push(encode_bool(min <= x && x < max));
+                }
+
+                OP_RIPEMD160 | OP_SHA1 | OP_SHA256 | OP_HASH160 | OP_HASH256 => {
+                    let item = stack.pop().ok_or(ScriptError::InvalidStackOperation)?;
+                    let digest = match opcode {
+                        OP_RIPEMD160 => ripemd160::Hash::hash(&item).to_byte_array().to_vec(),
+                        OP_SHA1 => sha1::Hash::hash(&item).to_byte_array().to_vec(),
+                        OP_SHA256 => sha256::Hash::hash(&item).to_byte_array().to_vec(),
+                        OP_HASH160 => hash160::Hash::hash(&item).to_byte_array().to_vec(),
+                        _ => sha256d::Hash::hash(&item).to_byte_array().to_vec(),
+                    };
+                    stack.push(digest);
+                }
+                OP_CODESEPARATOR => {
+                    begin_code = pc;
+                    exec.codeseparator_pos = this_opcode_pos;
+                }
+                OP_CHECKSIG | OP_CHECKSIGVERIFY => {
+                    require(stack, 2)?;
+                    let pubkey = stack.pop().unwrap();
+                    let signature = stack.pop().unwrap();
+                    let success = eval_checksig(
+                        &signature,
+                        &pubkey,
+                        &script[begin_code..],
+                        sig_version,
+                        exec,
+                        checker,
+                    )?;
+                    if opcode == OP_CHECKSIGVERIFY {
+                        if !success {
+                            return Err(ScriptError::CheckSigVerify);
+                        }
+                    } else {
+                        stack.push(encode_bool(success));
+                    }
+                }
+                OP_CHECKSIGADD if is_tapscript => {
+                    require(stack, 3)?;
+                    let n = decode_num(top(stack, 2)?, 4)?;
+                    let pubkey = stack.pop().unwrap();
+                    stack.pop();
+                    let signature = stack.pop().unwrap();
+                    let success = eval_checksig(
+                        &signature,
+                        &pubkey,
+                        &script[begin_code..],
+                        sig_version,
+                        exec,
+                        checker,
+                    )?;
+                    stack.push(encode_num(n + success as i64));
+                }
+                OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                    if is_tapscript {
+                        return Err(ScriptError::TapscriptCheckMultisig);
+                    }
+                    let success = eval_checkmultisig(
+                        stack,
+                        &script[begin_code..],
+                        sig_version,
+                        &mut op_count,
+                        checker,
+                    )?;
+                    if opcode == OP_CHECKMULTISIGVERIFY {
+                        if !success {
+                            return Err(ScriptError::CheckMultisigVerify);
+                        }
+                    } else {
+                        stack.push(encode_bool(success));
+                    }
+                }
+                _ => return Err(ScriptError::BadOpcode),
+            }
+        }
+
+        if stack.len() + alt_stack.len() > MAX_STACK_SIZE {
+            return Err(ScriptError::StackSize);
+        }
+    }
+
+    if !exec_stack.is_empty() {
+        return Err(ScriptError::UnbalancedConditional);
+    }
+    Ok(())
+}
+
+fn eval_checksig(
+    signature: &[u8],
+    pubkey: &[u8],
+    script_code: &[u8],
+    sig_version: SigVersion,
+    exec: &mut ExecData,
+    checker: &mut Checker,
+) -> Result<bool, ScriptError> {
+    if sig_version != SigVersion::Tapscript {
+        if signature.is_empty() {
+            return Ok(false);
+        }
+        if !is_valid_signature_encoding(signature) {
+            return Err(ScriptError::SigDer);
+        }
+        let script_code = if sig_version == SigVersion::Base {
+            find_and_delete(script_code, &push_data(signature))
+        } else {
+            script_code.to_vec()
+        };
+        return Ok(checker.check_ecdsa_signature(signature, pubkey, &script_code, sig_version));
+    }
+
+    // BIP342: an empty pubkey is invalid, an empty signature is a clean failure,
+    // and every attempted check consumes validation weight
+    if pubkey.is_empty() {
+        return Err(ScriptError::PubkeyType);
+    }
+    let success = !signature.is_empty();
+    if success {
+        exec.validation_weight_left -= VALIDATION_WEIGHT_PER_SIGOP_PASSED;
+        if exec.validation_weight_left < 0 {
+            return Err(ScriptError::TapscriptValidationWeight);
+        }
+    }
+    if pubkey.len() == 32 && success {
+        check_schnorr_signature(signature, pubkey, sig_version, exec, checker)?;
+    }
+    // Pubkeys of other sizes are reserved for future upgrades and always succeed
+    Ok(success)
+}
+
+fn eval_checkmultisig(
+    stack: &mut Stack,
+    script_code: &[u8],
+    sig_version: SigVersion,
+    op_count: &mut usize,
+    checker: &mut Checker,
+) -> Result<bool, ScriptError> {
+    let mut i = 1;
+    let mut keys_count = decode_num(top(stack, i)?, 4)?;
+    if !(0..=MAX_PUBKEYS_PER_MULTISIG).contains(&keys_count) {
+        return Err(ScriptError::PubkeyCount);
+    }
+    *op_count += keys_count as usize;
+    if *op_count > MAX_OPS_PER_SCRIPT {
+        return Err(ScriptError::OpCount);
+    }
+    i += 1;
+    let mut ikey = i;
+    i += keys_count as usize;
+
+    let mut sigs_count = decode_num(top(stack, i)?, 4)?;
+    if sigs_count < 0 || sigs_count > keys_count {
+        return Err(ScriptError::SigCount);
+    }
+    i += 1;
+    let mut isig = i;
+    i += sigs_count as usize;
+    // The dummy element consumed by the off-by-one bug must also be present
+    require(stack, i)?;
+
+    let mut script_code = script_code.to_vec();
+    if sig_version == SigVersion::Base {
+        for k in 0..sigs_count as usize {
+            script_code = find_and_delete(&script_code, &push_data(top(stack, isig + k)?));
+        }
+    }
+
+    let mut success = true;
+    while success && sigs_count > 0 {
+        let signature = top(stack, isig)?.clone();
+        let pubkey = top(stack, ikey)?.clone();
+        if !signature.is_empty() && !is_valid_signature_encoding(&signature) {
+            return Err(ScriptError::SigDer);
+        }
+        if !signature.is_empty()
+            && checker.check_ecdsa_signature(&signature, &pubkey, &script_code, sig_version)
+        {
+            isig += 1;
+            sigs_count -= 1;
+        }
+        ikey += 1;
+        keys_count -= 1;
+        if sigs_count > keys_count {
+            success = false;
+        }
+    }
+
+    stack.truncate(stack.len() - (i - 1));
+    // BIP147: the dummy element must be empty
+    if !stack
+        .last()
+        .ok_or(ScriptError::InvalidStackOperation)?
+        .is_empty()
+    {
+        return Err(ScriptError::SigNullDummy);
+    }
+    stack.pop();
+
+    Ok(success)
+}
+
+fn check_schnorr_signature(
+    signature: &[u8],
+    pubkey: &[u8],
+    sig_version: SigVersion,
+    exec: &ExecData,
+    checker: &mut Checker,
+) -> Result<(), ScriptError> {
+    let (signature, sighash_type) = match signature.len() {
+        64 => (signature, TapSighashType::Default),
+        65 if signature[64] != TapSighashType::Default as u8 => (
+            &signature[..64],
+            TapSighashType::from_consensus_u8(signature[64])
+                .map_err(|_| ScriptError::SchnorrSigHashtype)?,
+        ),
+        65 => return Err(ScriptError::SchnorrSigHashtype),
+        _ => return Err(ScriptError::SchnorrSigSize),
+    };
+
+    let annex = exec
+        .annex
+        .as_deref()
+        .map(|annex| Annex::new(annex).expect("msg: Annex starts with the annex tag"));
+    let leaf = match sig_version {
+        SigVersion::Tapscript => exec.tapleaf_hash.map(|leaf| (leaf, exec.codeseparator_pos)),
+        _ => None,
+    };
+    let mut engine = TapSighash::engine();
+    checker
+        .cache
+        .taproot_encode_signing_data_to(
+            &mut engine,
+            checker.index,
+            &Prevouts::All(checker.prevouts),
+            annex,
+            leaf,
+            sighash_type,
+        )
+        .map_err(|_| ScriptError::SchnorrSigHashtype)?;
+    let sighash = TapSighash::from_engine(engine);
+
+    let signature =
+        schnorr::Signature::from_slice(signature).map_err(|_| ScriptError::SchnorrSig)?;
+    let pubkey = XOnlyPublicKey::from_slice(pubkey).map_err(|_| ScriptError::SchnorrSig)?;
+    checker
+        .secp
+        .verify_schnorr(&signature, &Message::from(sighash), &pubkey)
+        .map_err(|_| ScriptError::SchnorrSig)
+}
+
+impl Checker<'_> {
+    fn check_ecdsa_signature(
+        &mut self,
+        signature: &[u8],
+        pubkey: &[u8],
+        script_code: &[u8],
+        sig_version: SigVersion,
+    ) -> bool {
+        let Ok(pubkey) = secp256k1::PublicKey::from_slice(pubkey) else {
+            return false;
+        };
+        let Some((&hash_type, der)) = signature.split_last() else {
+            return false;
+        };
+        let Ok(mut signature) = ecdsa::Signature::from_der_lax(der) else {
+            return false;
+        };
+        // libsecp256k1 only accepts low-S signatures; high S is valid by consensus
+        signature.normalize_s();
+
+        let sighash = match sig_version {
+            SigVersion::Base => {
+                let script_code = remove_codeseparators(script_code);
+                match self.cache.legacy_signature_hash(
+                    self.index,
+                    Script::from_bytes(&script_code),
+                    hash_type as u32,
+                ) {
+                    Ok(sighash) => sighash.to_byte_array(),
+                    Err(_) => return false,
+                }
+            }
+            _ => self.segwit_v0_signature_hash(script_code, hash_type as u32),
+        };
+
+        self.secp
+            .verify_ecdsa(&Message::from_digest(sighash), &signature, &pubkey)
+            .is_ok()
+    }
+
+    // BIP143 signature hash, written out so non-standard hash types commit to
+    // their exact value
+    fn segwit_v0_signature_hash(&self, script_code: &[u8], hash_type: u32) -> [u8; 32] {
+        let tx = self.tx;
+        let anyone_can_pay = hash_type & 0x80 != 0;
+        let base_type = hash_type & 0x1f;
+        let is_single = base_type == 0x03;
+        let is_none = base_type == 0x02;
+
+        let mut hash_prevouts = [0u8; 32];
+        let mut hash_sequence = [0u8; 32];
+        let mut hash_outputs = [0u8; 32];
+
+        if !anyone_can_pay {
+            let mut engine = sha256d::Hash::engine();
+            for input in &tx.input {
+                input.previous_output.consensus_encode(&mut engine).unwrap();
+            }
+            hash_prevouts = sha256d::Hash::from_engine(engine).to_byte_array();
+        }
+        if !anyone_can_pay && !is_single && !is_none {
+            let mut engine = sha256d::Hash::engine();
+            for input in &tx.input {
+                input.sequence.consensus_encode(&mut engine).unwrap();
+            }
+            hash_sequence = sha256d::Hash::from_engine(engine).to_byte_array();
+        }
+        if !is_single && !is_none {
+            let mut engine = sha256d::Hash::engine();
+            for output in &tx.output {
+                output.consensus_encode(&mut engine).unwrap();
+            }
+            hash_outputs = sha256d::Hash::from_engine(engine).to_byte_array();
+        } else if is_single && self.index < tx.output.len() {
+            let mut engine = sha256d::Hash::engine();
+            tx.output[self.index].consensus_encode(&mut engine).unwrap();
+            hash_outputs = sha256d::Hash::from_engine(engine).to_byte_array();
+        }
+
+        let input = &tx.input[self.index];
+        let mut engine = sha256d::Hash::engine();
+        tx.version.consensus_encode(&mut engine).unwrap();
+        engine.input(&hash_prevouts);
+        engine.input(&hash_sequence);
+        input.previous_output.consensus_encode(&mut engine).unwrap();
+        script_code.to_vec().consensus_encode(&mut engine).unwrap();
+        self.prevouts[self.index]
+            .value
+            .consensus_encode(&mut engine)
+            .unwrap();
+        input.sequence.consensus_encode(&mut engine).unwrap();
+        engine.input(&hash_outputs);
+        tx.lock_time.consensus_encode(&mut engine).unwrap();
+        hash_type.consensus_encode(&mut engine).unwrap();
+        sha256d::Hash::from_engine(engine).to_byte_array()
+    }
+
+    fn check_lock_time(&self, lock_time: i64) -> bool {
+        let tx_lock_time = self.tx.lock_time.to_consensus_u32() as i64;
+        // Both must be heights or both must be timestamps
+        if (tx_lock_time < LOCKTIME_THRESHOLD) != (lock_time < LOCKTIME_THRESHOLD) {
+            return false;
+        }
+        if lock_time > tx_lock_time {
+            return false;
+        }
+        // A final input would disable the transaction's locktime altogether
+        self.tx.input[self.index].sequence.0 != SEQUENCE_FINAL
+    }
+
+    fn check_sequence(&self, sequence: i64) -> bool {
+        let tx_sequence = self.tx.input[self.index].sequence.0 as i64;
+        if (self.tx.version.0 as u32) < 2 {
+            return false;
+        }
+        if tx_sequence & SEQUENCE_LOCKTIME_DISABLE_FLAG != 0 {
+            return false;
+        }
+        let mask = SEQUENCE_LOCKTIME_TYPE_FLAG | SEQUENCE_LOCKTIME_MASK;
+        let tx_sequence = tx_sequence & mask;
+        let sequence = sequence & mask;
+        if (tx_sequence < SEQUENCE_LOCKTIME_TYPE_FLAG) != (sequence < SEQUENCE_LOCKTIME_TYPE_FLAG) {
+            return false;
+        }
+        sequence <= tx_sequence
+    }
+}
+
+fn verify_taproot_commitment(
+    control: &[u8],
+    program: &[u8],
+    tapleaf_hash: TapLeafHash,
+    secp: &Secp256k1<VerifyOnly>,
+) -> bool {
+    let (Ok(internal_key), Ok(output_key)) = (
+        XOnlyPublicKey::from_slice(&control[1..TAPROOT_CONTROL_BASE_SIZE]),
+        XOnlyPublicKey::from_slice(program),
+    ) else {
+        return false;
+    };
+
+    let mut node = TapNodeHash::from(tapleaf_hash);
+    for branch in control[TAPROOT_CONTROL_BASE_SIZE..].chunks(TAPROOT_CONTROL_NODE_SIZE) {
+        let branch = TapNodeHash::from_byte_array(branch.try_into().unwrap());
+        node = TapNodeHash::from_node_hashes(node, branch);
+    }
+
+    let tweak = TapTweakHash::from_key_and_tweak(internal_key, Some(node)).to_scalar();
+    let parity = if control[0] & 1 == 1 {
+        Parity::Odd
+    } else {
+        Parity::Even
+    };
+    internal_key.tweak_add_check(secp, &output_key, parity, tweak)
+}
+
+// BIP341 leaf hash, computed directly so any even leaf version can be hashed
+fn tapleaf_hash(leaf_version: u8, script: &[u8]) -> TapLeafHash {
+    let mut engine = TapLeafHash::engine();
+    engine.input(&[leaf_version]);
+    script.to_vec().consensus_encode(&mut engine).unwrap();
+    TapLeafHash::from_engine(engine)
+}
+
+// Read the opcode at `pc`, returning the pushed bytes for push opcodes
+fn read_op<'s>(script: &'s [u8], pc: &mut usize) -> Result<(u8, Option<&'s [u8]>), ScriptError> {
+    let opcode = script[*pc];
+    *pc += 1;
+    if opcode > OP_PUSHDATA4 {
+        return Ok((opcode, None));
+    }
+
+    let (len, header) = match opcode {
+        OP_PUSHDATA1 => (script.get(*pc).map(|b| *b as usize), 1),
+        OP_PUSHDATA2 => (
+            script
+                .get(*pc..*pc + 2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as usize),
+            2,
+        ),
+        OP_PUSHDATA4 => (
+            script
+                .get(*pc..*pc + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize),
+            4,
+        ),
+        _ => (Some(opcode as usize), 0),
+    };
+    let len = len.ok_or(ScriptError::BadOpcode)?;
+    *pc += header;
+    let data = script.get(*pc..*pc + len).ok_or(ScriptError::BadOpcode)?;
+    *pc += len;
+    Ok((opcode, Some(data)))
+}
+
+fn is_op_success(opcode: u8) -> bool {
+    matches!(
+        opcode,
+        80 | 98 | 126..=129 | 131..=134 | 137..=138 | 141..=142 | 149..=153 | 187..=254
+    )
+}
+
+pub fn is_push_only(script: &[u8]) -> bool {
+    let mut pc = 0;
+    while pc < script.len() {
+        match read_op(script, &mut pc) {
+            Ok((opcode, _)) if opcode <= OP_16 => {}
+            _ => return false,
+        }
+    }
+    true
+}
+
+pub fn is_p2sh(script: &[u8]) -> bool {
+    script.len() == 23 && script[0] == OP_HASH160 && script[1] == 20 && script[22] == OP_EQUAL
+}
+
+// Split a witness program into its version and program bytes
+pub fn witness_program(script: &[u8]) -> Option<(u8, &[u8])> {
+    if !(4..=42).contains(&script.len()) || script[1] as usize + 2 != script.len() {
+        return None;
+    }
+    match script[0] {
+        OP_0 => Some((0, &script[2..])),
+        OP_1..=OP_16 => Some((script[0] - OP_1 + 1, &script[2..])),
+        _ => None,
+    }
+}
+
+// Serialize a data push the way `CScript() << data` does
+fn push_data(data: &[u8]) -> Vec<u8> {
+    let mut script = Vec::with_capacity(data.len() + 5);
+    match data.len() {
+        len if len < OP_PUSHDATA1 as usize => script.push(len as u8),
+        len if len <= 0xff => script.extend_from_slice(&[OP_PUSHDATA1, len as u8]),
+        len if len <= 0xffff => {
+            script.push(OP_PUSHDATA2);
+            script.extend_from_slice(&(len as u16).to_le_bytes());
+        }
+        len => {
+            script.push(OP_PUSHDATA4);
+            script.extend_from_slice(&(len as u32).to_le_bytes());
+        }
+    }
+    script.extend_from_slice(data);
+    script
+}
+
+// Remove every occurrence of `pattern` that starts on an opcode boundary
+fn find_and_delete(script: &[u8], pattern: &[u8]) -> Vec<u8> {
+    if pattern.is_empty() {
+        return script.to_vec();
+    }
+    let mut result = Vec::with_capacity(script.len());
+    let mut pc = 0;
+    let mut copied_from = 0;
+    loop {
+        result.extend_from_slice(&script[copied_from..pc]);
+        while script[pc..].starts_with(pattern) {
+            pc += pattern.len();
+        }
+        copied_from = pc;
+        if pc >= script.len() || read_op(script, &mut pc).is_err() {
+            break;
+        }
+    }
+    result.extend_from_slice(&script[copied_from..]);
+    result
+}
+
+fn remove_codeseparators(script: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(script.len());
+    let mut pc = 0;
+    while pc < script.len() {
+        let start = pc;
+        match read_op(script, &mut pc) {
+            Ok((OP_CODESEPARATOR, _)) => {}
+            Ok(_) => result.extend_from_slice(&script[start..pc]),
+            Err(_) => {
+                result.extend_from_slice(&script[start..]);
+                break;
+            }
+        }
+    }
+    result
+}
+
+// BIP66 strict DER check on a signature including its trailing hash type byte
+fn is_valid_signature_encoding(sig: &[u8]) -> bool {
+    if sig.len() < 9 || sig.len() > 73 {
+        return false;
+    }
+    if sig[0] != 0x30 || sig[1] as usize != sig.len() - 3 {
+        return false;
+    }
+    let len_r = sig[3] as usize;
+    if 5 + len_r >= sig.len() {
+        return false;
+    }
+    let len_s = sig[5 + len_r] as usize;
+    if len_r + len_s + 7 != sig.len() {
+        return false;
+    }
+    if sig[2] != 0x02 || len_r == 0 || sig[4] & 0x80 != 0 {
+        return false;
+    }
+    if len_r > 1 && sig[4] == 0x00 && sig[5] & 0x80 == 0 {
+        return false;
+    }
+    if sig[len_r + 4] != 0x02 || len_s == 0 || sig[len_r + 6] & 0x80 != 0 {
+        return false;
+    }
+    if len_s > 1 && sig[len_r + 6] == 0x00 && sig[len_r + 7] & 0x80 == 0 {
+        return false;
+    }
+    true
+}
+
+fn top(stack: &Stack, depth: usize) -> Result<&Vec<u8>, ScriptError> {
+    stack
+        .len()
+        .checked_sub(depth)
+        .map(|index| &stack[index])
+        .ok_or(ScriptError::InvalidStackOperation)
+}
+
+fn require(stack: &Stack, depth: usize) -> Result<(), ScriptError> {
+    if stack.len() < depth {
+        return Err(ScriptError::InvalidStackOperation);
+    }
+    Ok(())
+}
+
+fn cast_to_bool(bytes: &[u8]) -> bool {
+    match bytes.split_last() {
+        // Negative zero is false
+        Some((&last, rest)) => rest.iter().any(|b| *b != 0) || (last != 0 && last != 0x80),
+        None => false,
+    }
+}
+
+fn encode_bool(value: bool) -> Vec<u8> {
+    if value { vec![1] } else { Vec::new() }
+}
+
+// Decode a little-endian sign-magnitude script number of at most `max_len` bytes
+fn decode_num(bytes: &[u8], max_len: usize) -> Result<i64, ScriptError> {
+    if bytes.len() > max_len {
+        return Err(ScriptError::NumOverflow);
+    }
+    let Some((&last, _)) = bytes.split_last() else {
+        return Ok(0);
+    };
+    let mut value: i64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        value |= (*byte as i64) << (8 * i);
+    }
+    if last & 0x80 != 0 {
+        value &= !(0x80i64 << (8 * (bytes.len() - 1)));
+        value = -value;
+    }
+    Ok(value)
+}
+
+fn encode_num(value: i64) -> Vec<u8> {
+    if value == 0 {
+        return Vec::new();
+    }
+    let negative = value < 0;
+    let mut magnitude = value.unsigned_abs();
+    let mut bytes = Vec::new();
+    while magnitude > 0 {
+        bytes.push((magnitude & 0xff) as u8);
+        magnitude >>= 8;
+    }
+    if bytes.last().unwrap() & 0x80 != 0 {
+        bytes.push(if negative { 0x80 } else { 0x00 });
+    } else if negative {
+        *bytes.last_mut().unwrap() |= 0x80;
+    }
+    bytes
+}
+
+// Count signature operations in a script as Core's CScript::GetSigOpCount does.
+// Multisig counts as 20 unless `accurate` and preceded by OP_1..OP_16.
+pub fn sigop_count(script: &[u8], accurate: bool) -> u64 {
+    let mut count = 0;
+    let mut last_opcode = None;
+    let mut pc = 0;
+    while pc < script.len() {
+        let Ok((opcode, _)) = read_op(script, &mut pc) else {
+            break;
+        };
+        match opcode {
+            OP_CHECKSIG | OP_CHECKSIGVERIFY => count += 1,
+            OP_CHECKMULTISIG | OP_CHECKMULTISIGVERIFY => {
+                count += match last_opcode {
+                    Some(n @ OP_1..=OP_16) if accurate => (n - OP_1 + 1) as u64,
+                    _ => MAX_PUBKEYS_PER_MULTISIG as u64,
+                }
+            }
+            _ => {}
+        }
+        last_opcode = Some(opcode);
+    }
+    count
+}
+
+// Signature operations in the redeem script of a P2SH spend
+pub fn p2sh_sigop_count(script_sig: &[u8], script_pubkey: &[u8]) -> u64 {
+    if !is_p2sh(script_pubkey) {
+        return 0;
+    }
+    last_push(script_sig).map_or(0, |redeem_script| sigop_count(redeem_script, true))
+}
+
+// Signature operations of a witness spend, native or nested in P2SH, as
+// Core's CountWitnessSigOps
+pub fn witness_sigop_count(script_sig: &[u8], script_pubkey: &[u8], witness: &Witness) -> u64 {
+    let program = match witness_program(script_pubkey) {
+        Some(program) => Some(program),
+        None if is_p2sh(script_pubkey) => last_push(script_sig).and_then(witness_program),
+        None => None,
+    };
+    match program {
+        Some((0, program)) if program.len() == 20 => 1,
+        Some((0, program)) if program.len() == 32 => witness
+            .last()
+            .map_or(0, |witness_script| sigop_count(witness_script, true)),
+        _ => 0,
+    }
+}
+
+// The data of the final push of a push-only script, empty for OP_0..OP_16
+pub fn last_push(script: &[u8]) -> Option<&[u8]> {
+    let mut pc = 0;
+    let mut last = None;
+    while pc < script.len() {
+        match read_op(script, &mut pc) {
+            Ok((opcode, data)) if opcode <= OP_16 => last = Some(data.unwrap_or(&[])),
+            _ => return None,
+        }
+    }
+    last
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        Amount, OutPoint, ScriptBuf, Sequence, TxIn, absolute::LockTime, consensus, opcodes::all,
+        script::Builder, taproot::LeafVersion, taproot::TaprootBuilder, transaction::Version,
+    };
+
+    use super::*;
+
+    // A mainnet transaction and the outputs its inputs spend, as (sats,
+    // scriptPubKey hex)
+    struct Spend {
+        name: &'static str,
+        tx: &'static str,
+        prevouts: &'static [(u64, &'static str)],
+    }
+
+    const SPENDS: &[Spend] = &[
+        Spend {
+            name: "P2PKH",
+            tx: "0100000001520a155e0bb0d7fc28fd2b2a6245b9621d7f4c369a9549b3932c1f78bc6cae3b000000006a4730440220641f8bed1dd85ed0d62e8f2baa67b6170c499204779e80c870cfa17599c3e7f702200a299a2a1d2e5ff5057ee3de8f520857eb1ab80e90b3c3c724ff4be0153cd31e012102d28b5df072c07b34b4e73d9533482931243aa8683db6b1cc1f58561c48792491ffffffff0172f30100000000001600142720cd9043d4ab82c6e310682cd8f4f6713f68b900000000",
+            prevouts: &[(136007, "76a914119d9da166ef34e019e35a0ebfad17a34cdfc9c388ac")],
+        },
+        Spend {
+            name: "P2SH multisig",
+            tx: "010000000144b5700bbdc4ca73f632ced70fe5eab08da0fc6d2f6ae1d8752b43582672520e01000000fc00473044022061f5cb2c6a6638e40c7235b888224c6352ea1fe60bd5af6e52b2d77453279ebf0220175360ad1aa5d6f612e19eaa75e155e37a8de938f84985a1bf1220148e20a18301473044022065b884e14705c85b462b41fdfe295f4f0e53baf780840b30c0b90f71833dd64d02206ac04c63828ba80e274c4ff96a03f39f450b4bedcb70b415047ea1ee7c712570014c695221029640a253abbf6252e6d195882262da255d6dd17e66cd8aca2610083f6d4d17812103a553e30733d7a8df6d390d59cc136e2c9d9cf4e808f3b6ab009beae68dd608222103211aec906e232ad96f2d52e849cf2798f4cf2d3d3463f608a7638054542defd653aefdffffff0198b309000000000017a9148c5b2b2f7352c6d417f71120b6cf184f1d0254348700000000",
+            prevouts: &[(645637, "a914e6a63531e03db543823d0a46a4377af7de37153987")],
+        },
+        Spend {
+            name: "P2SH-P2WPKH",
+            tx: "020000000001015d2af5790eca4460cf3702c962114fe7b9d486bc0533f75c47e6a438e37b051b46000000171600145d6498ad116c4d98745dcae0d46c6b6570b86df30100000001fc62000000000000160014cce4af2871c21f01784f3ecb654c1c8c29d702d00247304402205cbce9e6ac3bf5ded395f45bba831276cb06587925f0804ae02f3c4784b7017c02201c296704793186eec69951c2a1c3ff4152e317b332ec3149fd43939f0da8b495012103a53268b6f502c9b684ddc32ef3c793eae0cbd66693e2c82f300c018743d3f91f00000000",
+            prevouts: &[(28220, "a914fc4761a14e4667f88a8bba2a10fc51662d02ff7087")],
+        },
+        Spend {
+            name: "P2SH-P2WSH",
+            tx: "01000000000101da9391394ba4f8ca9fbf5ac52d68ae63d76913a7aecbd7554570822c36f46b0e00000000232200207b5f2056c962486f44774d20ea4462b81fb716f3d7cc86fd6b112633f301dccaffffffff01372e04000000000017a91419e8f3ab2e4f11526b08f170b381924a32724898870400473044022029ac841b727422939ca21f1c3f554d25973bd7c57ada4e5c484370534bec524f0220675c6c35a5d19a051829e43af08db0fc6aae5af0afc0187774dbe39990ce29500147304402205f81acfd5fc9f40292c5873a3f3a14f2cbca6062625a50dd025476f9b35093dc02200c5760ffa6131bf938176d95e900b53b4045c0e6f18daa1442ca9874864c2ed501475221036049622db978c3028fc60237fc7f47fc7e883f03b446798172d59b007f4f5e3621022ce1aad322a2a6012996ff109aabdc16ec611b3d454426d3799bca6cb6af4ea052ae00000000",
+            prevouts: &[(279415, "a9140ca5eeb7ccf40738aa8eaba3954a8330d2129d7187")],
+        },
+        Spend {
+            name: "P2WPKH",
+            tx: "01000000000101419ef5ebcc115ff485ee4d7d72f1fa499766da7eb5436dc3bf31259e2ea998cf0100000000ffffffff014a01000000000000160014e2c8f76e5fa88ff998151a0f7a6b844b96bc8ff00247304402206de9d2b330c962ad2a31ca545e001fe42a86c182e56d1d873b881e664a892dfd02200ee177bc4300d2b8adf6d6b56f296b0ac71a0698ce6db053691859ccc8f17ab70121039944794ca9f7df6aa966e020b0b94c29f5e9aa776923773e6cf5eb6de5ffc2e000000000",
+            prevouts: &[(3300, "00146c5aed231370beef0f34fb33b6f7ba1270b9e1f9")],
+        },
+        Spend {
+            name: "P2WSH multisig",
+            tx: "010000000001017578da0e564db2c88c5a9d97a46bda0d4ee6e9091171249147c1be233627e7f100000000000000000001b735000000000000160014675e869be941f4317d910c1579844cf1a10dd11c0300473044022051ee4f016d515928f4a981e39a577208e3b627db306eac8c8733e2ce724b5f91022008a53022d4df0b4f36aba37395e4d30cda7db4f0369278194f08117e2859193b0147512103cda78c50d2199d22224de2e839da81ff6af1ea1786a1b896cf4fb43ae4cc3c682103e8f7ac94bcf322e696f49709dc22c87e456d23b85591a5ceb17893a4ff73405352ae00000000",
+            prevouts: &[(
+                15181,
+                "0020bc9551ddccad5f4339c01a17b532cad24f411d7b5fd0b816f63ff05a246d5cbe",
+            )],
+        },
+        Spend {
+            name: "taproot key path",
+            tx: "0200000000010194b6b9c34f738c2e1b378b20ea580ed10e02688813355d0a83761016c8910ebe00000000000000000001119501000000000016001420d2699d10c0e0d712fe77e4447e4598a68e671a0140378301008ff08fd55c3be8ee1ff3a770ad1799092e682c2148dba707a8980e9c64fea80accefdb6d3bf611f3f2145cbc776270fb3f5ca0516b0e3785f644974395bb0c00",
+            prevouts: &[(
+                105086,
+                "5120e12efa737eefa3a1635084a88a959c92cbc4095a34f669b3ff3fb27eea9944bb",
+            )],
+        },
+        Spend {
+            name: "taproot script path, checksig leaf",
+            tx: "02000000000101b554c4639bf4ad70ae8a23996d618a2bfb6969a07eb629a11f200668fef6bf960000000000fdffffff024a0a0000000000002251200e37d126ed269d5abe28b298c664f8d99c4d30a7d36c22b7c50126fa3ef78a383d08000000000000160014d9d200ae31c054a355cfc8fd0cceae82dd3dff78034043c5cacebe04f177b5e897ef880a08d9ad3f541d08b1fac96b1b981b98a7826852877b26b9cacd84aab85ef36189cefa138b76fdc48bd732483a9778bfd5431c222051256dd6146fe8e6e84cf7750ab9b8717743c4b71c658dcf8098890d71757fdfac21c051256dd6146fe8e6e84cf7750ab9b8717743c4b71c658dcf8098890d71757fdf00000000",
+            prevouts: &[(
+                7000,
+                "5120516b5c3cb7129ff4987dd625806920438406481c3f7a073fe1b73ccb1fab5037",
+            )],
+        },
+        Spend {
+            name: "taproot script path, inscription envelope",
+            tx: "0200000000010183fca8f4dab272854e1a6a2b734efc8e98a250e197e32590e2b61f3991902f0b0200000000ffffffff012601000000000000160014a40897ac0756778584e7dbe457cca54abc6daf4c0301024e750063036f726401010a746578742f706c61696e00367b2270223a226272632d3230222c226f70223a226d696e74222c227469636b223a2261616161222c22616d74223a223130303030227d685121c1b4195b6f3e8ccd6f9c6327fd7ac32b709a0dd80e920cdb63524e3fd9aaa0351300000000",
+            prevouts: &[(
+                1711,
+                "5120b7aa99bf8cbc1455592f690e086f5ca41eca074db1509b6f34843e70549ce736",
+            )],
+        },
+        Spend {
+            name: "taproot key path, SIGHASH_SINGLE|ANYONECANPAY",
+            tx: "0100000000010113e5ec0a79506968aa00ea6ca065ccac09a99b1ac157a4b062f01ae7ad1eb8f30000000000fdffffff01e803000000000000160014027bc455b49578636e080eabed6048b02ab3985f034131cdca35179946ccd98eb1e1bbf186b806d10851bec0289e2320cfd5284f3ce775f1e9b806d078fd7d19cdd1fb1aa92a3824838f73fd4299183ff25eecbabd6e835b20be11f1d0580232188e877ef283eb5a1efd4e9e5290a2633605d666f59ad3fff2ac00630461746f6d03646d742ca16461726773a36b6d696e745f7469636b657263627463656e6f6e63651a0052634b6474696d651a65eb4c0c6821c1be11f1d0580232188e877ef283eb5a1efd4e9e5290a2633605d666f59ad3fff200000000",
+            prevouts: &[(
+                2425,
+                "512077fa127b098e59205ff954974b17e17e10c6b09e952949f42a0289068eb5c6b0",
+            )],
+        },
+        Spend {
+            name: "P2PKH, SIGHASH_ALL|ANYONECANPAY",
+            tx: "010000000296c0259878137b186f5ba717f8f99973b82d7cd27f2ce901eef1caa6759e41c4020000006a47304402205f6ffb2ed4720182b5873bb61fcc3ee00f883dd2c53da8ed2e672c2026a2efe602207740eedd0d75bb77a5fc9d2b7a69ce8ede00f30caa824e65d44cd9684dd1f34f81210354c50be75c920bbeffcf2e196abb5f776fe0a47aae3ab2a31951801b29143334ffffffffc64646d04c05fa6343bc1cf56193a737b6e2ec262ce7c7a519eb8f544981747c000000006a47304402200570e77ddbc3beffbc9c13e2c8a89da1515d1ca9bfe77828e8ec2b3476d6f4a702207e607394de69b1ddee8232d8103e2f94a4cd780a17b2c9128bb83bfffbfa550b81210354c50be75c920bbeffcf2e196abb5f776fe0a47aae3ab2a31951801b29143334ffffffff02f37414000000000017a914c19b511be417d66436a5eac091db01006245f95b878dc33900000000001976a914c13fcd289089016745aefdb53c857e7a94ddde8a88ac00000000",
+            prevouts: &[
+                (220696, "76a914c13fcd289089016745aefdb53c857e7a94ddde8a88ac"),
+                (
+                    4912596,
+                    "76a914c13fcd289089016745aefdb53c857e7a94ddde8a88ac",
+                ),
+            ],
+        },
+        Spend {
+            name: "P2WPKH, SIGHASH_SINGLE|ANYONECANPAY",
+            tx: "02000000000102ca6cd360aab0c29f6b79d0631edc0917a114f325a4ac2abef2315ff3095231520300000000ffffffff262d6588c923137185019fce05a923d891934bdf665187ab4897b3d69042f7830000000000ffffffff04a0860100000000002251202d071a3b480cd9b47db94593e4592074b81d5edaec2e007722cfb1058d63912e238a01000000000016001498e44f85d38486b28bfc804c364b854586ab7ac7e107000000000000160014e5cb2de82fd4c136218c67a310fde879344c1bef8fe10800000000002251202d071a3b480cd9b47db94593e4592074b81d5edaec2e007722cfb1058d63912e0140c43dff92b5df7cf8e6fcc4757ee182dbcc17350e1324977e711bda24a178087d88dc62e58495e60385ba5c11a5a3d826534dc8837861c4b24b07420ab1ba84330247304402201dc3cff2f4bc81ec246b027a4de471addeb52e43b45927f2ada5883af69deacb022029806f50116b0b4966826463b518489414a6504a438f66c1839d9d2f1dfa30558321039265d33e9ff3cda18723b9c3589ab6aba4e749d9efc61b680a0579622c6d8dba00000000",
+            prevouts: &[
+                (
+                    688591,
+                    "51202d071a3b480cd9b47db94593e4592074b81d5edaec2e007722cfb1058d63912e",
+                ),
+                (100000, "001498e44f85d38486b28bfc804c364b854586ab7ac7"),
+            ],
+        },
+        Spend {
+            name: "P2WPKH, SIGHASH_ALL|ANYONECANPAY",
+            tx: "0200000000010115cd0c2fc044f6f097b3491fd93cb255618e1fc6f383d3e227958c77c6a40c5f0000000000faffffff0289e7ae000000000016001420e61a710b387b9ce6bbfd864e555542362af4ac65e80d000000000017a9144a0f43ab502e81ddba2df3116908213b58829f9087024730440220692d4aec287f0b0c038ec98cd6d8cabde1826c6b65e3a376296af79add2460af022012693782f204703331c803d965c55bb1c704b86f951b7727004d7439e9238b1b8121029d4ca05727de8632bcdc7a50eaf54d87f1120bb9c0846146bf77ff2db89b4e0500000000",
+            prevouts: &[(12376007, "00148a2d1c921ad8523a145ad6b3a231a7da60f01f19")],
+        },
+        Spend {
+            name: "taproot key path, SIGHASH_ALL|ANYONECANPAY",
+            tx: "02000000000102f3a6744ca876fd7624585fa006084738cb1ea25b8c9b0585580975eca4de14990300000000fdffffff99aa6ae847fc323b9e6854ab32eaf31cf780423a6408f7f1a4c0970af2739fbf0200000000fdffffff0420ca150000000000160014b8842cde7612775197d6f438daec1cc875769e481c0300000000000069512102825003a8664fa21921229532220e9b8bd19bc5b3ff89a66f9b31adf8194f026f21023a8264ca083fd8cca1b35db067ceb1eedbf97c065a728279fc52446278ed3eea2102020202020202020202020202020202020202020202020202020202020202020253ae1c030000000000006951210216f4a5170b87acfad1e6fee23ff15eed024ffed067ebc2afeabcbb2304f822d321022ac3742c7153ab4a8aa198a52702ae1887af6c87e5b5d0dd1b3eb863bc3b116e2103333333333333333333333333333333333333333333333333333333333333333353ae23020000000000002251203c3379789efc56c1d6165c856ab49edc877d80a97e7ea1db65de6742b787142e0141bd8e761919d0c022cbe2b40d1c04983bd42cd699b652cb3c704d136d9caf3a9142e2da0204b830a0767156aa2f04c8007d08e0767221c7ea5db4c5d385ba393281024730440220428eb2634b1f6ee39796dd62fbf011594c5a7d79bff8aa9d651dc02dd2d65ac802205f32da73563fe91158b6aaa277fb4547aaa8d9ad5a8566eb204b9f3143f8053701210276ae471e6f669fa6884378c445d7d1a11d16096f634b9e826025c1046ed8ffaf00000000",
+            prevouts: &[
+                (
+                    547,
+                    "51203c3379789efc56c1d6165c856ab49edc877d80a97e7ea1db65de6742b787142e",
+                ),
+                (1441409, "0014368c4eeb10af3a5067f2bceadacc6eab54efd0a4"),
+            ],
+        },
+    ];
+
+    // x-only secp256k1 generator, used as an arbitrary taproot internal key
+    const INTERNAL_KEY: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+
+    fn decode(spend: &Spend) -> (Transaction, Vec<TxOut>) {
+        let tx: Transaction =
+            consensus::deserialize(&hex::decode(spend.tx).expect("msg: Valid hex"))
+                .expect("msg: Valid transaction");
+        let prevouts = spend
+            .prevouts
+            .iter()
+            .map(|(value, script_pubkey)| TxOut {
+                value: Amount::from_sat(*value),
+                script_pubkey: ScriptBuf::from_hex(script_pubkey).expect("msg: Valid hex"),
+            })
+            .collect();
+        (tx, prevouts)
+    }
+
+    // Run a single input spending `script_pubkey` with the given scriptSig and
+    // witness, the way script_tests.json cases are run
+    fn run(
+        script_sig: ScriptBuf,
+        script_pubkey: ScriptBuf,
+        witness: Vec<Vec<u8>>,
+    ) -> Result<(), ScriptError> {
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig,
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&witness),
+            }],
+            output: vec![TxOut {
+                value: Amount::ZERO,
+                script_pubkey: ScriptBuf::new(),
+            }],
+        };
+        let prevouts = [TxOut {
+            value: Amount::ZERO,
+            script_pubkey,
+        }];
+        verify_transaction(&tx, &prevouts).remove(0)
+    }
+
+    // Spend a single-leaf taproot output through `leaf`, with `items` below
+    // the script and control block
+    fn run_tapscript(leaf: ScriptBuf, items: Vec<Vec<u8>>) -> Result<(), ScriptError> {
+        let secp = Secp256k1::new();
+        let internal_key: XOnlyPublicKey = INTERNAL_KEY.parse().expect("msg: Valid key");
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, leaf.clone())
+            .expect("msg: Valid leaf")
+            .finalize(&secp, internal_key)
+            .expect("msg: Complete tree");
+        let control_block = spend_info
+            .control_block(&(leaf.clone(), LeafVersion::TapScript))
+            .expect("msg: Leaf is in the tree");
+        let mut witness = items;
+        witness.push(leaf.into_bytes());
+        witness.push(control_block.serialize());
+        run(
+            ScriptBuf::new(),
+            ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+            witness,
+        )
+    }
+
+    fn p2sh(redeem_script: &ScriptBuf) -> ScriptBuf {
+        ScriptBuf::new_p2sh(&redeem_script.script_hash())
+    }
+
+    fn p2wsh(witness_script: &ScriptBuf) -> ScriptBuf {
+        ScriptBuf::new_p2wsh(&witness_script.wscript_hash())
+    }
+
+    #[test]
+    fn mainnet_spends_verify() {
+        for spend in SPENDS {
+            let (tx, prevouts) = decode(spend);
+            for (input, result) in verify_transaction(&tx, &prevouts).into_iter().enumerate() {
+                assert_eq!(result, Ok(()), "{} input {}", spend.name, input);
+            }
+        }
+    }
+
+    #[test]
+    fn changed_outputs_break_signatures() {
+        for spend in SPENDS {
+            let (mut tx, prevouts) = decode(spend);
+            // The envelope leaf has no signature to break
+            if spend.name.contains("envelope") {
+                continue;
+            }
+            // Every sighash type in the vectors commits to the first output
+            tx.output[0].value += Amount::ONE_SAT;
+            let result = verify_transaction(&tx, &prevouts).remove(0);
+            if spend.name.starts_with("taproot") {
+                assert_eq!(result, Err(ScriptError::SchnorrSig), "{}", spend.name);
+            } else {
+                assert!(result.is_err(), "{}", spend.name);
+            }
+        }
+    }
+
+    #[test]
+    fn segwit_sighashes_commit_to_amounts() {
+        for spend in SPENDS {
+            let (tx, mut prevouts) = decode(spend);
+            prevouts[0].value += Amount::ONE_SAT;
+            let result = verify_transaction(&tx, &prevouts).remove(0);
+            // Legacy sighashes do not cover the spent amount (BIP143 fixed that)
+            let legacy = spend.name.starts_with("P2PKH") || spend.name == "P2SH multisig";
+            let unsigned = spend.name.contains("envelope");
+            assert_eq!(result.is_ok(), legacy || unsigned, "{}", spend.name);
+        }
+    }
+
+    #[test]
+    fn script_cases() {
+        let cases = [
+            (
+                Builder::new().push_int(1).push_int(2),
+                Builder::new()
+                    .push_opcode(all::OP_ADD)
+                    .push_int(3)
+                    .push_opcode(all::OP_EQUAL),
+                Ok(()),
+            ),
+            (
+                Builder::new().push_int(0),
+                Builder::new()
+                    .push_opcode(all::OP_IF)
+                    .push_int(0)
+                    .push_opcode(all::OP_ELSE)
+                    .push_int(1)
+                    .push_opcode(all::OP_ENDIF),
+                Ok(()),
+            ),
+            (
+                Builder::new().push_int(1),
+                Builder::new().push_opcode(all::OP_IF),
+                Err(ScriptError::UnbalancedConditional),
+            ),
+            (
+                Builder::new().push_int(1),
+                Builder::new().push_opcode(all::OP_RETURN),
+                Err(ScriptError::OpReturn),
+            ),
+            // Disabled opcodes fail even in an unexecuted branch
+            (
+                Builder::new().push_int(0),
+                Builder::new()
+                    .push_opcode(all::OP_IF)
+                    .push_opcode(all::OP_CAT)
+                    .push_opcode(all::OP_ENDIF)
+                    .push_int(1),
+                Err(ScriptError::DisabledOpcode),
+            ),
+            (
+                Builder::new().push_int(1),
+                Builder::new().push_opcode(all::OP_DROP),
+                Err(ScriptError::EvalFalse),
+            ),
+            (
+                Builder::new().push_int(0),
+                Builder::new()
+                    .push_int(1)
+                    .push_opcode(all::OP_EQUALVERIFY)
+                    .push_int(1),
+                Err(ScriptError::EqualVerify),
+            ),
+            (
+                Builder::new(),
+                Builder::new().push_opcode(all::OP_DUP),
+                Err(ScriptError::InvalidStackOperation),
+            ),
+            // Arithmetic inputs are limited to 4 bytes
+            (
+                Builder::new().push_slice([0, 0, 0, 0x80, 0]),
+                Builder::new().push_opcode(all::OP_1ADD),
+                Err(ScriptError::NumOverflow),
+            ),
+            (
+                Builder::new().push_int(-1),
+                Builder::new().push_opcode(all::OP_CLTV),
+                Err(ScriptError::NegativeLocktime),
+            ),
+            // A final sequence disables the transaction's locktime
+            (
+                Builder::new().push_int(0),
+                Builder::new().push_opcode(all::OP_CLTV),
+                Err(ScriptError::UnsatisfiedLocktime),
+            ),
+            // NULLDUMMY (BIP147): the extra CHECKMULTISIG element must be empty
+            (
+                Builder::new().push_int(1).push_int(0),
+                Builder::new()
+                    .push_int(1)
+                    .push_slice([2; 33])
+                    .push_int(1)
+                    .push_opcode(all::OP_CHECKMULTISIG)
+                    .push_opcode(all::OP_NOT),
+                Err(ScriptError::SigNullDummy),
+            ),
+            (
+                Builder::new().push_int(0).push_int(0),
+                Builder::new()
+                    .push_int(1)
+                    .push_slice([2; 33])
+                    .push_int(1)
+                    .push_opcode(all::OP_CHECKMULTISIG)
+                    .push_opcode(all::OP_NOT),
+                Ok(()),
+            ),
+        ];
+        for (index, (script_sig, script_pubkey, expected)) in cases.into_iter().enumerate() {
+            let (script_sig, script_pubkey) =
+                (script_sig.into_script(), script_pubkey.into_script());
+            assert_eq!(
+                run(script_sig.clone(), script_pubkey.clone(), Vec::new()),
+                expected,
+                "case {}: {} / {}",
+                index,
+                script_sig.to_asm_string(),
+                script_pubkey.to_asm_string()
+            );
+        }
+    }
+
+    #[test]
+    fn p2sh_cases() {
+        let redeem_script = Builder::new().push_int(1).into_script();
+        let push_redeem = Builder::new()
+            .push_slice(<&[u8; 1]>::try_from(redeem_script.as_bytes()).expect("msg: One byte"));
+        assert_eq!(
+            run(
+                push_redeem.clone().into_script(),
+                p2sh(&redeem_script),
+                Vec::new()
+            ),
+            Ok(())
+        );
+        // The scriptSig must be push-only once the P2SH script is recognised
+        let script_sig = Builder::new()
+            .push_opcode(all::OP_NOP)
+            .push_slice(<&[u8; 1]>::try_from(redeem_script.as_bytes()).expect("msg: One byte"))
+            .into_script();
+        assert_eq!(
+            run(script_sig, p2sh(&redeem_script), Vec::new()),
+            Err(ScriptError::SigPushOnly)
+        );
+        // A redeem script evaluating to false fails after the outer hash matched
+        let redeem_false = Builder::new().push_int(0).into_script();
+        let script_sig = Builder::new()
+            .push_slice(<&[u8; 1]>::try_from(redeem_false.as_bytes()).expect("msg: One byte"))
+            .into_script();
+        assert_eq!(
+            run(script_sig, p2sh(&redeem_false), Vec::new()),
+            Err(ScriptError::EvalFalse)
+        );
+    }
+
+    #[test]
+    fn witness_cases() {
+        let witness_script = Builder::new().push_int(1).into_script();
+        let script_pubkey = p2wsh(&witness_script);
+        assert_eq!(
+            run(
+                ScriptBuf::new(),
+                script_pubkey.clone(),
+                vec![witness_script.to_bytes()]
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            run(ScriptBuf::new(), script_pubkey.clone(), Vec::new()),
+            Err(ScriptError::WitnessProgramWitnessEmpty)
+        );
+        assert_eq!(
+            run(
+                ScriptBuf::new(),
+                script_pubkey.clone(),
+                vec![vec![all::OP_PUSHNUM_2.to_u8()]]
+            ),
+            Err(ScriptError::WitnessProgramMismatch)
+        );
+        // Segwit scripts must leave exactly one element
+        let two = Builder::new().push_int(1).push_int(1).into_script();
+        assert_eq!(
+            run(ScriptBuf::new(), p2wsh(&two), vec![two.to_bytes()]),
+            Err(ScriptError::CleanStack)
+        );
+        // Native segwit spends must have an empty scriptSig
+        assert_eq!(
+            run(
+                Builder::new().push_int(1).into_script(),
+                script_pubkey,
+                vec![witness_script.to_bytes()]
+            ),
+            Err(ScriptError::WitnessMalleated)
+        );
+        // Legacy outputs may not carry a witness
+        assert_eq!(
+            run(
+                ScriptBuf::new(),
+                Builder::new().push_int(1).into_script(),
+                vec![vec![1]]
+            ),
+            Err(ScriptError::WitnessUnexpected)
+        );
+        let wrong_length = Builder::new().push_int(0).push_slice([1; 21]).into_script();
+        assert_eq!(
+            run(ScriptBuf::new(), wrong_length, vec![vec![1]]),
+            Err(ScriptError::WitnessProgramWrongLength)
+        );
+    }
+
+    #[test]
+    fn tapscript_cases() {
+        assert_eq!(
+            run_tapscript(Builder::new().push_int(1).into_script(), Vec::new()),
+            Ok(())
+        );
+        // OP_SUCCESSx makes the script succeed before anything else runs
+        assert_eq!(
+            run_tapscript(
+                Builder::new()
+                    .push_opcode(all::OP_RETURN)
+                    .push_opcode(all::OP_RESERVED)
+                    .into_script(),
+                Vec::new()
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            run_tapscript(
+                Builder::new()
+                    .push_int(0)
+                    .push_opcode(all::OP_CHECKMULTISIG)
+                    .into_script(),
+                Vec::new()
+            ),
+            Err(ScriptError::TapscriptCheckMultisig)
+        );
+        // MINIMALIF is consensus in tapscript
+        let if_leaf = Builder::new()
+            .push_opcode(all::OP_IF)
+            .push_int(1)
+            .push_opcode(all::OP_ENDIF)
+            .into_script();
+        assert_eq!(run_tapscript(if_leaf.clone(), vec![vec![1]]), Ok(()));
+        assert_eq!(
+            run_tapscript(if_leaf, vec![vec![2]]),
+            Err(ScriptError::TapscriptMinimalIf)
+        );
+        // An empty signature is a failed check, not an error
+        let checksig_leaf = Builder::new()
+            .push_slice([2; 32])
+            .push_opcode(all::OP_CHECKSIG)
+            .push_opcode(all::OP_NOT)
+            .into_script();
+        assert_eq!(
+            run_tapscript(checksig_leaf.clone(), vec![Vec::new()]),
+            Ok(())
+        );
+        assert_eq!(
+            run_tapscript(checksig_leaf, vec![vec![1; 63]]),
+            Err(ScriptError::SchnorrSigSize)
+        );
+    }
+
+    #[test]
+    fn taproot_control_block_size() {
+        let leaf = Builder::new().push_int(1).into_script();
+        let secp = Secp256k1::new();
+        let internal_key: XOnlyPublicKey = INTERNAL_KEY.parse().expect("msg: Valid key");
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, leaf.clone())
+            .expect("msg: Valid leaf")
+            .finalize(&secp, internal_key)
+            .expect("msg: Complete tree");
+        let mut control_block = spend_info
+            .control_block(&(leaf.clone(), LeafVersion::TapScript))
+            .expect("msg: Leaf is in the tree")
+            .serialize();
+        control_block.push(0);
+        assert_eq!(
+            run(
+                ScriptBuf::new(),
+                ScriptBuf::new_p2tr_tweaked(spend_info.output_key()),
+                vec![leaf.into_bytes(), control_block]
+            ),
+            Err(ScriptError::TaprootWrongControlSize)
+        );
+    }
+}
@@ -1,21 +1,59 @@
-use std::{path::Path, str::FromStr};
-
-use bitcoincore_rpc::bitcoin::{
-    Address, Amount, BlockHash, ScriptBuf, Sequence, Target, Transaction, TxIn, TxMerkleNode,
-    TxOut, Txid, Witness, Wtxid,
-    absolute::LockTime,
-    block::{Header, Version},
-    consensus::{self, Decodable},
-    hashes::{
-        Hash as OtherHash,
-        sha256d::{self, Hash},
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering as AtomicOrdering},
+        mpsc::{self, RecvTimeoutError},
     },
-    merkle_tree::calculate_root,
-    transaction::Version as TxVersion,
+    thread,
+    time::{Duration, Instant},
 };
-use serde::Deserialize;
 
-#[derive(Debug, Deserialize)]
+use bitcoincore_rpc::{
+    Auth,
+    bitcoin::{
+        Address, Amount, Block, BlockHash, CompactTarget, Script, ScriptBuf, Sequence, Target,
+        Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness, Wtxid,
+        absolute::LockTime,
+        block::{Header, Version},
+        consensus::{self, Decodable},
+        hashes::{
+            Hash as OtherHash, HashEngine, sha256,
+            sha256d::{self, Hash},
+        },
+        merkle_tree::calculate_root,
+        script::{Builder, PushBytes},
+        transaction::Version as TxVersion,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+mod block_validation;
+mod chain;
+mod conflicts;
+mod difficulty;
+mod finality;
+mod gbt;
+mod interpreter;
+mod mempool;
+mod merkle;
+mod policy;
+mod rbf;
+mod selection;
+mod sigops;
+mod stratum;
+mod validation;
+
+use conflicts::ConflictRule;
+use difficulty::Network;
+use mempool::MempoolSource;
+use selection::SelectionStrategy;
+use sigops::MAX_BLOCK_SIGOPS_COST;
+
+#[derive(Debug, PartialEq, Deserialize)]
 struct MempoolTransaction {
     txid: String,
     version: u32,
@@ -31,7 +69,7 @@ struct MempoolTransaction {
     hex: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize)]
 struct Vin {
     txid: String,
     vout: u32,
@@ -48,7 +86,7 @@ struct Vin {
     sequence: u64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize)]
 struct Prevout {
     scriptpubkey: String,
     scriptpubkey_asm: String,
@@ -56,9 +94,13 @@ struct Prevout {
     #[serde(default)]
     scriptpubkey_address: Option<String>,
     value: u64,
+    // Where the output confirmed, when the source knows it; esplora data only
+    // has this on the status of the parent transaction
+    #[serde(default)]
+    confirmation: Option<finality::Confirmation>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize)]
 struct Vout {
     scriptpubkey: String,
     scriptpubkey_asm: String,
@@ -68,7 +110,7 @@ struct Vout {
     value: u64,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, PartialEq, Deserialize)]
 struct Status {
     confirmed: bool,
     #[serde(default)]
@@ -85,79 +127,738 @@ struct ValidTransactions {
     hex: String,
     weight: u32,
     fee: u64,
+    // Txids of the outputs this transaction spends; those present in the
+    // mempool set are its unconfirmed parents
+    #[serde(default)]
+    parents: Vec<String>,
+    #[serde(default)]
+    sigop_cost: u64,
+    // Version, locktime and input sequences, which decide finality
+    #[serde(default)]
+    version: i32,
+    #[serde(default)]
+    lock_time: u32,
+    #[serde(default)]
+    sequences: Vec<u32>,
+    // Where each input's prevout confirmed, where the mempool data says,
+    // indexed like `parents`
+    #[serde(default)]
+    prevout_confirmations: Vec<Option<finality::Confirmation>>,
 }
 
-const DIFFICULTY_TARGET: &'static str =
-    "0000ffff00000000000000000000000000000000000000000000000000000000";
+const DIFFICULTY_TARGET: &str = "0000ffff00000000000000000000000000000000000000000000000000000000";
 
 const MAX_BLOCK_WEIGHT: u32 = 4_000_000;
 
-fn main() {
-    let valid_txs = load_txs();
-    let miner_address = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
-        .expect("msg: Invalid miner address")
-        .assume_checked();
-    let previous_hash =
-        BlockHash::from_str("0000000000000000000c6f8b1d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e")
-            .expect("msg: Invalid previous block hash");
-    let (header, txs) = mine_transaction_block(valid_txs, miner_address, previous_hash)
-        .expect("msg: Failed to mine transaction block");
+// BIP9 versionbits top bits with no deployment bit set. Above the version 4
+// that BIP34, BIP66 and BIP65 require.
+const BLOCK_VERSION: i32 = 0x2000_0000;
 
-    println!("{}", hex::encode(consensus::serialize(&header)));
-    println!("{}", hex::encode(consensus::serialize(&txs[0])));
+// Weight of the 80-byte header plus the largest transaction count varint
+const BLOCK_OVERHEAD_WEIGHT: u32 = (80 + 9) * 4;
 
-    for tx in &txs {
-        println!("{}", tx.compute_txid());
+// Size of the extranonce push in the coinbase scriptSig
+const EXTRANONCE_SIZE: usize = 8;
+
+// Coinbase scriptSig length limits enforced by consensus
+const MIN_COINBASE_SCRIPT_SIZE: usize = 2;
+const MAX_COINBASE_SCRIPT_SIZE: usize = 100;
+
+const DEFAULT_MINER_TAG: &str = "/research-miner/";
+
+// Credentials of the regtest node in docker-compose.yaml, used with --rpc
+const DEFAULT_RPC_USER: &str = "alice";
+const DEFAULT_RPC_PASSWORD: &str = "password";
+
+// Nonces a mining worker hashes between checks of the stop flags
+const NONCE_BATCH_SIZE: u32 = 1 << 16;
+
+// Seconds past the template time a mining worker rolls the header time
+// through before moving on to its next extranonce
+const MAX_NTIME_ROLL: u32 = 60;
+
+// BIP141 witness reserved value, carried as the single coinbase witness item
+const WITNESS_RESERVED_VALUE: [u8; 32] = [0u8; 32];
+
+// OP_RETURN push of 36 bytes starting with the BIP141 commitment tag
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// Command-line options, e.g. `mining --conflict-rule first-seen --height 840000 --network testnet`
+// or `mining --rpc http://127.0.0.1:18443 --network regtest --gbt-bind 127.0.0.1:18444`
+struct Config {
+    mempool: MempoolSource,
+    conflict_rule: ConflictRule,
+    // Leave transactions that break relay policy out of the set
+    require_standard: bool,
+    selection: SelectionStrategy,
+    // Height of the block to mine; defaults to the one after the highest
+    // block the mempool data mentions
+    height: Option<u32>,
+    miner_tag: String,
+    threads: usize,
+    // Explicit target from --bits or --target
+    bits: Option<CompactTarget>,
+    network: Option<Network>,
+    // Previous headers to retarget from, ending at height - 1
+    headers: Option<PathBuf>,
+    // Header store to build on and extend; --height only places the first
+    // block of a new store
+    chain: Option<PathBuf>,
+    // Successive blocks to mine, each on top of the last
+    blocks: u32,
+    // Give up on a block that takes longer than this to mine
+    timeout: Option<Duration>,
+    // Where to write the serialized block and its JSON summary; `-` is stdout
+    block_out: Option<PathBuf>,
+    summary_out: Option<PathBuf>,
+    // Check this block instead of mining one
+    validate_block: Option<PathBuf>,
+    // Transactions to prove inclusion of in a merkleblock written to merkleblock_out
+    prove: Vec<Txid>,
+    merkleblock_out: Option<PathBuf>,
+    // Check this merkleblock instead of mining
+    verify_merkleblock: Option<PathBuf>,
+    // Check this transaction as a BIP125 replacement into the mempool set
+    // instead of mining
+    replace: Option<PathBuf>,
+    // Serve getblocktemplate or Stratum on this address instead of mining
+    gbt_bind: Option<String>,
+    stratum_bind: Option<String>,
+    // Stratum share difficulty, relative to difficulty 1
+    share_difficulty: Option<f64>,
+}
+
+impl Config {
+    fn from_args() -> Result<Config, String> {
+        let mut config = Config {
+            mempool: MempoolSource::Directory {
+                dir: PathBuf::from("mempool"),
+                index: None,
+            },
+            conflict_rule: ConflictRule::HighestFeeRate,
+            require_standard: false,
+            selection: SelectionStrategy::Greedy,
+            height: None,
+            miner_tag: DEFAULT_MINER_TAG.to_string(),
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            bits: None,
+            network: None,
+            headers: None,
+            chain: None,
+            blocks: 1,
+            timeout: None,
+            block_out: None,
+            summary_out: None,
+            validate_block: None,
+            prove: Vec::new(),
+            merkleblock_out: None,
+            verify_merkleblock: None,
+            replace: None,
+            gbt_bind: None,
+            stratum_bind: None,
+            share_difficulty: None,
+        };
+
+        let mut mempool_dir = None;
+        let mut index = None;
+        let mut rpc_url = None;
+        let mut rpc_user = DEFAULT_RPC_USER.to_string();
+        let mut rpc_password = DEFAULT_RPC_PASSWORD.to_string();
+        let mut rpc_cookie = None;
+
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| format!("Missing value for {}", arg))
+            };
+            match arg.as_str() {
+                "--mempool" => mempool_dir = Some(PathBuf::from(value()?)),
+                "--index" => index = Some(PathBuf::from(value()?)),
+                "--rpc" => rpc_url = Some(value()?),
+                "--rpc-user" => rpc_user = value()?,
+                "--rpc-password" => rpc_password = value()?,
+                "--rpc-cookie" => rpc_cookie = Some(PathBuf::from(value()?)),
+                "--conflict-rule" => config.conflict_rule = value()?.parse()?,
+                "--require-standard" => config.require_standard = true,
+                "--selection" => config.selection = value()?.parse()?,
+                "--miner-tag" => config.miner_tag = value()?,
+                "--threads" => {
+                    config.threads = value()?
+                        .parse()
+                        .ok()
+                        .filter(|threads| *threads > 0)
+                        .ok_or("Invalid --threads: expected a positive integer")?
+                }
+                "--bits" | "--target" if config.bits.is_some() => {
+                    return Err("Only one of --bits and --target may be given".to_string());
+                }
+                "--bits" => config.bits = Some(difficulty::parse_bits(&value()?)?),
+                "--target" => {
+                    let target = difficulty::parse_target(&value()?)?;
+                    let bits = target.to_compact_lossy();
+                    if Target::from_compact(bits) != target {
+                        println!(
+                            "msg: Target {:x} rounded to compact bits {:08x}",
+                            target,
+                            bits.to_consensus()
+                        );
+                    }
+                    config.bits = Some(bits);
+                }
+                "--network" => config.network = Some(value()?.parse()?),
+                "--headers" => config.headers = Some(PathBuf::from(value()?)),
+                "--chain" => config.chain = Some(PathBuf::from(value()?)),
+                "--blocks" => {
+                    config.blocks = value()?
+                        .parse()
+                        .ok()
+                        .filter(|blocks| *blocks > 0)
+                        .ok_or("Invalid --blocks: expected a positive integer")?
+                }
+                "--timeout" => {
+                    config.timeout =
+                        Some(Duration::from_secs(
+                            value()?.parse().ok().filter(|seconds| *seconds > 0).ok_or(
+                                "Invalid --timeout: expected a positive number of seconds",
+                            )?,
+                        ))
+                }
+                "--block-out" => config.block_out = Some(PathBuf::from(value()?)),
+                "--summary-out" => config.summary_out = Some(PathBuf::from(value()?)),
+                "--validate-block" => config.validate_block = Some(PathBuf::from(value()?)),
+                "--prove" => config.prove.push(
+                    value()?
+                        .parse()
+                        .map_err(|e| format!("Invalid --prove: {}", e))?,
+                ),
+                "--merkleblock-out" => config.merkleblock_out = Some(PathBuf::from(value()?)),
+                "--verify-merkleblock" => config.verify_merkleblock = Some(PathBuf::from(value()?)),
+                "--replace" => config.replace = Some(PathBuf::from(value()?)),
+                "--gbt-bind" => config.gbt_bind = Some(value()?),
+                "--stratum-bind" => config.stratum_bind = Some(value()?),
+                "--share-difficulty" => {
+                    config.share_difficulty = Some(
+                        value()?
+                            .parse()
+                            .ok()
+                            .filter(|difficulty: &f64| *difficulty > 0.0)
+                            .ok_or("Invalid --share-difficulty: expected a positive number")?,
+                    )
+                }
+                "--height" => {
+                    config.height = Some(
+                        value()?
+                            .parse()
+                            .map_err(|e| format!("Invalid --height: {}", e))?,
+                    )
+                }
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+
+        match (mempool_dir, rpc_url) {
+            (Some(_), Some(_)) => {
+                return Err("Only one of --mempool and --rpc may be given".to_string());
+            }
+            (_, Some(_)) if index.is_some() => {
+                return Err("--index only applies to a --mempool directory".to_string());
+            }
+            (Some(dir), None) => config.mempool = MempoolSource::Directory { dir, index },
+            (None, None) => {
+                if let MempoolSource::Directory { index: default, .. } = &mut config.mempool {
+                    *default = index;
+                }
+            }
+            (None, Some(url)) => {
+                let auth = match rpc_cookie {
+                    Some(cookie) => Auth::CookieFile(cookie),
+                    None => Auth::UserPass(rpc_user, rpc_password),
+                };
+                config.mempool = MempoolSource::Rpc { url, auth };
+            }
+        }
+
+        if config.prove.is_empty() != config.merkleblock_out.is_none() {
+            return Err("--prove and --merkleblock-out must be given together".to_string());
+        }
+        if config.gbt_bind.is_some() && config.stratum_bind.is_some() {
+            return Err("Only one of --gbt-bind and --stratum-bind may be given".to_string());
+        }
+
+        if config.bits.is_some() && config.headers.is_some() {
+            return Err(
+                "--headers retargets on its own and cannot be combined with --bits or --target"
+                    .to_string(),
+            );
+        }
+        if matches!(config.mempool, MempoolSource::Rpc { .. })
+            && (config.headers.is_some() || config.chain.is_some())
+        {
+            return Err(
+                "--rpc builds on the node's tip and cannot be combined with --headers or --chain"
+                    .to_string(),
+            );
+        }
+        if config.headers.is_some() && config.chain.is_some() {
+            return Err("Only one of --headers and --chain may be given".to_string());
+        }
+
+        Ok(config)
+    }
+
+    // Bits for the block after the tip of `chain`: an explicit target, the
+    // retarget result over the chain's headers, the network's minimum
+    // difficulty, or the built-in research target, in that order
+    fn bits(&self, chain: &chain::HeaderChain, time: u32) -> Result<CompactTarget, String> {
+        if let Some(bits) = self.bits {
+            return Ok(bits);
+        }
+        if !chain.headers.is_empty() {
+            let network = self.network.unwrap_or(Network::Mainnet);
+            return difficulty::next_work_required(
+                network,
+                &chain.headers,
+                chain.next_height() - 1,
+                time,
+            );
+        }
+        if let Some(network) = self.network {
+            return Ok(network.pow_limit().to_compact_lossy());
+        }
+        Ok(difficulty::parse_target(DIFFICULTY_TARGET)?.to_compact_lossy())
     }
 }
 
-// Load valid txs
-fn load_txs() -> Vec<ValidTransactions> {
-    let txs_path = Path::new("mempool/");
-    if !txs_path.exists() {
-        panic!("msg: Mempool directory does not exist");
+// A mined block with the details that are not recoverable from it alone
+struct MinedBlock {
+    block: Block,
+    height: u32,
+    fees: Amount,
+    sigop_cost: u64,
+}
+
+// JSON description of a mined block, written with --summary-out
+#[derive(Debug, Serialize)]
+struct BlockSummary {
+    hash: BlockHash,
+    height: u32,
+    weight: u64,
+    size: usize,
+    fees: u64,
+    sigop_cost: u64,
+    tx_count: usize,
+}
+
+impl MinedBlock {
+    fn summary(&self) -> BlockSummary {
+        BlockSummary {
+            hash: self.block.block_hash(),
+            height: self.height,
+            weight: self.block.weight().to_wu(),
+            size: self.block.total_size(),
+            fees: self.fees.to_sat(),
+            sigop_cost: self.sigop_cost,
+            tx_count: self.block.txdata.len(),
+        }
     }
-    let mempool_json_path = txs_path.join("mempool.json");
-    if !mempool_json_path.exists() {
-        panic!("msg: Mempool JSON file does not exist");
+}
+
+// An unmined block: the header has a zero nonce and the coinbase a zero extranonce
+#[derive(Clone)]
+struct BlockTemplate {
+    block: Block,
+    height: u32,
+    subsidy: Amount,
+    fees: Amount,
+    sigop_cost: u64,
+    // Indices into the valid transactions of block.txdata[1..]
+    selected: Vec<usize>,
+    witness_commitment: Option<[u8; 32]>,
+}
+
+// Everything about the block being mined other than its transactions
+struct BlockParams<'a> {
+    miner_address: Address,
+    previous_hash: BlockHash,
+    height: u32,
+    time: u32,
+    // Median time past of the previous block, the cutoff for time locks
+    median_time_past: u32,
+    bits: CompactTarget,
+    miner_tag: &'a [u8],
+    // Decides the subsidy schedule
+    network: Network,
+}
+
+fn main() {
+    let mut config = Config::from_args().expect("msg: Invalid arguments");
+    if let Some(path) = &config.validate_block {
+        validate_block_file(
+            path,
+            &config.mempool,
+            config.threads,
+            config.network.unwrap_or(Network::Mainnet),
+        );
+        return;
+    }
+    if let Some(path) = &config.verify_merkleblock {
+        verify_merkleblock_file(path);
+        return;
+    }
+    if let Some(path) = &config.replace {
+        check_replacement_file(path, &config);
+        return;
     }
-    let file = std::fs::File::open(mempool_json_path).expect("msg: Failed to open file");
-    let reader = std::io::BufReader::new(file);
-    let txs: Vec<String> = serde_json::from_reader(reader).expect("msg: Failed to parse JSON");
 
-    let mut valid_transactions = Vec::new();
+    // A node behind --rpc decides the network and the tip to build on
+    let node = config
+        .mempool
+        .node()
+        .expect("msg: Failed to connect to node");
+    if let Some(node) = &node {
+        let network = chain::node_network(node).expect("msg: Failed to query node");
+        if let Some(configured) = config.network.filter(|configured| *configured != network) {
+            panic!(
+                "msg: --network {} does not match the node's {}",
+                configured, network
+            );
+        }
+        config.network = Some(network);
+    }
 
-    for tx in txs.iter() {
-        let tx_path = txs_path.join(format!("{}.json", tx));
-        if !tx_path.exists() {
-            println!("msg: Transaction file does not exist: {}", tx);
-            continue;
+    let mempool_transactions = config
+        .mempool
+        .load(config.threads)
+        .expect("msg: Failed to load mempool");
+    // Blocks are checked against the outputs the mempool spends
+    let prevouts = block_validation::mempool_prevouts(&mempool_transactions);
+    let requested_height = config
+        .height
+        .unwrap_or_else(|| next_height(&mempool_transactions));
+    let valid_txs = load_txs(
+        mempool_transactions,
+        config.conflict_rule,
+        config.require_standard,
+    );
+    let miner_address = Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx")
+        .expect("msg: Invalid miner address")
+        .assume_checked();
+    // Build on the node's tip, the header store or the supplied headers if
+    // there are any, otherwise on a fixed parent
+    let headers = match (&node, &config.chain, &config.headers) {
+        (Some(node), _, _) => chain::node_headers(node),
+        (None, Some(path), _) => chain::HeaderChain::open(path, requested_height),
+        (None, None, Some(path)) => difficulty::read_headers(path).and_then(|headers| {
+            let start_height = requested_height
+                .checked_sub(headers.len() as u32)
+                .ok_or("More headers than the height allows")?;
+            Ok(chain::HeaderChain::new(start_height, headers))
+        }),
+        (None, None, None) => Ok(chain::HeaderChain::new(requested_height, Vec::new())),
+    }
+    .expect("msg: Failed to load headers");
+    let height = headers.next_height();
+    let time = headers.next_block_time(chain::unix_time());
+    let previous_hash = match headers.tip() {
+        Some(tip) => tip.block_hash(),
+        None => {
+            BlockHash::from_str("0000000000000000000c6f8b1d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e")
+                .expect("msg: Invalid previous block hash")
         }
+    };
+    let bits = config
+        .bits(&headers, time)
+        .expect("msg: Failed to determine difficulty target");
+    println!(
+        "msg: Mining at height {} with bits {:08x} (target {:x})",
+        height,
+        bits.to_consensus(),
+        Target::from_compact(bits)
+    );
 
-        match std::fs::File::open(&tx_path) {
-            Ok(tx_file) => match serde_json::from_reader::<_, MempoolTransaction>(tx_file) {
-                Ok(tx_data) => {
-                    if tx_data.hex.is_some() && !tx_data.txid.is_empty() {
-                        let hex = tx_data.hex.clone().unwrap();
-                        let valid_tx = ValidTransactions {
-                            id: tx_data.txid,
-                            hex,
-                            weight: tx_data.weight,
-                            fee: tx_data.fee,
-                        };
-                        valid_transactions.push(valid_tx);
-                    }
+    let params = BlockParams {
+        miner_address,
+        previous_hash,
+        height,
+        time,
+        median_time_past: headers.median_time_past().unwrap_or(time),
+        bits,
+        miner_tag: config.miner_tag.as_bytes(),
+        network: config.network.unwrap_or(Network::Mainnet),
+    };
+    let next_bits = |headers: &chain::HeaderChain, time| config.bits(headers, time);
+    let mut chain = chain::ChainState::new(
+        valid_txs,
+        prevouts,
+        params,
+        headers,
+        config.selection,
+        &next_bits,
+    )
+    .expect("msg: Failed to build block template");
+    if let Some(addr) = &config.gbt_bind {
+        gbt::TemplateServer::new(chain, node)
+            .serve(addr)
+            .expect("msg: Template server failed");
+        return;
+    }
+    if let Some(addr) = &config.stratum_bind {
+        stratum::StratumServer::new(chain, config.share_difficulty)
+            .serve(addr)
+            .expect("msg: Stratum server failed");
+        return;
+    }
+
+    // Ctrl-c cancels the block being mined; the blocks already mined are kept
+    let interrupted = Arc::new(AtomicBool::new(false));
+    {
+        let interrupted = Arc::clone(&interrupted);
+        ctrlc::set_handler(move || interrupted.store(true, AtomicOrdering::Relaxed))
+            .expect("msg: Failed to set ctrl-c handler");
+    }
+
+    // Mine each block on the last, leaving its transactions out of the next.
+    // The outputs below describe the last block.
+    let mut mined = None;
+    for block_number in 1..=config.blocks {
+        if config.blocks > 1 {
+            println!(
+                "msg: Mining block {} of {} at height {} with bits {:08x} ({} transactions left)",
+                block_number,
+                config.blocks,
+                chain.params.height,
+                chain.params.bits.to_consensus(),
+                chain.valid_txs.len()
+            );
+        }
+        let template = chain
+            .template()
+            .expect("msg: Failed to build block template")
+            .clone();
+        let block =
+            match mine_until_cancelled(template, config.threads, &interrupted, config.timeout) {
+                Ok(block) => block,
+                Err(e) if mined.is_some() => {
+                    println!("msg: Stopped after {} blocks: {}", block_number - 1, e);
+                    break;
                 }
                 Err(e) => {
-                    println!("msg: Failed to parse transaction file {}: {}", tx, e);
-                    continue;
+                    println!("msg: Failed to mine transaction block: {}", e);
+                    std::process::exit(1);
                 }
-            },
-            Err(e) => {
-                println!("msg: Failed to open transaction file {}: {}", tx, e);
+            };
+        if let Err(reason) = chain.check_block(&block.block, true) {
+            panic!("msg: Mined block is invalid: {}", reason);
+        }
+        chain.accept_block(&block.block);
+        mined = Some(block);
+    }
+    let mined = mined.expect("msg: At least one block is mined");
+
+    if let Some(path) = &config.block_out {
+        write_output(path, &hex::encode(consensus::serialize(&mined.block)))
+            .expect("msg: Failed to write block");
+    }
+    if let Some(path) = &config.summary_out {
+        let summary = serde_json::to_string_pretty(&mined.summary())
+            .expect("msg: Failed to serialize block summary");
+        write_output(path, &summary).expect("msg: Failed to write block summary");
+    }
+    if let Some(path) = &config.merkleblock_out {
+        let txids: Vec<Txid> = mined
+            .block
+            .txdata
+            .iter()
+            .map(|tx| tx.compute_txid())
+            .collect();
+        for txid in &config.prove {
+            let Some(position) = txids.iter().position(|included| included == txid) else {
+                println!("msg: Transaction {} is not in the block", txid);
+                continue;
+            };
+            let proof =
+                merkle::merkle_proof(&txids, position).expect("msg: Position is in the block");
+            assert!(
+                proof.verify(*txid, mined.block.header.merkle_root),
+                "msg: Merkle proof does not reach the block's merkle root"
+            );
+            println!(
+                "msg: Transaction {} is at position {} ({} branch hashes)",
+                txid,
+                position,
+                proof.siblings.len()
+            );
+        }
+        let proven: HashSet<Txid> = config.prove.iter().copied().collect();
+        let merkle_block = merkle::MerkleBlock::new(&mined.block, &proven);
+        write_output(path, &hex::encode(consensus::serialize(&merkle_block)))
+            .expect("msg: Failed to write merkleblock");
+    }
+    if config.block_out.is_some()
+        || config.summary_out.is_some()
+        || config.merkleblock_out.is_some()
+    {
+        return;
+    }
+
+    println!("{}", hex::encode(consensus::serialize(&mined.block.header)));
+    println!(
+        "{}",
+        hex::encode(consensus::serialize(&mined.block.txdata[0]))
+    );
+
+    for tx in &mined.block.txdata {
+        println!("{}", tx.compute_txid());
+    }
+}
+
+// Write to the file at `path`, or to stdout when `path` is `-`
+fn write_output(path: &Path, contents: &str) -> Result<(), String> {
+    if path == Path::new("-") {
+        println!("{}", contents);
+        return Ok(());
+    }
+    std::fs::write(path, format!("{}\n", contents))
+        .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+// The height after the highest block the mempool data records a confirmation
+// in, so finality is judged against the chain the data comes from; 1 when it
+// records none
+fn next_height(mempool_transactions: &[MempoolTransaction]) -> u32 {
+    let statuses = mempool_transactions
+        .iter()
+        .filter_map(|tx| confirmation(&tx.status));
+    let prevouts = mempool_transactions
+        .iter()
+        .flat_map(|tx| &tx.vin)
+        .filter_map(|vin| vin.prevout.as_ref()?.confirmation);
+    statuses
+        .chain(prevouts)
+        .map(|confirmation| confirmation.height + 1)
+        .max()
+        .unwrap_or(1)
+}
+
+// Esplora gives the time of a confirming block rather than the median time
+// past before it; the block time is later, so locks measured from it only
+// mature later than they should
+fn confirmation(status: &Option<Status>) -> Option<finality::Confirmation> {
+    let status = status.as_ref().filter(|status| status.confirmed)?;
+    Some(finality::Confirmation {
+        height: status.block_height?,
+        median_time_past: status.block_time? as u32,
+    })
+}
+
+// Where each input's prevout confirmed, indexed like `vin`. A prevout outside
+// the mempool data confirmed no later than its spender. One with no known
+// confirmation at all is assumed old enough, as a node only admits
+// transactions whose locks were met when they arrived; parents that are in
+// the set are treated as unconfirmed by finality::final_transactions anyway.
+fn prevout_confirmations(
+    mempool_transactions: &[MempoolTransaction],
+) -> Vec<Vec<Option<finality::Confirmation>>> {
+    let confirmations: HashMap<&str, finality::Confirmation> = mempool_transactions
+        .iter()
+        .filter_map(|tx| Some((tx.txid.as_str(), confirmation(&tx.status)?)))
+        .collect();
+    mempool_transactions
+        .iter()
+        .map(|tx| {
+            tx.vin
+                .iter()
+                .map(|vin| {
+                    let known = vin
+                        .prevout
+                        .as_ref()
+                        .and_then(|prevout| prevout.confirmation)
+                        .or_else(|| confirmations.get(vin.txid.as_str()).copied())
+                        .or_else(|| confirmation(&tx.status));
+                    Some(known.unwrap_or(finality::MATURE))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+// Load valid txs
+fn load_txs(
+    mempool_transactions: Vec<MempoolTransaction>,
+    conflict_rule: ConflictRule,
+    require_standard: bool,
+) -> Vec<ValidTransactions> {
+    let results =
+        validation::validate_transactions(&mempool_transactions, conflict_rule, require_standard);
+    let prevout_confirmations = prevout_confirmations(&mempool_transactions);
+
+    let mut rejections: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut flagged = 0;
+    let mut non_standard: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut valid_transactions = Vec::new();
+    for ((tx_data, result), prevout_confirmations) in mempool_transactions
+        .into_iter()
+        .zip(results)
+        .zip(prevout_confirmations)
+    {
+        let verified = match result {
+            Ok(verified) => verified,
+            Err(verdict) => {
+                *rejections.entry(verdict.kind()).or_default() += 1;
                 continue;
             }
+        };
+        // Keep transactions whose JSON misstates fee or weight, using the computed values
+        if !verified.discrepancies.is_empty() {
+            flagged += 1;
+            for discrepancy in &verified.discrepancies {
+                println!("msg: {}.json: {}", tx_data.txid, discrepancy);
+            }
+        }
+        // Without --require-standard, non-standard transactions stay in the set
+        for violation in &verified.policy_violations {
+            println!(
+                "msg: {}.json: non-standard: {} ({})",
+                tx_data.txid,
+                violation.reason(),
+                violation
+            );
         }
+        if let Some(violation) = verified.policy_violations.first() {
+            *non_standard.entry(violation.reason()).or_default() += 1;
+        }
+        let valid_tx = ValidTransactions {
+            id: tx_data.txid,
+            hex: tx_data.hex.expect("msg: Validated transaction has hex"),
+            weight: verified.weight as u32,
+            fee: verified.fee,
+            parents: tx_data.vin.iter().map(|vin| vin.txid.clone()).collect(),
+            sigop_cost: verified.sigop_cost,
+            version: verified.tx.version.0,
+            lock_time: verified.tx.lock_time.to_consensus_u32(),
+            sequences: verified
+                .tx
+                .input
+                .iter()
+                .map(|input| input.sequence.0)
+                .collect(),
+            prevout_confirmations,
+        };
+        valid_transactions.push(valid_tx);
+    }
+
+    if flagged > 0 {
+        println!(
+            "msg: {} transactions declare a fee or weight that disagrees with their hex",
+            flagged
+        );
+    }
+    for (reason, count) in &non_standard {
+        println!("msg: Kept {} non-standard transactions: {}", count, reason);
+    }
+    for (kind, count) in &rejections {
+        println!("msg: Rejected {} transactions: {}", count, kind);
     }
 
     println!(
@@ -168,44 +869,174 @@ fn load_txs() -> Vec<ValidTransactions> {
     valid_transactions
 }
 
+// Check a serialized block from `path` against the mempool set, exiting with
+// status 1 if it breaks any rule
+fn validate_block_file(path: &Path, mempool: &MempoolSource, threads: usize, network: Network) {
+    let contents = std::fs::read(path).expect("msg: Failed to read block file");
+    // Accept the hex written by --block-out as well as raw bytes
+    let bytes = std::str::from_utf8(&contents)
+        .ok()
+        .and_then(|text| hex::decode(text.trim()).ok())
+        .unwrap_or(contents);
+    let block: Block = consensus::deserialize(&bytes).expect("msg: Failed to decode block");
+
+    let mempool_transactions = mempool.load(threads).expect("msg: Failed to load mempool");
+    let prevouts = block_validation::mempool_prevouts(&mempool_transactions);
+    let violations = block_validation::validate_block(&block, &prevouts, network);
+
+    for violation in &violations {
+        println!("msg: Block violation: {}", violation);
+    }
+    if !violations.is_empty() {
+        println!(
+            "msg: Block {} is invalid: {} violations",
+            block.block_hash(),
+            violations.len()
+        );
+        std::process::exit(1);
+    }
+    println!(
+        "msg: Block {} is valid ({} transactions)",
+        block.block_hash(),
+        block.txdata.len()
+    );
+}
+
+// Check a merkleblock written by --merkleblock-out and list the transactions
+// it proves
+fn verify_merkleblock_file(path: &Path) {
+    let contents = std::fs::read(path).expect("msg: Failed to read merkleblock file");
+    let bytes = std::str::from_utf8(&contents)
+        .ok()
+        .and_then(|text| hex::decode(text.trim()).ok())
+        .unwrap_or(contents);
+    let merkle_block: merkle::MerkleBlock =
+        consensus::deserialize(&bytes).expect("msg: Failed to decode merkleblock");
+
+    match merkle_block.verify() {
+        Ok(matches) => {
+            for (position, txid) in &matches {
+                println!("msg: Transaction {} is at position {}", txid, position);
+            }
+            println!(
+                "msg: Merkleblock for block {} proves {} of {} transactions",
+                merkle_block.header.block_hash(),
+                matches.len(),
+                merkle_block.tree.total_transactions
+            );
+        }
+        Err(e) => {
+            println!("msg: Merkleblock is invalid: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Check a serialized transaction from `path` as a replacement for the
+// transactions in the mempool set it conflicts with, exiting with status 1 if
+// the BIP125 rules reject it
+fn check_replacement_file(path: &Path, config: &Config) {
+    let contents = std::fs::read(path).expect("msg: Failed to read transaction file");
+    let bytes = std::str::from_utf8(&contents)
+        .ok()
+        .and_then(|text| hex::decode(text.trim()).ok())
+        .unwrap_or(contents);
+    let tx: Transaction =
+        consensus::deserialize(&bytes).expect("msg: Failed to decode transaction");
+
+    let mempool_transactions = config
+        .mempool
+        .load(config.threads)
+        .expect("msg: Failed to load mempool");
+    let prevouts = block_validation::mempool_prevouts(&mempool_transactions);
+    let valid_txs = load_txs(
+        mempool_transactions,
+        config.conflict_rule,
+        config.require_standard,
+    );
+    let pool = rbf::Pool::new(&valid_txs, &prevouts).expect("msg: Failed to index mempool");
+
+    let txid = tx.compute_txid();
+    match pool.check_replacement(&tx) {
+        Ok(replacement) => {
+            for index in &replacement.replaced {
+                let replaced = &valid_txs[*index];
+                println!(
+                    "msg: Replaces {} ({} sats, {} vB{})",
+                    replaced.id,
+                    replaced.fee,
+                    replaced.weight.div_ceil(4),
+                    if replacement.conflicts.contains(index) {
+                        ""
+                    } else {
+                        ", descendant"
+                    }
+                );
+            }
+            println!(
+                "msg: Replacement {} accepted: pays {} sats for {} vB, replacing {} transactions ({} direct conflicts) paying {} sats",
+                txid,
+                replacement.fee,
+                replacement.vsize,
+                replacement.replaced.len(),
+                replacement.conflicts.len(),
+                replacement.replaced_fee
+            );
+        }
+        Err(rejection) => {
+            println!(
+                "msg: Replacement {} rejected: {} ({})",
+                txid,
+                rejection.reason(),
+                rejection
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
 // Create coinbase tx
 fn create_coinbase_tx(
     miner_address: Address,
-    witness_commitment: Option<Vec<u8>>,
+    height: u32,
+    miner_tag: &[u8],
+    extranonce: u64,
+    reward: Amount,
+    witness_commitment: Option<[u8; 32]>,
 ) -> Result<Transaction, String> {
     let fake_tx_id =
         Txid::from_str("0000000000000000000000000000000000000000000000000000000000000000")
             .expect("msg: Failed to create fake txid");
-    let input = TxIn {
+    let mut input = TxIn {
         previous_output: bitcoincore_rpc::bitcoin::OutPoint {
             txid: fake_tx_id,
             vout: 0xffffffff,
         },
-        script_sig: ScriptBuf::new(),
+        script_sig: coinbase_script_sig(height, extranonce, miner_tag)?,
         sequence: Sequence(0xffffffff),
         witness: Witness::new(),
     };
 
     let mut outputs = Vec::new();
 
-    let block_reward = Amount::from_int_btc(50);
-    let total_reward = block_reward + Amount::from_sat(1000);
-
     let output = TxOut {
-        value: total_reward,
+        value: reward,
         script_pubkey: miner_address.script_pubkey(),
     };
 
     outputs.push(output);
 
     if let Some(commitment) = witness_commitment {
-        let mut witness_script = vec![0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+        let mut witness_script = WITNESS_COMMITMENT_HEADER.to_vec();
         witness_script.extend_from_slice(&commitment);
 
         outputs.push(TxOut {
             value: Amount::ZERO,
             script_pubkey: ScriptBuf::from(witness_script),
         });
+
+        // The commitment is only valid if the coinbase witness carries the reserved value
+        input.witness.push(WITNESS_RESERVED_VALUE);
     }
 
     let tx = Transaction {
@@ -218,59 +1049,266 @@ fn create_coinbase_tx(
     Ok(tx)
 }
 
-fn calculate_merkle_root(txids: Vec<Txid>) -> TxMerkleNode {
-    if txids.is_empty() {
-        return TxMerkleNode::all_zeros();
+// Coinbase scriptSig: the BIP34 height push, a fixed-size extranonce push that
+// mine_block can roll, then the miner tag
+fn coinbase_script_sig(
+    height: u32,
+    extranonce: u64,
+    miner_tag: &[u8],
+) -> Result<ScriptBuf, String> {
+    let tag = <&PushBytes>::try_from(miner_tag).map_err(|e| format!("Invalid miner tag: {}", e))?;
+    let script_sig = Builder::new()
+        .push_int(height as i64)
+        .push_slice(extranonce.to_le_bytes())
+        .push_slice(tag)
+        .into_script();
+
+    if !(MIN_COINBASE_SCRIPT_SIZE..=MAX_COINBASE_SCRIPT_SIZE).contains(&script_sig.len()) {
+        return Err(format!(
+            "Coinbase scriptSig is {} bytes, must be {}-{}",
+            script_sig.len(),
+            MIN_COINBASE_SCRIPT_SIZE,
+            MAX_COINBASE_SCRIPT_SIZE
+        ));
     }
+    Ok(script_sig)
+}
+
+// Overwrite the extranonce push of a coinbase built by create_coinbase_tx
+fn set_extranonce(coinbase_tx: &mut Transaction, extranonce: u64) {
+    let script_sig = &mut coinbase_tx.input[0].script_sig;
+    let offset = extranonce_offset(script_sig);
+    let mut bytes = script_sig.to_bytes();
+    bytes[offset..offset + EXTRANONCE_SIZE].copy_from_slice(&extranonce.to_le_bytes());
+    *script_sig = ScriptBuf::from_bytes(bytes);
+}
 
-    let hashes: Vec<sha256d::Hash> = txids.iter().map(|txid| txid.to_raw_hash()).collect();
-    let merkle_root = calculate_root(hashes.into_iter()).unwrap();
+// Position of the extranonce bytes in a scriptSig built by coinbase_script_sig
+fn extranonce_offset(script_sig: &Script) -> usize {
+    // The height push is either OP_0/OP_N or a direct push of a few bytes,
+    // followed by the extranonce push opcode
+    let height_push = match script_sig.as_bytes()[0] {
+        len @ 0x01..=0x4b => 1 + len as usize,
+        _ => 1,
+    };
+    height_push + 1
+}
+
+// Block subsidy at `height`: 50 BTC halved every halving interval of `network`
+fn block_subsidy(network: Network, height: u32) -> Amount {
+    let halvings = height / network.subsidy_halving_interval();
+    if halvings >= 64 {
+        return Amount::ZERO;
+    }
+    Amount::from_sat(Amount::from_int_btc(50).to_sat() >> halvings)
+}
+
+// Ancestor package score: the combined fee and weight of a transaction and all
+// of its not-yet-selected in-mempool ancestors
+#[derive(Debug, PartialEq, Eq)]
+struct PackageScore {
+    fee: u64,
+    weight: u64,
+    index: usize,
+}
+
+impl Ord for PackageScore {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Compare fee rates by cross-multiplying to stay in integer arithmetic
+        let lhs = self.fee as u128 * other.weight as u128;
+        let rhs = other.fee as u128 * self.weight as u128;
+        lhs.cmp(&rhs).then_with(|| other.index.cmp(&self.index))
+    }
+}
 
-    TxMerkleNode::from_raw_hash(merkle_root)
+impl PartialOrd for PackageScore {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
 }
 
+// Select transactions by ancestor fee rate, in the style of Bitcoin Core's
+// `addPackageTxs`: each candidate is scored together with its unselected
+// in-mempool ancestors, the best package is added whole (parents first), and
+// the scores of the remaining descendants are updated to exclude it. A package
+// that would push the block past `max_weight` or `max_sigop_cost` is skipped,
+// as is every transaction not marked `eligible`. Returns indices into
+// `valid_txs` in block order.
 fn select_transactions(
-    valid_txs: Vec<ValidTransactions>,
+    valid_txs: &[ValidTransactions],
+    eligible: &[bool],
     max_weight: u32,
-) -> Vec<ValidTransactions> {
-    let mut selected = Vec::new();
-    let mut total_weight = 0u32;
-
-    let mut sorted_txs = valid_txs;
-    sorted_txs.sort_by(|a, b| {
-        let fee_per_weight_a = a.fee as f64 / a.weight as f64;
-        let fee_per_weight_b = b.fee as f64 / b.weight as f64;
-        fee_per_weight_b.partial_cmp(&fee_per_weight_a).unwrap()
-    });
+    max_sigop_cost: u64,
+) -> Vec<usize> {
+    let index_by_id: HashMap<&str, usize> = valid_txs
+        .iter()
+        .enumerate()
+        .map(|(index, tx)| (tx.id.as_str(), index))
+        .collect();
 
-    for tx in sorted_txs {
-        if total_weight + tx.weight <= max_weight {
-            total_weight += tx.weight;
-            selected.push(tx);
+    let mut parents: Vec<HashSet<usize>> = vec![HashSet::new(); valid_txs.len()];
+    let mut children: Vec<HashSet<usize>> = vec![HashSet::new(); valid_txs.len()];
+    for (index, tx) in valid_txs.iter().enumerate() {
+        for parent_id in &tx.parents {
+            if let Some(&parent) = index_by_id.get(parent_id.as_str()) {
+                parents[index].insert(parent);
+                children[parent].insert(index);
+            }
         }
     }
 
-    selected
+    // Ancestor sets (including the transaction itself), resolved parents-first
+    let mut ancestors: Vec<Option<HashSet<usize>>> = vec![None; valid_txs.len()];
+    for index in 0..valid_txs.len() {
+        let mut stack = vec![index];
+        while let Some(&current) = stack.last() {
+            if ancestors[current].is_some() {
+                stack.pop();
+                continue;
+            }
+            let pending: Vec<usize> = parents[current]
+                .iter()
+                .copied()
+                .filter(|parent| ancestors[*parent].is_none())
+                .collect();
+            if pending.is_empty() {
+                let mut set = HashSet::from([current]);
+                for parent in &parents[current] {
+                    set.extend(ancestors[*parent].as_ref().unwrap());
+                }
+                ancestors[current] = Some(set);
+                stack.pop();
+            } else {
+                stack.extend(pending);
+            }
+        }
+    }
+    let mut ancestors: Vec<HashSet<usize>> = ancestors.into_iter().map(Option::unwrap).collect();
+
+    // Ancestor counts never change and give a valid topological order within a package
+    let ancestor_counts: Vec<usize> = ancestors.iter().map(HashSet::len).collect();
+
+    let mut ancestor_fee: Vec<u64> = Vec::with_capacity(valid_txs.len());
+    let mut ancestor_weight: Vec<u64> = Vec::with_capacity(valid_txs.len());
+    let mut ancestor_sigop_cost: Vec<u64> = Vec::with_capacity(valid_txs.len());
+    for set in &ancestors {
+        ancestor_fee.push(set.iter().map(|i| valid_txs[*i].fee).sum());
+        ancestor_weight.push(set.iter().map(|i| valid_txs[*i].weight as u64).sum());
+        ancestor_sigop_cost.push(set.iter().map(|i| valid_txs[*i].sigop_cost).sum());
+    }
+
+    // Ancestors of eligible transactions are eligible, so no package crosses into the rest
+    let mut queue: BinaryHeap<PackageScore> = (0..valid_txs.len())
+        .filter(|index| eligible[*index])
+        .map(|index| PackageScore {
+            fee: ancestor_fee[index],
+            weight: ancestor_weight[index],
+            index,
+        })
+        .collect();
+
+    let mut included = vec![false; valid_txs.len()];
+    let mut failed = vec![false; valid_txs.len()];
+    let mut order = Vec::new();
+    let mut total_weight = 0u64;
+    let mut total_sigop_cost = 0u64;
+
+    while let Some(score) = queue.pop() {
+        let index = score.index;
+        // Skip entries made stale by an earlier package inclusion
+        if included[index]
+            || failed[index]
+            || score.fee != ancestor_fee[index]
+            || score.weight != ancestor_weight[index]
+        {
+            continue;
+        }
+
+        if total_weight + ancestor_weight[index] > max_weight as u64
+            || total_sigop_cost + ancestor_sigop_cost[index] > max_sigop_cost
+        {
+            failed[index] = true;
+            continue;
+        }
+
+        let mut package: Vec<usize> = ancestors[index].iter().copied().collect();
+        package.sort_by_key(|tx| (ancestor_counts[*tx], *tx));
+
+        for tx in package {
+            included[tx] = true;
+            total_weight += valid_txs[tx].weight as u64;
+            total_sigop_cost += valid_txs[tx].sigop_cost;
+            order.push(tx);
+
+            // Remove the included transaction from every remaining descendant's package
+            let mut stack: Vec<usize> = children[tx].iter().copied().collect();
+            let mut visited = HashSet::new();
+            while let Some(descendant) = stack.pop() {
+                if !visited.insert(descendant) || included[descendant] {
+                    continue;
+                }
+                ancestors[descendant].remove(&tx);
+                ancestor_fee[descendant] -= valid_txs[tx].fee;
+                ancestor_weight[descendant] -= valid_txs[tx].weight as u64;
+                ancestor_sigop_cost[descendant] -= valid_txs[tx].sigop_cost;
+                queue.push(PackageScore {
+                    fee: ancestor_fee[descendant],
+                    weight: ancestor_weight[descendant],
+                    index: descendant,
+                });
+                stack.extend(children[descendant].iter().copied());
+            }
+        }
+    }
+
+    order
+}
+
+// Print how the refined selection compares with the greedy one it started from
+fn report_refinement(valid_txs: &[ValidTransactions], greedy: &[usize], refined: &[usize]) {
+    let totals = |selection: &[usize]| {
+        selection.iter().fold((0u64, 0u64), |(fee, weight), index| {
+            (
+                fee + valid_txs[*index].fee,
+                weight + valid_txs[*index].weight as u64,
+            )
+        })
+    };
+    let (greedy_fee, greedy_weight) = totals(greedy);
+    let (refined_fee, refined_weight) = totals(refined);
+    let greedy_set: HashSet<usize> = greedy.iter().copied().collect();
+    let refined_set: HashSet<usize> = refined.iter().copied().collect();
+    println!(
+        "msg: Greedy selection: {} transactions, {} sats in {} WU",
+        greedy.len(),
+        greedy_fee,
+        greedy_weight
+    );
+    println!(
+        "msg: Refined selection: {} transactions, {} sats in {} WU ({:+} sats, {} removed, {} added)",
+        refined.len(),
+        refined_fee,
+        refined_weight,
+        refined_fee as i64 - greedy_fee as i64,
+        greedy_set.difference(&refined_set).count(),
+        refined_set.difference(&greedy_set).count()
+    );
 }
 
 fn create_block_header(
     previous_hash: Hash,
     merkle_root: Hash,
     timestamp: u32,
+    bits: CompactTarget,
     nonce: u32,
 ) -> Result<Header, String> {
-    let target_bytes = hex::decode(DIFFICULTY_TARGET).expect("Invalid difficulty target hex");
-    let mut target_array = [0u8; 32];
-    target_array.copy_from_slice(&target_bytes);
-    let target = Target::from_be_bytes(target_array);
-
     let block_header = Header {
-        version: Version::TWO,
+        version: Version::from_consensus(BLOCK_VERSION),
         prev_blockhash: BlockHash::from_raw_hash(previous_hash),
         merkle_root: TxMerkleNode::from_raw_hash(merkle_root),
         time: timestamp,
         nonce,
-        bits: target.to_compact_lossy(),
+        bits,
     };
 
     Ok(block_header)
@@ -281,63 +1319,333 @@ fn hash_block_header(header: &Header) -> BlockHash {
     BlockHash::hash(&serialized)
 }
 
-// Compute witness transaction IDs (wtxids) for witness commitment
-// Compute the witness commitment
-fn calculate_witness_commitment(wtxids: &Vec<Wtxid>) -> Vec<u8> {
-    // This is a just simplified version
-    let mut commitment = Vec::new();
-    for wtxid in wtxids {
-        commitment.extend_from_slice(&wtxid.to_byte_array());
-    }
-    commitment
+// Compute the BIP141 witness commitment from the wtxids of the non-coinbase
+// transactions: SHA256d(witness merkle root || witness reserved value), where
+// the coinbase leaf of the witness merkle tree is all zeros
+fn calculate_witness_commitment(wtxids: &[Wtxid], witness_reserved_value: &[u8; 32]) -> [u8; 32] {
+    let leaves = std::iter::once(sha256d::Hash::all_zeros())
+        .chain(wtxids.iter().map(|wtxid| wtxid.to_raw_hash()));
+    let witness_root = calculate_root(leaves).unwrap();
+
+    let mut preimage = witness_root.to_byte_array().to_vec();
+    preimage.extend_from_slice(witness_reserved_value);
+    sha256d::Hash::hash(&preimage).to_byte_array()
 }
 
-fn mine_block(mut header: Header) -> Result<Header, String> {
+// Search for a header meeting its target on `threads` worker threads. Worker
+// `w` owns extranonces w, w + threads, w + 2 * threads, ... and for each sweeps
+// the full nonce range at every time from the header's up to MAX_NTIME_ROLL
+// seconds later, staying within the two-hour future limit. The search stops
+// early if `cancel` is set.
+fn mine_block(
+    header: Header,
+    block_transactions: &mut [Transaction],
+    threads: usize,
+    cancel: &AtomicBool,
+) -> Result<Header, String> {
     let target = Target::from_compact(header.bits);
-    for nonce in 0..u32::MAX {
-        header.nonce = nonce;
+    let txids: Vec<Txid> = block_transactions
+        .iter()
+        .map(|tx| tx.compute_txid())
+        .collect();
+
+    let first_time = header.time;
+    let last_time = first_time
+        .saturating_add(MAX_NTIME_ROLL)
+        .min(chain::unix_time().saturating_add(chain::MAX_FUTURE_BLOCK_TIME))
+        .max(first_time);
+
+    let found: Mutex<Option<(Header, u64)>> = Mutex::new(None);
+    let stop = AtomicBool::new(false);
+    let hashes = AtomicU64::new(0);
+    let start = Instant::now();
+
+    thread::scope(|scope| {
+        for worker in 0..threads {
+            let (coinbase_tx, txids) = (&block_transactions[0], &txids);
+            let (found, stop, hashes) = (&found, &stop, &hashes);
+            scope.spawn(move || {
+                let mut coinbase_tx = coinbase_tx.clone();
+                let mut txids = txids.clone();
+                let mut header = header;
+                let mut extranonce = worker as u64;
+                loop {
+                    if extranonce > 0 {
+                        set_extranonce(&mut coinbase_tx, extranonce);
+                        txids[0] = coinbase_tx.compute_txid();
+                        header.merkle_root = merkle::compute_merkle_root(&txids).0;
+                    }
+                    // Rolling the time is cheaper than a new extranonce, which
+                    // changes the merkle root
+                    for time in first_time..=last_time {
+                        header.time = time;
+                        if let Some(nonce) = search_nonces(&header, target, stop, cancel, hashes) {
+                            header.nonce = nonce;
+                            found.lock().unwrap().get_or_insert((header, extranonce));
+                            stop.store(true, AtomicOrdering::Relaxed);
+                            return;
+                        }
+                        if stop.load(AtomicOrdering::Relaxed)
+                            || cancel.load(AtomicOrdering::Relaxed)
+                        {
+                            return;
+                        }
+                    }
+                    match extranonce.checked_add(threads as u64) {
+                        Some(next) => extranonce = next,
+                        None => return,
+                    }
+                }
+            });
+        }
+
+        // Report progress until a worker finds a block or the search is cancelled
+        let mut last_report = Instant::now();
+        while !stop.load(AtomicOrdering::Relaxed) && !cancel.load(AtomicOrdering::Relaxed) {
+            thread::sleep(Duration::from_millis(50));
+            if last_report.elapsed() >= Duration::from_secs(5) {
+                last_report = Instant::now();
+                println!(
+                    "Mining... {} hashes, {}",
+                    hashes.load(AtomicOrdering::Relaxed),
+                    format_hashrate(hashes.load(AtomicOrdering::Relaxed), start.elapsed())
+                );
+            }
+        }
+    });
+
+    let elapsed = start.elapsed();
+    let total_hashes = hashes.load(AtomicOrdering::Relaxed);
+    println!(
+        "Hashed {} headers in {:.2}s on {} threads, {}",
+        total_hashes,
+        elapsed.as_secs_f64(),
+        threads,
+        format_hashrate(total_hashes, elapsed)
+    );
 
-        let hash = hash_block_header(&header);
+    let Some((header, extranonce)) = found.into_inner().unwrap() else {
+        return Err(if cancel.load(AtomicOrdering::Relaxed) {
+            "Mining cancelled".to_string()
+        } else {
+            "Failed to find valid nonce".to_string()
+        });
+    };
 
-        let hash_as_u256 = Target::from_le_bytes(hash.to_byte_array());
+    set_extranonce(&mut block_transactions[0], extranonce);
+    println!(
+        "Block mined! Nonce: {}, Extranonce: {}, Time: {}, Hash: {}",
+        header.nonce,
+        extranonce,
+        header.time,
+        hash_block_header(&header)
+    );
+    Ok(header)
+}
+
+// Sweep all nonces for one header, hashing only the last 16 bytes on top of
+// the SHA-256 midstate of the first 64. Returns None when the range is
+// exhausted or the search was stopped.
+fn search_nonces(
+    header: &Header,
+    target: Target,
+    stop: &AtomicBool,
+    cancel: &AtomicBool,
+    hashes: &AtomicU64,
+) -> Option<u32> {
+    let serialized = consensus::serialize(header);
+    let mut midstate = sha256::Hash::engine();
+    midstate.input(&serialized[..64]);
+    let mut tail = [0u8; 16];
+    tail[..12].copy_from_slice(&serialized[64..76]);
 
-        if hash_as_u256 <= target {
-            println!("Block mined! Nonce: {}, Hash: {}", nonce, hash);
-            return Ok(header);
+    for nonce in 0..=u32::MAX {
+        if nonce % NONCE_BATCH_SIZE == 0 && nonce > 0 {
+            hashes.fetch_add(NONCE_BATCH_SIZE as u64, AtomicOrdering::Relaxed);
+            if stop.load(AtomicOrdering::Relaxed) || cancel.load(AtomicOrdering::Relaxed) {
+                return None;
+            }
         }
 
-        if nonce % 100000 == 0 {
-            println!("Mining... nonce: {}", nonce);
+        tail[12..].copy_from_slice(&nonce.to_le_bytes());
+        let mut engine = midstate.clone();
+        engine.input(&tail);
+        let first = sha256::Hash::from_engine(engine);
+        let hash = sha256::Hash::hash(first.as_byte_array());
+
+        if Target::from_le_bytes(hash.to_byte_array()) <= target {
+            hashes.fetch_add(
+                (nonce % NONCE_BATCH_SIZE) as u64 + 1,
+                AtomicOrdering::Relaxed,
+            );
+            return Some(nonce);
         }
     }
 
-    Err("Failed to find valid nonce".to_string())
+    hashes.fetch_add(NONCE_BATCH_SIZE as u64, AtomicOrdering::Relaxed);
+    None
+}
+
+fn format_hashrate(hashes: u64, elapsed: Duration) -> String {
+    let rate = hashes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+    if rate >= 1e9 {
+        format!("{:.2} GH/s", rate / 1e9)
+    } else if rate >= 1e6 {
+        format!("{:.2} MH/s", rate / 1e6)
+    } else if rate >= 1e3 {
+        format!("{:.2} kH/s", rate / 1e3)
+    } else {
+        format!("{:.2} H/s", rate)
+    }
+}
+
+// Mine `template`, cancelling the search once `interrupted` is set or
+// `timeout` has passed
+fn mine_until_cancelled(
+    template: BlockTemplate,
+    threads: usize,
+    interrupted: &AtomicBool,
+    timeout: Option<Duration>,
+) -> Result<MinedBlock, String> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let cancel = AtomicBool::new(false);
+    let (done, finished) = mpsc::channel::<()>();
+    let result = thread::scope(|scope| {
+        let cancel = &cancel;
+        scope.spawn(move || {
+            // Wakes when mining ends and drops `done`
+            while let Err(RecvTimeoutError::Timeout) =
+                finished.recv_timeout(Duration::from_millis(100))
+            {
+                if interrupted.load(AtomicOrdering::Relaxed)
+                    || deadline.is_some_and(|deadline| Instant::now() >= deadline)
+                {
+                    cancel.store(true, AtomicOrdering::Relaxed);
+                    break;
+                }
+            }
+        });
+        let result = mine_transaction_block(template, threads, cancel);
+        drop(done);
+        result
+    });
+
+    result.map_err(|e| {
+        if interrupted.load(AtomicOrdering::Relaxed) {
+            "Interrupted".to_string()
+        } else if let Some(timeout) = timeout.filter(|_| cancel.load(AtomicOrdering::Relaxed)) {
+            format!("No block found within {} seconds", timeout.as_secs())
+        } else {
+            e
+        }
+    })
 }
 
 fn mine_transaction_block(
-    valid_transactions: Vec<ValidTransactions>,
-    miner_address: Address,
-    previous_hash: BlockHash,
-) -> Result<(Header, Vec<Transaction>), String> {
-    let temp_coinbase_tx = create_coinbase_tx(miner_address.clone(), None)?;
+    template: BlockTemplate,
+    threads: usize,
+    cancel: &AtomicBool,
+) -> Result<MinedBlock, String> {
+    let BlockTemplate {
+        mut block,
+        height,
+        subsidy,
+        fees,
+        sigop_cost,
+        ..
+    } = template;
 
-    let mut wtxids = vec![temp_coinbase_tx.compute_wtxid()];
-    let witness_commitment = calculate_witness_commitment(&wtxids);
+    block.header = mine_block(block.header, &mut block.txdata, threads, cancel)?;
 
-    let coinbase_tx = create_coinbase_tx(miner_address, Some(witness_commitment))
-        .map_err(|e| format!("Failed to create coinbase transaction: {}", e))?;
-    let mut block_transactions = vec![coinbase_tx.clone()];
-    let coinbase_weight = coinbase_tx.weight().to_wu() as u32;
-    let available_weight = MAX_BLOCK_WEIGHT - coinbase_weight;
+    println!(
+        "Successfully mined block with {} transactions",
+        block.txdata.len()
+    );
+    println!(
+        "Block subsidy at height {}: {} sats",
+        height,
+        subsidy.to_sat()
+    );
+    println!("Total fees collected: {} sats", fees.to_sat());
+    println!(
+        "Total sigop cost: {} of {}",
+        sigop_cost, MAX_BLOCK_SIGOPS_COST
+    );
 
-    let selected_txs = select_transactions(valid_transactions, available_weight);
+    Ok(MinedBlock {
+        block,
+        height,
+        fees,
+        sigop_cost,
+    })
+}
 
-    for tx_data in &selected_txs {
+// Select transactions for the block and build its coinbase, merkle root and
+// header, leaving the nonce at zero
+fn build_block_template(
+    valid_transactions: &[ValidTransactions],
+    params: &BlockParams,
+    strategy: SelectionStrategy,
+) -> Result<BlockTemplate, String> {
+    let BlockParams {
+        miner_address,
+        previous_hash,
+        height,
+        time,
+        median_time_past,
+        bits,
+        miner_tag,
+        network,
+    } = params;
+    let height = *height;
+    let subsidy = block_subsidy(*network, height);
+
+    // Size the coinbase with a placeholder commitment; the real one depends on the selection
+    let placeholder_coinbase_tx = create_coinbase_tx(
+        miner_address.clone(),
+        height,
+        miner_tag,
+        0,
+        subsidy,
+        Some([0u8; 32]),
+    )?;
+    let coinbase_weight = placeholder_coinbase_tx.weight().to_wu() as u32;
+    let available_weight = MAX_BLOCK_WEIGHT - BLOCK_OVERHEAD_WEIGHT - coinbase_weight;
+    let coinbase_sigop_cost = sigops::legacy_sigop_cost(&placeholder_coinbase_tx);
+    let available_sigop_cost = MAX_BLOCK_SIGOPS_COST - coinbase_sigop_cost;
+
+    let eligible = finality::final_transactions(valid_transactions, height, *median_time_past);
+    let mut selected = select_transactions(
+        valid_transactions,
+        &eligible,
+        available_weight,
+        available_sigop_cost,
+    );
+    if strategy == SelectionStrategy::Refine {
+        let refined = selection::refine_selection(
+            valid_transactions,
+            &eligible,
+            &selected,
+            available_weight as u64,
+            available_sigop_cost,
+        );
+        report_refinement(valid_transactions, &selected, &refined);
+        selected = refined;
+    }
+
+    let mut selected_transactions = Vec::new();
+    let mut included = Vec::new();
+    let mut fees = Amount::ZERO;
+    let mut sigop_cost = coinbase_sigop_cost;
+    for index in selected {
+        let tx_data = &valid_transactions[index];
         match hex::decode(&tx_data.hex) {
             Ok(tx_bytes) => match Transaction::consensus_decode(&mut tx_bytes.as_slice()) {
                 Ok(tx) => {
-                    wtxids.push(tx.clone().into());
-                    block_transactions.push(tx);
+                    fees += Amount::from_sat(tx_data.fee);
+                    sigop_cost += tx_data.sigop_cost;
+                    selected_transactions.push(tx);
+                    included.push(index);
                 }
                 Err(e) => println!("Failed to decode transaction {}: {}", tx_data.id, e),
             },
@@ -345,34 +1653,317 @@ fn mine_transaction_block(
         }
     }
 
+    // A commitment is only required (and only allowed alongside a coinbase witness)
+    // when at least one transaction carries witness data
+    let has_witness = selected_transactions
+        .iter()
+        .any(|tx| tx.input.iter().any(|input| !input.witness.is_empty()));
+    let witness_commitment = if has_witness {
+        let wtxids: Vec<Wtxid> = selected_transactions
+            .iter()
+            .map(|tx| tx.compute_wtxid())
+            .collect();
+        Some(calculate_witness_commitment(
+            &wtxids,
+            &WITNESS_RESERVED_VALUE,
+        ))
+    } else {
+        None
+    };
+
+    let coinbase_tx = create_coinbase_tx(
+        miner_address.clone(),
+        height,
+        miner_tag,
+        0,
+        subsidy + fees,
+        witness_commitment,
+    )
+    .map_err(|e| format!("Failed to create coinbase transaction: {}", e))?;
+    let mut block_transactions = vec![coinbase_tx];
+    block_transactions.extend(selected_transactions);
+
+    conflicts::check_no_double_spends(&block_transactions)
+        .map_err(|outpoint| format!("Block spends {} more than once", outpoint))?;
+
     let txids: Vec<Txid> = block_transactions
         .iter()
         .map(|tx| tx.compute_txid())
         .collect();
-    let merkle_root = calculate_merkle_root(txids);
-
-    let timestamp = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs() as u32;
+    let (merkle_root, mutated) = merkle::compute_merkle_root(&txids);
+    if mutated {
+        return Err("Block transactions form a mutated merkle tree".to_string());
+    }
 
     let header = create_block_header(
         previous_hash.to_raw_hash(),
         merkle_root.to_raw_hash(),
-        timestamp,
+        *time,
+        *bits,
         0,
     )?;
 
-    let mined_header = mine_block(header)?;
+    Ok(BlockTemplate {
+        block: Block {
+            header,
+            txdata: block_transactions,
+        },
+        height,
+        subsidy,
+        fees,
+        sigop_cost,
+        selected: included,
+        witness_commitment,
+    })
+}
 
-    println!(
-        "Successfully mined block with {} transactions",
-        block_transactions.len()
-    );
-    println!(
-        "Total fees collected: {} sats",
-        selected_txs.iter().map(|tx| tx.fee).sum::<u64>()
-    );
+#[cfg(test)]
+mod tests {
+    use serde_json::{Value, json};
+
+    use super::*;
+
+    // A mempool entry spending output 0 of each of `parents`
+    fn mempool_transaction(txid: &str, parents: &[&str], status: Value) -> MempoolTransaction {
+        let vin: Vec<Value> = parents
+            .iter()
+            .map(|parent| json!({ "txid": parent, "vout": 0, "sequence": 10 }))
+            .collect();
+        serde_json::from_value(json!({
+            "txid": txid,
+            "version": 2,
+            "locktime": 0,
+            "vin": vin,
+            "vout": [],
+            "size": 0,
+            "weight": 0,
+            "fee": 0,
+            "status": status,
+        }))
+        .unwrap()
+    }
+
+    fn confirmed_at(height: u32, time: u32) -> Value {
+        json!({ "confirmed": true, "block_height": height, "block_time": time })
+    }
 
-    Ok((mined_header, block_transactions))
+    #[test]
+    fn height_follows_the_mempool_data() {
+        assert_eq!(next_height(&[]), 1);
+        let unconfirmed = json!({ "confirmed": false });
+        let mut spender = mempool_transaction("spender", &["parent"], unconfirmed.clone());
+        assert_eq!(next_height(std::slice::from_ref(&spender)), 1);
+
+        spender.vin[0].prevout = serde_json::from_value(json!({
+            "scriptpubkey": "",
+            "scriptpubkey_asm": "",
+            "scriptpubkey_type": "",
+            "value": 0,
+            "confirmation": { "height": 834_200, "median_time_past": 0 },
+        }))
+        .unwrap();
+        let txs = [
+            mempool_transaction("parent", &[], confirmed_at(834_000, 0)),
+            spender,
+            mempool_transaction("other", &[], unconfirmed),
+        ];
+        assert_eq!(next_height(&txs), 834_201);
+    }
+
+    #[test]
+    fn prevout_confirmations_fall_back_to_mature() {
+        let unconfirmed = json!({ "confirmed": false });
+        let mut spender = mempool_transaction(
+            "spender",
+            &["parent", "unknown", "noted"],
+            unconfirmed.clone(),
+        );
+        spender.vin[2].prevout = serde_json::from_value(json!({
+            "scriptpubkey": "",
+            "scriptpubkey_asm": "",
+            "scriptpubkey_type": "",
+            "value": 0,
+            "confirmation": { "height": 830_000, "median_time_past": 1_700_000_000 },
+        }))
+        .unwrap();
+        let txs = [
+            mempool_transaction("parent", &[], confirmed_at(834_000, 1_710_000_000)),
+            spender,
+            mempool_transaction(
+                "confirmed",
+                &["unknown"],
+                confirmed_at(834_100, 1_710_060_000),
+            ),
+        ];
+
+        let confirmations = prevout_confirmations(&txs);
+        assert_eq!(
+            confirmations[1],
+            vec![
+                Some(finality::Confirmation {
+                    height: 834_000,
+                    median_time_past: 1_710_000_000
+                }),
+                Some(finality::MATURE),
+                Some(finality::Confirmation {
+                    height: 830_000,
+                    median_time_past: 1_700_000_000
+                }),
+            ]
+        );
+        // An unknown prevout confirmed no later than its confirmed spender
+        assert_eq!(
+            confirmations[2],
+            vec![Some(finality::Confirmation {
+                height: 834_100,
+                median_time_past: 1_710_060_000
+            })]
+        );
+
+        // A ten-block relative lock on the unknown prevout is met even at height 1
+        let tx = ValidTransactions {
+            id: "spender".to_string(),
+            hex: String::new(),
+            weight: 0,
+            fee: 0,
+            parents: vec!["unknown".to_string()],
+            sigop_cost: 0,
+            version: 2,
+            lock_time: 0,
+            sequences: vec![10],
+            prevout_confirmations: vec![confirmations[1][1]],
+        };
+        assert_eq!(finality::final_transactions(&[tx], 1, 0), vec![true]);
+    }
+
+    #[test]
+    fn found_nonce_meets_the_target() {
+        let header = Header {
+            version: Version::from_consensus(4),
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: 1_700_000_000,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        };
+        // One in 4096 hashes meets it
+        let mut target = [0xff; 32];
+        target[0] = 0x00;
+        target[1] = 0x0f;
+        let target = Target::from_be_bytes(target);
+        let (stop, cancel, hashes) = (
+            AtomicBool::new(false),
+            AtomicBool::new(false),
+            AtomicU64::new(0),
+        );
+
+        let nonce = search_nonces(&header, target, &stop, &cancel, &hashes).unwrap();
+        let mined = Header { nonce, ..header };
+        // The midstate hash is the header's own hash and the first to meet the target
+        assert!(target.is_met_by(mined.block_hash()));
+        assert!((0..nonce).all(|nonce| !target.is_met_by(Header { nonce, ..header }.block_hash())));
+        assert_eq!(hashes.load(AtomicOrdering::Relaxed), nonce as u64 + 1);
+
+        // Stopping ends the search at the next batch
+        stop.store(true, AtomicOrdering::Relaxed);
+        hashes.store(0, AtomicOrdering::Relaxed);
+        assert_eq!(
+            search_nonces(&header, Target::ZERO, &stop, &cancel, &hashes),
+            None
+        );
+        assert_eq!(
+            hashes.load(AtomicOrdering::Relaxed),
+            NONCE_BATCH_SIZE as u64
+        );
+    }
+
+    #[test]
+    fn subsidy_halves_at_each_interval() {
+        let btc = Amount::from_int_btc;
+        let cases = [
+            (Network::Mainnet, 0, btc(50)),
+            (Network::Mainnet, 209_999, btc(50)),
+            (Network::Mainnet, 210_000, btc(25)),
+            (Network::Mainnet, 840_000, Amount::from_sat(312_500_000)),
+            (Network::Testnet, 210_000, btc(25)),
+            (Network::Regtest, 149, btc(50)),
+            (Network::Regtest, 150, btc(25)),
+            (Network::Regtest, 300, Amount::from_sat(1_250_000_000)),
+            // After 33 halvings the shift leaves nothing, and from 64 it would wrap
+            (Network::Regtest, 150 * 33, Amount::ZERO),
+            (Network::Regtest, 150 * 64, Amount::ZERO),
+        ];
+        for (network, height, subsidy) in cases {
+            assert_eq!(
+                block_subsidy(network, height),
+                subsidy,
+                "{:?} {}",
+                network,
+                height
+            );
+        }
+    }
+
+    fn candidate(
+        id: &str,
+        fee: u64,
+        weight: u32,
+        sigop_cost: u64,
+        parents: &[&str],
+    ) -> ValidTransactions {
+        ValidTransactions {
+            id: id.to_string(),
+            hex: String::new(),
+            weight,
+            fee,
+            parents: parents.iter().map(|parent| parent.to_string()).collect(),
+            sigop_cost,
+            version: 2,
+            lock_time: 0,
+            sequences: vec![],
+            prevout_confirmations: vec![],
+        }
+    }
+
+    #[test]
+    fn high_fee_child_pulls_in_its_parent() {
+        // The child comes first in the list but has to follow its parent
+        let txs = [
+            candidate("child", 10_000, 1_000, 0, &["parent"]),
+            candidate("other", 3_000, 1_000, 0, &[]),
+            candidate("parent", 100, 1_000, 0, &[]),
+        ];
+        let eligible = [true; 3];
+
+        // The package pays 5.05 sat/WU, beating the other transaction's 3
+        assert_eq!(
+            select_transactions(&txs, &eligible, 2_000, MAX_BLOCK_SIGOPS_COST),
+            vec![2, 0]
+        );
+        assert_eq!(
+            select_transactions(&txs, &eligible, 3_000, MAX_BLOCK_SIGOPS_COST),
+            vec![2, 0, 1]
+        );
+        // Alone the parent is the worst choice
+        assert_eq!(
+            select_transactions(&txs[1..], &eligible[1..], 1_000, MAX_BLOCK_SIGOPS_COST),
+            vec![0]
+        );
+    }
+
+    #[test]
+    fn selection_stays_within_the_limits() {
+        let txs = [
+            candidate("sigops", 5_000, 1_000, 400, &[]),
+            candidate("more sigops", 4_000, 1_000, 100, &[]),
+            candidate("heavy", 3_000, 3_000, 0, &[]),
+            candidate("light", 1_000, 1_000, 0, &[]),
+            candidate("held back", 50_000, 1_000, 0, &[]),
+            candidate("held back child", 50_000, 1_000, 0, &["held back"]),
+        ];
+        let eligible = [true, true, true, true, false, false];
+
+        // The second sigop-heavy transaction would pass 450 and the heavy one 3000 WU
+        assert_eq!(select_transactions(&txs, &eligible, 3_000, 450), vec![0, 3]);
+    }
 }
@@ -0,0 +1,820 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::{Instant, UNIX_EPOCH},
+};
+
+use bitcoincore_rpc::{
+    Auth, Client, RpcApi,
+    bitcoin::{
+        Address, BlockHash, Network, OutPoint, Script, Transaction, TxOut, Txid, consensus,
+        hashes::{Hash, HashEngine, sha256},
+    },
+};
+
+use crate::{MempoolTransaction, Prevout, Status, Vin, Vout, finality::Confirmation};
+
+// Identifies the index format; bump the version when the layout changes
+const INDEX_MAGIC: &[u8; 4] = b"MPIX";
+const INDEX_VERSION: u32 = 2;
+
+// Smallest encoding of a transaction: an empty txid, the version, locktime,
+// input and output counts, size, weight and fee, and the absent status and hex
+const MIN_INDEX_TRANSACTION_SIZE: u64 = 4 + 4 + 4 + 4 + 4 + 4 + 4 + 8 + 1 + 1;
+
+// Transactions parsed between progress reports
+const PROGRESS_INTERVAL: usize = 1000;
+
+// Where the transactions to mine come from
+#[derive(Debug, Clone)]
+pub enum MempoolSource {
+    // An esplora-style directory: mempool.json listing the txids, plus one
+    // <txid>.json per transaction. The parsed transactions are cached at
+    // `index`, or under the system temp directory when it is None; the
+    // directory itself is never written to.
+    Directory {
+        dir: PathBuf,
+        index: Option<PathBuf>,
+    },
+    // The mempool of a node over JSON-RPC; confirmed parents are fetched with
+    // getrawtransaction, so the node needs txindex=1
+    Rpc {
+        url: String,
+        auth: Auth,
+    },
+}
+
+impl MempoolSource {
+    pub fn load(&self, threads: usize) -> Result<Vec<MempoolTransaction>, String> {
+        match self {
+            MempoolSource::Directory { dir, index } => {
+                let index = index.clone().unwrap_or_else(|| default_index_path(dir));
+                load_directory(dir, &index, threads)
+            }
+            MempoolSource::Rpc { url, auth } => load_rpc(url, auth),
+        }
+    }
+
+    // A client for the node behind an Rpc source
+    pub fn node(&self) -> Result<Option<Client>, String> {
+        match self {
+            MempoolSource::Directory { .. } => Ok(None),
+            MempoolSource::Rpc { url, auth } => Client::new(url, auth.clone())
+                .map(Some)
+                .map_err(|e| format!("Failed to connect to {}: {}", url, e)),
+        }
+    }
+}
+
+// Index location for a directory without --index: one file per directory in
+// the system temp directory, named after a hash of its absolute path
+fn default_index_path(dir: &Path) -> PathBuf {
+    let dir = std::path::absolute(dir).unwrap_or_else(|_| dir.to_path_buf());
+    let hash = sha256::Hash::hash(dir.as_os_str().as_encoded_bytes());
+    std::env::temp_dir().join(format!("mempool-{}.index", &hash.to_string()[..16]))
+}
+
+// Load every transaction listed in `dir/mempool.json`, from the index at
+// `index_path` when it matches the directory contents, otherwise by parsing
+// the JSON files on `threads` workers and rebuilding the index
+fn load_directory(
+    dir: &Path,
+    index_path: &Path,
+    threads: usize,
+) -> Result<Vec<MempoolTransaction>, String> {
+    let mempool_json_path = dir.join("mempool.json");
+    let listing = std::fs::read(&mempool_json_path)
+        .map_err(|e| format!("Failed to read {}: {}", mempool_json_path.display(), e))?;
+    let txids: Vec<String> = serde_json::from_slice(&listing)
+        .map_err(|e| format!("Failed to parse {}: {}", mempool_json_path.display(), e))?;
+
+    let paths: Vec<PathBuf> = txids
+        .iter()
+        .map(|txid| dir.join(format!("{}.json", txid)))
+        .collect();
+    let fingerprint = fingerprint(&listing, &paths);
+
+    let start = Instant::now();
+    match read_index(index_path, &fingerprint) {
+        Ok(Some(txs)) => {
+            println!(
+                "msg: Loaded {} transactions from index {} in {:.2}s",
+                txs.len(),
+                index_path.display(),
+                start.elapsed().as_secs_f64()
+            );
+            return Ok(txs);
+        }
+        Ok(None) => {}
+        Err(e) => println!(
+            "msg: Ignoring unreadable index {}: {}",
+            index_path.display(),
+            e
+        ),
+    }
+
+    let txs = parse_files(&paths, threads);
+
+    // The index is only a cache; the next run parses the files again
+    if let Err(e) = write_index(index_path, &fingerprint, &txs) {
+        println!("msg: Not caching index at {}: {}", index_path.display(), e);
+    }
+    Ok(txs)
+}
+
+// Parse the JSON files in parallel, skipping missing or unparsable ones.
+// Transactions keep the order of mempool.json.
+fn parse_files(paths: &[PathBuf], threads: usize) -> Vec<MempoolTransaction> {
+    let start = Instant::now();
+    let parsed = AtomicUsize::new(0);
+    let chunk_size = paths.len().div_ceil(threads.max(1)).max(1);
+
+    let chunks: Vec<Vec<Option<MempoolTransaction>>> = thread::scope(|scope| {
+        let workers: Vec<_> = paths
+            .chunks(chunk_size)
+            .map(|chunk| {
+                let parsed = &parsed;
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|path| {
+                            let tx = parse_file(path);
+                            let done = parsed.fetch_add(1, Ordering::Relaxed) + 1;
+                            if done.is_multiple_of(PROGRESS_INTERVAL) {
+                                println!("msg: Parsed {}/{} transaction files", done, paths.len());
+                            }
+                            tx
+                        })
+                        .collect()
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("msg: Mempool parser panicked"))
+            .collect()
+    });
+
+    let txs: Vec<MempoolTransaction> = chunks.into_iter().flatten().flatten().collect();
+    println!(
+        "msg: Parsed {} of {} transaction files in {:.2}s",
+        txs.len(),
+        paths.len(),
+        start.elapsed().as_secs_f64()
+    );
+    txs
+}
+
+fn parse_file(path: &Path) -> Option<MempoolTransaction> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            println!(
+                "msg: Failed to open transaction file {}: {}",
+                path.display(),
+                e
+            );
+            return None;
+        }
+    };
+    match serde_json::from_reader(BufReader::new(file)) {
+        Ok(tx_data) => Some(tx_data),
+        Err(e) => {
+            println!(
+                "msg: Failed to parse transaction file {}: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+// Hash of mempool.json and the size and modification time of every file it
+// lists; any change to the set invalidates the index
+fn fingerprint(listing: &[u8], paths: &[PathBuf]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(listing);
+    for path in paths {
+        let (len, modified) = std::fs::metadata(path)
+            .map(|metadata| {
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |duration| duration.as_nanos() as u64);
+                (metadata.len(), modified)
+            })
+            .unwrap_or((u64::MAX, 0));
+        engine.input(&len.to_le_bytes());
+        engine.input(&modified.to_le_bytes());
+    }
+    sha256::Hash::from_engine(engine).to_byte_array()
+}
+
+// Read the index, returning None if it is missing or was built from a
+// different set of files
+fn read_index(
+    path: &Path,
+    fingerprint: &[u8; 32],
+) -> Result<Option<Vec<MempoolTransaction>>, String> {
+    let Ok(file) = File::open(path) else {
+        return Ok(None);
+    };
+    let len = file.metadata().map_err(|e| e.to_string())?.len();
+    let mut reader = IndexReader {
+        reader: BufReader::new(file),
+        remaining: len,
+    };
+
+    let mut magic = [0u8; 4];
+    reader.bytes_into(&mut magic)?;
+    if &magic != INDEX_MAGIC || reader.u32()? != INDEX_VERSION {
+        return Ok(None);
+    }
+    let mut stored = [0u8; 32];
+    reader.bytes_into(&mut stored)?;
+    if &stored != fingerprint {
+        return Ok(None);
+    }
+
+    let count = reader.u64()?;
+    let count = reader.count(count, MIN_INDEX_TRANSACTION_SIZE)?;
+    let mut txs = Vec::with_capacity(count);
+    for _ in 0..count {
+        txs.push(reader.transaction()?);
+    }
+    Ok(Some(txs))
+}
+
+// Write the index next to `path` and move it into place, so a failed or
+// concurrent write never leaves a partial index behind
+fn write_index(
+    path: &Path,
+    fingerprint: &[u8; 32],
+    txs: &[MempoolTransaction],
+) -> Result<(), String> {
+    let partial = path.with_extension(format!("partial-{}", std::process::id()));
+    let result = write_index_file(&partial, fingerprint, txs)
+        .and_then(|()| std::fs::rename(&partial, path).map_err(|e| e.to_string()));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&partial);
+    }
+    result
+}
+
+fn write_index_file(
+    path: &Path,
+    fingerprint: &[u8; 32],
+    txs: &[MempoolTransaction],
+) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| e.to_string())?;
+    let mut writer = IndexWriter(BufWriter::new(file));
+    writer.bytes(INDEX_MAGIC)?;
+    writer.u32(INDEX_VERSION)?;
+    writer.bytes(fingerprint)?;
+    writer.u64(txs.len() as u64)?;
+    for tx in txs {
+        writer.transaction(tx)?;
+    }
+    writer.0.flush().map_err(|e| e.to_string())
+}
+
+// Little-endian integers and length-prefixed strings, field by field in
+// declaration order
+struct IndexWriter<W: Write>(W);
+
+impl<W: Write> IndexWriter<W> {
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), String> {
+        self.0.write_all(bytes).map_err(|e| e.to_string())
+    }
+
+    fn u32(&mut self, value: u32) -> Result<(), String> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn u64(&mut self, value: u64) -> Result<(), String> {
+        self.bytes(&value.to_le_bytes())
+    }
+
+    fn bool(&mut self, value: bool) -> Result<(), String> {
+        self.bytes(&[value as u8])
+    }
+
+    fn string(&mut self, value: &str) -> Result<(), String> {
+        self.u32(value.len() as u32)?;
+        self.bytes(value.as_bytes())
+    }
+
+    fn option<T>(
+        &mut self,
+        value: Option<&T>,
+        write: impl FnOnce(&mut Self, &T) -> Result<(), String>,
+    ) -> Result<(), String> {
+        self.bool(value.is_some())?;
+        match value {
+            Some(value) => write(self, value),
+            None => Ok(()),
+        }
+    }
+
+    fn transaction(&mut self, tx: &MempoolTransaction) -> Result<(), String> {
+        self.string(&tx.txid)?;
+        self.u32(tx.version)?;
+        self.u32(tx.locktime)?;
+        self.u32(tx.vin.len() as u32)?;
+        for vin in &tx.vin {
+            self.string(&vin.txid)?;
+            self.u32(vin.vout)?;
+            self.option(vin.prevout.as_ref(), |writer, prevout| {
+                writer.string(&prevout.scriptpubkey)?;
+                writer.string(&prevout.scriptpubkey_asm)?;
+                writer.string(&prevout.scriptpubkey_type)?;
+                writer.option(prevout.scriptpubkey_address.as_ref(), |w, a| w.string(a))?;
+                writer.u64(prevout.value)?;
+                writer.option(prevout.confirmation.as_ref(), |w, confirmation| {
+                    w.u32(confirmation.height)?;
+                    w.u32(confirmation.median_time_past)
+                })
+            })?;
+            self.string(&vin.scriptsig)?;
+            self.string(&vin.scriptsig_asm)?;
+            self.u32(vin.witness.len() as u32)?;
+            for item in &vin.witness {
+                self.string(item)?;
+            }
+            self.bool(vin.is_coinbase)?;
+            self.u64(vin.sequence)?;
+        }
+        self.u32(tx.vout.len() as u32)?;
+        for vout in &tx.vout {
+            self.string(&vout.scriptpubkey)?;
+            self.string(&vout.scriptpubkey_asm)?;
+            self.string(&vout.scriptpubkey_type)?;
+            self.option(vout.scriptpubkey_address.as_ref(), |w, a| w.string(a))?;
+            self.u64(vout.value)?;
+        }
+        self.u32(tx.size)?;
+        self.u32(tx.weight)?;
+        self.u64(tx.fee)?;
+        self.option(tx.status.as_ref(), |writer, status| {
+            writer.bool(status.confirmed)?;
+            writer.option(status.block_height.as_ref(), |w, h| w.u32(*h))?;
+            writer.option(status.block_hash.as_ref(), |w, h| w.string(h))?;
+            writer.option(status.block_time.as_ref(), |w, t| w.u64(*t))
+        })?;
+        self.option(tx.hex.as_ref(), |w, hex| w.string(hex))
+    }
+}
+
+// Reads what IndexWriter wrote. Counts and lengths are checked against the
+// bytes left before anything is allocated for them, so a corrupt index fails
+// to read instead of exhausting memory.
+struct IndexReader<R: Read> {
+    reader: R,
+    remaining: u64,
+}
+
+impl<R: Read> IndexReader<R> {
+    fn bytes_into(&mut self, buf: &mut [u8]) -> Result<(), String> {
+        self.reader.read_exact(buf).map_err(|e| e.to_string())?;
+        self.remaining = self.remaining.saturating_sub(buf.len() as u64);
+        Ok(())
+    }
+
+    // Check that `count` items of at least `min_size` bytes each can still follow
+    fn count(&self, count: u64, min_size: u64) -> Result<usize, String> {
+        if count.saturating_mul(min_size) > self.remaining {
+            return Err(format!(
+                "{} items do not fit in the {} bytes left",
+                count, self.remaining
+            ));
+        }
+        Ok(count as usize)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut buf = [0u8; 4];
+        self.bytes_into(&mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        let mut buf = [0u8; 8];
+        self.bytes_into(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn bool(&mut self) -> Result<bool, String> {
+        let mut buf = [0u8; 1];
+        self.bytes_into(&mut buf)?;
+        Ok(buf[0] != 0)
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        let len = self.u32()?;
+        let mut buf = vec![0u8; self.count(len as u64, 1)?];
+        self.bytes_into(&mut buf)?;
+        String::from_utf8(buf).map_err(|e| e.to_string())
+    }
+
+    fn option<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        if self.bool()? {
+            read(self).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn transaction(&mut self) -> Result<MempoolTransaction, String> {
+        let txid = self.string()?;
+        let version = self.u32()?;
+        let locktime = self.u32()?;
+        let mut vin = Vec::new();
+        for _ in 0..self.u32()? {
+            vin.push(Vin {
+                txid: self.string()?,
+                vout: self.u32()?,
+                prevout: self.option(|reader| {
+                    Ok(Prevout {
+                        scriptpubkey: reader.string()?,
+                        scriptpubkey_asm: reader.string()?,
+                        scriptpubkey_type: reader.string()?,
+                        scriptpubkey_address: reader.option(|r| r.string())?,
+                        value: reader.u64()?,
+                        confirmation: reader.option(|r| {
+                            Ok(Confirmation {
+                                height: r.u32()?,
+                                median_time_past: r.u32()?,
+                            })
+                        })?,
+                    })
+                })?,
+                scriptsig: self.string()?,
+                scriptsig_asm: self.string()?,
+                witness: (0..self.u32()?)
+                    .map(|_| self.string())
+                    .collect::<Result<_, _>>()?,
+                is_coinbase: self.bool()?,
+                sequence: self.u64()?,
+            });
+        }
+        let mut vout = Vec::new();
+        for _ in 0..self.u32()? {
+            vout.push(Vout {
+                scriptpubkey: self.string()?,
+                scriptpubkey_asm: self.string()?,
+                scriptpubkey_type: self.string()?,
+                scriptpubkey_address: self.option(|r| r.string())?,
+                value: self.u64()?,
+            });
+        }
+        Ok(MempoolTransaction {
+            txid,
+            version,
+            locktime,
+            vin,
+            vout,
+            size: self.u32()?,
+            weight: self.u32()?,
+            fee: self.u64()?,
+            status: self.option(|reader| {
+                Ok(Status {
+                    confirmed: reader.bool()?,
+                    block_height: reader.option(|r| r.u32())?,
+                    block_hash: reader.option(|r| r.string())?,
+                    block_time: reader.option(|r| r.u64())?,
+                })
+            })?,
+            hex: self.option(|r| r.string())?,
+        })
+    }
+}
+
+// Fetch the node's mempool with `getrawmempool true` and each transaction and
+// its spent outputs with getrawtransaction, in the order the node accepted
+// them, noting where each confirmed output confirmed. Transactions that leave
+// the mempool while loading are skipped.
+fn load_rpc(url: &str, auth: &Auth) -> Result<Vec<MempoolTransaction>, String> {
+    let start = Instant::now();
+    let client = Client::new(url, auth.clone())
+        .map_err(|e| format!("Failed to connect to {}: {}", url, e))?;
+    let network = client
+        .get_blockchain_info()
+        .map_err(|e| format!("getblockchaininfo failed: {}", e))?
+        .chain;
+    let mut entries: Vec<_> = client
+        .get_raw_mempool_verbose()
+        .map_err(|e| format!("getrawmempool failed: {}", e))?
+        .into_iter()
+        .collect();
+    entries.sort_by_key(|(txid, entry)| (entry.time, *txid));
+
+    let mut fetched: HashMap<Txid, Fetched> = HashMap::new();
+    let mut confirmations: HashMap<BlockHash, Confirmation> = HashMap::new();
+    let mut txs = Vec::with_capacity(entries.len());
+    for (txid, entry) in &entries {
+        let tx = match fetch_transaction(&client, &mut fetched, txid) {
+            Ok((tx, _)) => tx,
+            Err(e) => {
+                println!("msg: Failed to fetch transaction {}: {}", txid, e);
+                continue;
+            }
+        };
+
+        let mut vin = Vec::with_capacity(tx.input.len());
+        for input in &tx.input {
+            let prevout = match spent_output(
+                &client,
+                &mut fetched,
+                &mut confirmations,
+                input.previous_output,
+            ) {
+                Ok((txout, confirmation)) => Some(prevout(&txout, confirmation, network)),
+                Err(e) => {
+                    println!(
+                        "msg: Failed to fetch prevout {} of {}: {}",
+                        input.previous_output, txid, e
+                    );
+                    None
+                }
+            };
+            vin.push(Vin {
+                txid: input.previous_output.txid.to_string(),
+                vout: input.previous_output.vout,
+                prevout,
+                scriptsig: input.script_sig.to_hex_string(),
+                scriptsig_asm: input.script_sig.to_asm_string(),
+                witness: input.witness.iter().map(hex::encode).collect(),
+                is_coinbase: false,
+                sequence: input.sequence.0 as u64,
+            });
+        }
+
+        txs.push(MempoolTransaction {
+            txid: txid.to_string(),
+            version: tx.version.0 as u32,
+            locktime: tx.lock_time.to_consensus_u32(),
+            vin,
+            vout: tx.output.iter().map(|txout| vout(txout, network)).collect(),
+            size: tx.total_size() as u32,
+            weight: entry.weight.unwrap_or_else(|| tx.weight().to_wu()) as u32,
+            fee: entry.fees.base.to_sat(),
+            status: Some(Status {
+                confirmed: false,
+                block_height: None,
+                block_hash: None,
+                block_time: None,
+            }),
+            hex: Some(consensus::encode::serialize_hex(&tx)),
+        });
+    }
+
+    println!(
+        "msg: Fetched {} of {} mempool transactions from {} in {:.2}s",
+        txs.len(),
+        entries.len(),
+        url,
+        start.elapsed().as_secs_f64()
+    );
+    Ok(txs)
+}
+
+// A transaction from getrawtransaction and the block it confirmed in, if any
+type Fetched = (Transaction, Option<BlockHash>);
+
+// Verbose getrawtransaction, remembering results since parents are shared
+fn fetch_transaction(
+    client: &Client,
+    fetched: &mut HashMap<Txid, Fetched>,
+    txid: &Txid,
+) -> Result<Fetched, String> {
+    if let Some(result) = fetched.get(txid) {
+        return Ok(result.clone());
+    }
+    let info = client
+        .get_raw_transaction_info(txid, None)
+        .map_err(|e| e.to_string())?;
+    let tx = info.transaction().map_err(|e| e.to_string())?;
+    fetched.insert(*txid, (tx.clone(), info.blockhash));
+    Ok((tx, info.blockhash))
+}
+
+// The output `outpoint` spends and, if its transaction is in a block, where
+fn spent_output(
+    client: &Client,
+    fetched: &mut HashMap<Txid, Fetched>,
+    confirmations: &mut HashMap<BlockHash, Confirmation>,
+    outpoint: OutPoint,
+) -> Result<(TxOut, Option<Confirmation>), String> {
+    let (parent, blockhash) = fetch_transaction(client, fetched, &outpoint.txid)?;
+    let txout = parent
+        .output
+        .get(outpoint.vout as usize)
+        .cloned()
+        .ok_or_else(|| format!("{} has no output {}", outpoint.txid, outpoint.vout))?;
+    let confirmation = match blockhash {
+        Some(blockhash) => Some(block_confirmation(client, confirmations, blockhash)?),
+        None => None,
+    };
+    Ok((txout, confirmation))
+}
+
+// Height of a block and the median time past of the block before it, which
+// BIP68 measures time-based locks from
+fn block_confirmation(
+    client: &Client,
+    confirmations: &mut HashMap<BlockHash, Confirmation>,
+    blockhash: BlockHash,
+) -> Result<Confirmation, String> {
+    if let Some(confirmation) = confirmations.get(&blockhash) {
+        return Ok(*confirmation);
+    }
+    let header = client
+        .get_block_header_info(&blockhash)
+        .map_err(|e| format!("getblockheader {} failed: {}", blockhash, e))?;
+    // The genesis block has no parent and counts its own median time
+    let parent = match header.previous_block_hash {
+        Some(parent) => client
+            .get_block_header_info(&parent)
+            .map_err(|e| format!("getblockheader {} failed: {}", parent, e))?,
+        None => header.clone(),
+    };
+    let confirmation = Confirmation {
+        height: header.height as u32,
+        median_time_past: parent
+            .median_time
+            .ok_or_else(|| format!("getblockheader {} has no mediantime", parent.hash))?
+            as u32,
+    };
+    confirmations.insert(blockhash, confirmation);
+    Ok(confirmation)
+}
+
+fn prevout(txout: &TxOut, confirmation: Option<Confirmation>, network: Network) -> Prevout {
+    let script = &txout.script_pubkey;
+    Prevout {
+        scriptpubkey: script.to_hex_string(),
+        scriptpubkey_asm: script.to_asm_string(),
+        scriptpubkey_type: script_type(script).to_string(),
+        scriptpubkey_address: address(script, network),
+        value: txout.value.to_sat(),
+        confirmation,
+    }
+}
+
+fn vout(txout: &TxOut, network: Network) -> Vout {
+    let script = &txout.script_pubkey;
+    Vout {
+        scriptpubkey: script.to_hex_string(),
+        scriptpubkey_asm: script.to_asm_string(),
+        scriptpubkey_type: script_type(script).to_string(),
+        scriptpubkey_address: address(script, network),
+        value: txout.value.to_sat(),
+    }
+}
+
+// The names esplora uses for scriptpubkey_type
+fn script_type(script: &Script) -> &'static str {
+    if script.is_p2pkh() {
+        "p2pkh"
+    } else if script.is_p2sh() {
+        "p2sh"
+    } else if script.is_p2wpkh() {
+        "v0_p2wpkh"
+    } else if script.is_p2wsh() {
+        "v0_p2wsh"
+    } else if script.is_p2tr() {
+        "v1_p2tr"
+    } else if script.is_op_return() {
+        "op_return"
+    } else if script.is_p2pk() {
+        "p2pk"
+    } else if script.is_multisig() {
+        "multisig"
+    } else {
+        "unknown"
+    }
+}
+
+fn address(script: &Script, network: Network) -> Option<String> {
+    Address::from_script(script, network)
+        .ok()
+        .map(|address| address.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn transactions() -> Vec<MempoolTransaction> {
+        serde_json::from_value(json!([
+            {
+                "txid": "aa".repeat(32),
+                "version": 2,
+                "locktime": 834_000,
+                "vin": [{
+                    "txid": "bb".repeat(32),
+                    "vout": 1,
+                    "prevout": {
+                        "scriptpubkey": "0014".to_string() + &"cc".repeat(20),
+                        "scriptpubkey_asm": "OP_0 OP_PUSHBYTES_20",
+                        "scriptpubkey_type": "v0_p2wpkh",
+                        "scriptpubkey_address": "bc1q",
+                        "value": 100_000,
+                        "confirmation": { "height": 834_000, "median_time_past": 1_700_000_000 },
+                    },
+                    "scriptsig": "",
+                    "scriptsig_asm": "",
+                    "witness": ["30", "02"],
+                    "is_coinbase": false,
+                    "sequence": 0xffff_fffd_u32,
+                }],
+                "vout": [{
+                    "scriptpubkey": "6a",
+                    "scriptpubkey_asm": "OP_RETURN",
+                    "scriptpubkey_type": "op_return",
+                    "value": 0,
+                }],
+                "size": 110,
+                "weight": 440,
+                "fee": 1_000,
+                "status": {
+                    "confirmed": true,
+                    "block_height": 834_001,
+                    "block_hash": "dd".repeat(32),
+                    "block_time": 1_700_000_600,
+                },
+                "hex": "02000000",
+            },
+            {
+                "txid": "",
+                "version": 1,
+                "locktime": 0,
+                "vin": [],
+                "vout": [],
+                "size": 0,
+                "weight": 0,
+                "fee": 0,
+            },
+        ]))
+        .unwrap()
+    }
+
+    fn index_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("mempool-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn index_round_trips() {
+        let path = index_path("round-trip");
+        let txs = transactions();
+        write_index(&path, &[1; 32], &txs).unwrap();
+
+        assert_eq!(read_index(&path, &[1; 32]).unwrap(), Some(txs));
+        // An index built from other files is ignored
+        assert_eq!(read_index(&path, &[2; 32]).unwrap(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn smallest_transaction_fits_the_minimum() {
+        let mut writer = IndexWriter(Vec::new());
+        writer.transaction(&transactions()[1]).unwrap();
+        assert_eq!(writer.0.len() as u64, MIN_INDEX_TRANSACTION_SIZE);
+    }
+
+    #[test]
+    fn corrupt_lengths_fail_to_read() {
+        let path = index_path("corrupt");
+        write_index(&path, &[1; 32], &transactions()).unwrap();
+        let index = std::fs::read(&path).unwrap();
+
+        // The transaction count follows the magic, version and fingerprint,
+        // and the first txid's length follows the count
+        let count_offset = 4 + 4 + 32;
+        let txid_offset = count_offset + 8;
+        for (offset, value) in [
+            (count_offset, u64::MAX.to_le_bytes().to_vec()),
+            (count_offset, 3u64.to_le_bytes().to_vec()),
+            (txid_offset, u32::MAX.to_le_bytes().to_vec()),
+        ] {
+            let mut corrupt = index.clone();
+            corrupt[offset..offset + value.len()].copy_from_slice(&value);
+            std::fs::write(&path, corrupt).unwrap();
+            assert!(read_index(&path, &[1; 32]).is_err());
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
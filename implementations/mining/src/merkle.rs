@@ -0,0 +1,539 @@
+use std::collections::HashSet;
+
+use bitcoincore_rpc::bitcoin::{
+    Block, Target, TxMerkleNode, Txid,
+    block::Header,
+    consensus::{Decodable, Encodable, encode},
+    hashes::{Hash, HashEngine, sha256d},
+    io::{self, Read, Write},
+};
+
+use crate::MAX_BLOCK_WEIGHT;
+
+// Smallest possible transaction weight, bounding the transactions a block can hold
+const MIN_TRANSACTION_WEIGHT: u32 = 60 * 4;
+
+// The merkle root of `txids` as Bitcoin Core's ComputeMerkleRoot computes it,
+// and whether the tree is mutated: some level pairs a node with an identical
+// sibling. Odd levels pair their last node with itself, so appending copies of
+// the trailing transactions (CVE-2012-2459) leaves the root unchanged; such a
+// block shares its hash with the valid one and must be rejected without
+// marking that hash invalid.
+pub fn compute_merkle_root(txids: &[Txid]) -> (TxMerkleNode, bool) {
+    if txids.is_empty() {
+        return (TxMerkleNode::all_zeros(), false);
+    }
+    let mut level: Vec<sha256d::Hash> = txids.iter().map(|txid| txid.to_raw_hash()).collect();
+    let mut mutated = false;
+    while level.len() > 1 {
+        // Checked before padding, so the node paired with itself does not count
+        mutated |= level.chunks_exact(2).any(|pair| pair[0] == pair[1]);
+        level = level
+            .chunks(2)
+            .map(|pair| parent(pair[0], *pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+    }
+    (TxMerkleNode::from_raw_hash(level[0]), mutated)
+}
+
+// The sibling hashes on the path from one transaction to the merkle root,
+// bottom first
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub index: usize,
+    pub siblings: Vec<sha256d::Hash>,
+}
+
+impl MerkleProof {
+    // Recompute the root from the transaction the proof is for
+    pub fn root(&self, txid: Txid) -> TxMerkleNode {
+        let mut node = txid.to_raw_hash();
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            node = if (self.index >> level) & 1 == 0 {
+                parent(node, *sibling)
+            } else {
+                parent(*sibling, node)
+            };
+        }
+        TxMerkleNode::from_raw_hash(node)
+    }
+
+    pub fn verify(&self, txid: Txid, merkle_root: TxMerkleNode) -> bool {
+        self.root(txid) == merkle_root
+    }
+}
+
+// Inclusion proof for the transaction at `index` in a block with `txids`
+pub fn merkle_proof(txids: &[Txid], index: usize) -> Option<MerkleProof> {
+    if index >= txids.len() {
+        return None;
+    }
+    let mut level: Vec<sha256d::Hash> = txids.iter().map(|txid| txid.to_raw_hash()).collect();
+    let mut position = index;
+    let mut siblings = Vec::new();
+    while level.len() > 1 {
+        // A node without a right neighbour is paired with itself
+        let sibling = level.get(position ^ 1).unwrap_or(&level[position]);
+        siblings.push(*sibling);
+        level = level
+            .chunks(2)
+            .map(|pair| parent(pair[0], *pair.get(1).unwrap_or(&pair[0])))
+            .collect();
+        position /= 2;
+    }
+    Some(MerkleProof { index, siblings })
+}
+
+// A BIP37 partial merkle tree: a depth-first walk of the tree that descends
+// only into subtrees holding matched transactions, with one flag bit per node
+// visited and the hashes of the subtrees it did not descend into
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PartialMerkleTree {
+    pub total_transactions: u32,
+    pub hashes: Vec<sha256d::Hash>,
+    pub flags: Vec<bool>,
+}
+
+impl PartialMerkleTree {
+    // Build the tree for `txids`, proving those with `matches[i]` set
+    pub fn new(txids: &[Txid], matches: &[bool]) -> PartialMerkleTree {
+        assert_eq!(txids.len(), matches.len(), "one match flag per txid");
+        let leaves: Vec<sha256d::Hash> = txids.iter().map(|txid| txid.to_raw_hash()).collect();
+        let mut tree = PartialMerkleTree {
+            total_transactions: txids.len() as u32,
+            hashes: Vec::new(),
+            flags: Vec::new(),
+        };
+        tree.build(tree.height(), 0, &leaves, matches);
+        tree
+    }
+
+    // Walk the tree as Core's CPartialMerkleTree::ExtractMatches does, returning
+    // the merkle root it commits to and the position and txid of every match.
+    // Fails on malformed or mutated trees.
+    pub fn extract_matches(&self) -> Result<(TxMerkleNode, Vec<(u32, Txid)>), String> {
+        if self.total_transactions == 0 {
+            return Err("Tree has no transactions".to_string());
+        }
+        if self.total_transactions > MAX_BLOCK_WEIGHT / MIN_TRANSACTION_WEIGHT {
+            return Err(format!(
+                "{} transactions cannot fit in a block",
+                self.total_transactions
+            ));
+        }
+        if self.hashes.len() > self.total_transactions as usize {
+            return Err("More hashes than transactions".to_string());
+        }
+        if self.flags.len() < self.hashes.len() {
+            return Err("Fewer flag bits than hashes".to_string());
+        }
+
+        let mut walk = Extract {
+            tree: self,
+            bits_used: 0,
+            hashes_used: 0,
+            matches: Vec::new(),
+        };
+        let root = walk.traverse(self.height(), 0)?;
+
+        // Every hash must be used, and only the padding of the last flag byte left over
+        if walk.bits_used.div_ceil(8) != self.flags.len().div_ceil(8) {
+            return Err("Unused flag bits".to_string());
+        }
+        if walk.hashes_used != self.hashes.len() {
+            return Err("Unused hashes".to_string());
+        }
+        Ok((TxMerkleNode::from_raw_hash(root), walk.matches))
+    }
+
+    // Levels above the leaves
+    fn height(&self) -> u32 {
+        let mut height = 0;
+        while self.width(height) > 1 {
+            height += 1;
+        }
+        height
+    }
+
+    // Nodes at `height`, counting leaves as height 0
+    fn width(&self, height: u32) -> u32 {
+        (self.total_transactions + (1 << height) - 1) >> height
+    }
+
+    fn hash(&self, height: u32, position: u32, leaves: &[sha256d::Hash]) -> sha256d::Hash {
+        if height == 0 {
+            return leaves[position as usize];
+        }
+        let left = self.hash(height - 1, position * 2, leaves);
+        let right = if position * 2 + 1 < self.width(height - 1) {
+            self.hash(height - 1, position * 2 + 1, leaves)
+        } else {
+            left
+        };
+        parent(left, right)
+    }
+
+    fn build(&mut self, height: u32, position: u32, leaves: &[sha256d::Hash], matches: &[bool]) {
+        let start = (position << height) as usize;
+        let end = (((position + 1) << height) as usize).min(leaves.len());
+        let parent_of_match = matches[start..end].iter().any(|matched| *matched);
+        self.flags.push(parent_of_match);
+
+        if height == 0 || !parent_of_match {
+            let hash = self.hash(height, position, leaves);
+            self.hashes.push(hash);
+        } else {
+            self.build(height - 1, position * 2, leaves, matches);
+            if position * 2 + 1 < self.width(height - 1) {
+                self.build(height - 1, position * 2 + 1, leaves, matches);
+            }
+        }
+    }
+}
+
+// State of one extract_matches walk
+struct Extract<'a> {
+    tree: &'a PartialMerkleTree,
+    bits_used: usize,
+    hashes_used: usize,
+    matches: Vec<(u32, Txid)>,
+}
+
+impl Extract<'_> {
+    fn traverse(&mut self, height: u32, position: u32) -> Result<sha256d::Hash, String> {
+        let flag = *self
+            .tree
+            .flags
+            .get(self.bits_used)
+            .ok_or("Ran out of flag bits")?;
+        self.bits_used += 1;
+
+        if height == 0 || !flag {
+            let hash = *self
+                .tree
+                .hashes
+                .get(self.hashes_used)
+                .ok_or("Ran out of hashes")?;
+            self.hashes_used += 1;
+            if height == 0 && flag {
+                self.matches.push((position, Txid::from_raw_hash(hash)));
+            }
+            return Ok(hash);
+        }
+
+        let left = self.traverse(height - 1, position * 2)?;
+        let right = if position * 2 + 1 < self.tree.width(height - 1) {
+            let right = self.traverse(height - 1, position * 2 + 1)?;
+            // Identical siblings would let a mutated tree (CVE-2012-2459) prove
+            // a duplicated transaction
+            if right == left {
+                return Err("Identical left and right branches".to_string());
+            }
+            right
+        } else {
+            left
+        };
+        Ok(parent(left, right))
+    }
+}
+
+impl Encodable for PartialMerkleTree {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        let mut len = self.total_transactions.consensus_encode(writer)?;
+        // Hashes share the vector encoding of merkle nodes
+        let hashes: Vec<TxMerkleNode> = self
+            .hashes
+            .iter()
+            .map(|hash| TxMerkleNode::from_raw_hash(*hash))
+            .collect();
+        len += hashes.consensus_encode(writer)?;
+
+        // Flag bits are packed least significant bit first
+        let mut bytes = vec![0u8; self.flags.len().div_ceil(8)];
+        for (index, flag) in self.flags.iter().enumerate() {
+            bytes[index / 8] |= (*flag as u8) << (index % 8);
+        }
+        len += bytes.consensus_encode(writer)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for PartialMerkleTree {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, encode::Error> {
+        let total_transactions = u32::consensus_decode(reader)?;
+        let hashes = Vec::<TxMerkleNode>::consensus_decode(reader)?
+            .into_iter()
+            .map(TxMerkleNode::to_raw_hash)
+            .collect();
+        let bytes = Vec::<u8>::consensus_decode(reader)?;
+        let flags = (0..bytes.len() * 8)
+            .map(|index| bytes[index / 8] & (1 << (index % 8)) != 0)
+            .collect();
+        Ok(PartialMerkleTree {
+            total_transactions,
+            hashes,
+            flags,
+        })
+    }
+}
+
+// A BIP37 merkleblock message: a block header and a partial merkle tree
+// proving some of its transactions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBlock {
+    pub header: Header,
+    pub tree: PartialMerkleTree,
+}
+
+impl MerkleBlock {
+    // Prove the transactions of `block` listed in `txids`
+    pub fn new(block: &Block, txids: &HashSet<Txid>) -> MerkleBlock {
+        let block_txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.compute_txid()).collect();
+        let matches: Vec<bool> = block_txids
+            .iter()
+            .map(|txid| txids.contains(txid))
+            .collect();
+        MerkleBlock {
+            header: block.header,
+            tree: PartialMerkleTree::new(&block_txids, &matches),
+        }
+    }
+
+    // Check the header's proof of work and that the tree commits to its merkle
+    // root, returning the position and txid of every proven transaction
+    pub fn verify(&self) -> Result<Vec<(u32, Txid)>, String> {
+        let hash = self.header.block_hash();
+        if !Target::from_compact(self.header.bits).is_met_by(hash) {
+            return Err(format!("Header {} does not meet its target", hash));
+        }
+        let (root, matches) = self.tree.extract_matches()?;
+        if root != self.header.merkle_root {
+            return Err(format!(
+                "Tree root {} does not match header merkle root {}",
+                root, self.header.merkle_root
+            ));
+        }
+        Ok(matches)
+    }
+}
+
+impl Encodable for MerkleBlock {
+    fn consensus_encode<W: Write + ?Sized>(&self, writer: &mut W) -> Result<usize, io::Error> {
+        Ok(self.header.consensus_encode(writer)? + self.tree.consensus_encode(writer)?)
+    }
+}
+
+impl Decodable for MerkleBlock {
+    fn consensus_decode<R: Read + ?Sized>(reader: &mut R) -> Result<Self, encode::Error> {
+        Ok(MerkleBlock {
+            header: Header::consensus_decode(reader)?,
+            tree: PartialMerkleTree::consensus_decode(reader)?,
+        })
+    }
+}
+
+fn parent(left: sha256d::Hash, right: sha256d::Hash) -> sha256d::Hash {
+    let mut engine = sha256d::Hash::engine();
+    engine.input(left.as_byte_array());
+    engine.input(right.as_byte_array());
+    sha256d::Hash::from_engine(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        BlockHash, CompactTarget, Transaction, absolute::LockTime, block::Version, consensus,
+        transaction,
+    };
+
+    use super::*;
+
+    fn txids(count: u8) -> Vec<Txid> {
+        (1..=count)
+            .map(|n| Txid::from_byte_array([n; 32]))
+            .collect()
+    }
+
+    // Which transactions to prove: none, all, each one alone and every third
+    fn match_sets(count: usize) -> Vec<Vec<bool>> {
+        let mut sets = vec![vec![false; count], vec![true; count]];
+        sets.extend((0..count).map(|matched| (0..count).map(|i| i == matched).collect()));
+        sets.push((0..count).map(|i| i % 3 == 0).collect());
+        sets
+    }
+
+    #[test]
+    fn partial_tree_round_trips() {
+        for count in [1, 2, 3, 4, 5, 6, 7, 8, 9, 15, 16, 17] {
+            let txids = txids(count);
+            let (root, _) = compute_merkle_root(&txids);
+            for matches in match_sets(txids.len()) {
+                let tree = PartialMerkleTree::new(&txids, &matches);
+                let bytes = consensus::serialize(&tree);
+                let decoded: PartialMerkleTree = consensus::deserialize(&bytes).unwrap();
+                assert_eq!(decoded.total_transactions, tree.total_transactions);
+                assert_eq!(decoded.hashes, tree.hashes);
+                assert_eq!(consensus::serialize(&decoded), bytes);
+
+                let expected: Vec<(u32, Txid)> = (0..txids.len())
+                    .filter(|i| matches[*i])
+                    .map(|i| (i as u32, txids[i]))
+                    .collect();
+                assert_eq!(
+                    decoded.extract_matches(),
+                    Ok((root, expected)),
+                    "{count} txids"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn partial_tree_rejects_tampering() {
+        let txids = txids(7);
+        let mut matches = vec![false; 7];
+        matches[4] = true;
+        let tree = PartialMerkleTree::new(&txids, &matches);
+        let (root, _) = compute_merkle_root(&txids);
+
+        let mut damaged = tree.clone();
+        damaged.hashes[0] = sha256d::Hash::all_zeros();
+        assert_ne!(damaged.extract_matches().unwrap().0, root);
+
+        let mut extra = tree.clone();
+        extra.hashes.push(sha256d::Hash::all_zeros());
+        assert_eq!(extra.extract_matches(), Err("Unused hashes".to_string()));
+
+        let mut few_hashes = tree.clone();
+        few_hashes.hashes.pop();
+        assert_eq!(
+            few_hashes.extract_matches(),
+            Err("Ran out of hashes".to_string())
+        );
+
+        let mut few_flags = tree.clone();
+        few_flags.flags.truncate(4);
+        assert_eq!(
+            few_flags.extract_matches(),
+            Err("Ran out of flag bits".to_string())
+        );
+
+        let mut empty = tree;
+        empty.total_transactions = 0;
+        assert!(empty.extract_matches().is_err());
+    }
+
+    #[test]
+    fn duplicated_tail_is_mutated() {
+        // CVE-2012-2459: repeating the trailing transactions keeps the root
+        for (count, tail) in [(5, 1), (10, 2), (3, 1)] {
+            let txids = txids(count);
+            let mut duplicated = txids.clone();
+            duplicated.extend_from_slice(&txids[txids.len() - tail..]);
+            let (root, mutated) = compute_merkle_root(&txids);
+            assert!(!mutated, "{count} txids");
+            assert_eq!(
+                compute_merkle_root(&duplicated),
+                (root, true),
+                "{count} txids"
+            );
+        }
+
+        // The last node of an odd level paired with itself is not a mutation,
+        // but two identical transactions side by side are
+        assert!(!compute_merkle_root(&txids(9)).1);
+        let mut repeated = txids(4);
+        repeated[1] = repeated[0];
+        assert!(compute_merkle_root(&repeated).1);
+    }
+
+    #[test]
+    fn partial_tree_rejects_duplicated_tail() {
+        let mut txids = txids(10);
+        txids.extend_from_within(8..);
+        let mut matches = vec![false; txids.len()];
+        matches[9] = true;
+        matches[10] = true;
+        let tree = PartialMerkleTree::new(&txids, &matches);
+        assert_eq!(
+            tree.extract_matches(),
+            Err("Identical left and right branches".to_string())
+        );
+    }
+
+    #[test]
+    fn merkle_proofs_verify() {
+        for count in [1, 2, 3, 5, 8, 11] {
+            let txids = txids(count);
+            let (root, _) = compute_merkle_root(&txids);
+            for (index, txid) in txids.iter().enumerate() {
+                let proof = merkle_proof(&txids, index).unwrap();
+                assert!(proof.verify(*txid, root));
+                assert!(!proof.verify(Txid::all_zeros(), root));
+            }
+            assert_eq!(merkle_proof(&txids, txids.len()), None);
+        }
+    }
+
+    #[test]
+    fn decodes_core_merkleblock() {
+        // bitcoin-cli gettxoutproof '["5a4ebf66822b0b2d56bd9dc64ece0bc38ee7844a23ff1d7320a88c5fdb2ad3e2"]'
+        let bytes = hex::decode(
+            "01000000ba8b9cda965dd8e536670f9ddec10e53aab14b20bacad27b9137190000000000190760b278fe7b\
+             8565fda3b968b918d5fd997f993b23674c0af3b6fde300b38f33a5914ce6ed5b1b01e32f570200000002\
+             252bf9d75c4f481ebb6278d708257d1f12beb6dd30301d26c623f789b2ba6fc0e2d32adb5f8ca820731d\
+             ff234a84e78ec30bce4ec69dbd562d0b2b8266bf4e5a0105",
+        )
+        .unwrap();
+        let merkle_block: MerkleBlock = consensus::deserialize(&bytes).unwrap();
+        assert_eq!(merkle_block.tree.total_transactions, 2);
+        assert_eq!(
+            merkle_block.verify(),
+            Ok(vec![(
+                1,
+                "5a4ebf66822b0b2d56bd9dc64ece0bc38ee7844a23ff1d7320a88c5fdb2ad3e2"
+                    .parse()
+                    .unwrap()
+            )])
+        );
+        assert_eq!(consensus::serialize(&merkle_block), bytes);
+    }
+
+    #[test]
+    fn merkle_block_proves_block_transactions() {
+        let txdata: Vec<Transaction> = (0..5)
+            .map(|n| Transaction {
+                version: transaction::Version::TWO,
+                lock_time: LockTime::from_consensus(n),
+                input: Vec::new(),
+                output: Vec::new(),
+            })
+            .collect();
+        let block_txids: Vec<Txid> = txdata.iter().map(|tx| tx.compute_txid()).collect();
+        let mut block = Block {
+            header: Header {
+                version: Version::TWO,
+                prev_blockhash: BlockHash::all_zeros(),
+                merkle_root: compute_merkle_root(&block_txids).0,
+                time: 0,
+                bits: CompactTarget::from_consensus(0x207fffff),
+                nonce: 0,
+            },
+            txdata,
+        };
+        while !Target::from_compact(block.header.bits).is_met_by(block.block_hash()) {
+            block.header.nonce += 1;
+        }
+
+        let proven = HashSet::from([block_txids[1], block_txids[3]]);
+        let merkle_block = MerkleBlock::new(&block, &proven);
+        assert_eq!(
+            merkle_block.verify(),
+            Ok(vec![(1, block_txids[1]), (3, block_txids[3])])
+        );
+
+        let mut wrong_root = merkle_block;
+        wrong_root.header.merkle_root = TxMerkleNode::all_zeros();
+        assert!(wrong_root.verify().is_err());
+    }
+}
@@ -0,0 +1,624 @@
+use std::fmt;
+
+use bitcoincore_rpc::bitcoin::{
+    Script, Transaction, TxIn, TxOut,
+    opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_1, OP_PUSHNUM_16},
+    script::Instruction,
+};
+
+use crate::{
+    interpreter::{self, ANNEX_TAG, MAX_SCRIPT_SIZE, TAPROOT_LEAF_MASK, TAPROOT_LEAF_TAPSCRIPT},
+    sigops::{self, MAX_BLOCK_SIGOPS_COST},
+};
+
+// Transaction versions Bitcoin Core relays without the TRUC (version 3) rules
+const MAX_STANDARD_VERSION: i32 = 2;
+
+// Largest transaction weight Bitcoin Core will relay (MAX_STANDARD_TX_WEIGHT)
+const MAX_STANDARD_TX_WEIGHT: u64 = 400_000;
+
+// Smallest serialized size without witness, so no transaction is 64 bytes
+// and mistaken for an inner merkle node
+const MIN_STANDARD_TX_NONWITNESS_SIZE: usize = 65;
+
+// Large enough for a 15-of-15 P2SH multisig spend
+const MAX_STANDARD_SCRIPTSIG_SIZE: usize = 1_650;
+
+// Bitcoin Core's default -datacarriersize before v30: the whole OP_RETURN
+// output script, opcode and pushes included
+const MAX_OP_RETURN_RELAY: usize = 83;
+
+// Most keys in a standard bare multisig output
+const MAX_BARE_MULTISIG_KEYS: u8 = 3;
+
+// Bitcoin Core's default -dustrelayfee, in sats per 1000 virtual bytes
+const DUST_RELAY_FEE_RATE: u64 = 3_000;
+
+// Signature operations in a standard P2SH redeem script
+const MAX_P2SH_SIGOPS: u64 = 15;
+
+// Sigop cost of a standard transaction, a fifth of the block limit
+const MAX_STANDARD_TX_SIGOPS_COST: u64 = MAX_BLOCK_SIGOPS_COST / 5;
+
+// P2WSH witness limits: script size, stack items besides the script, item size
+const MAX_STANDARD_P2WSH_SCRIPT_SIZE: usize = 3_600;
+const MAX_STANDARD_P2WSH_STACK_ITEMS: usize = 100;
+const MAX_STANDARD_P2WSH_STACK_ITEM_SIZE: usize = 80;
+
+// Size of each tapscript stack item besides the script and control block
+const MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE: usize = 80;
+
+// A relay policy rule broken by a transaction that consensus would accept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    Version(i32),
+    Overweight(u64),
+    // Serialized size without witness is below MIN_STANDARD_TX_NONWITNESS_SIZE
+    TooSmall(usize),
+    ScriptSigSize {
+        input: usize,
+        size: usize,
+    },
+    ScriptSigNotPushOnly(usize),
+    // The output script matches none of the standard templates
+    NonStandardOutput(usize),
+    OpReturnTooLarge {
+        output: usize,
+        size: usize,
+    },
+    BareMultisigTooLarge {
+        output: usize,
+        keys: u8,
+    },
+    MultipleOpReturns(usize),
+    // Pays less than it would cost to spend at the dust relay fee rate
+    Dust {
+        output: usize,
+        value: u64,
+        threshold: u64,
+    },
+    // Spends a non-standard or unknown witness version output
+    NonStandardInput(usize),
+    P2shSigops {
+        input: usize,
+        sigops: u64,
+    },
+    NonStandardWitness {
+        input: usize,
+        reason: &'static str,
+    },
+    TooManySigops(u64),
+}
+
+impl PolicyViolation {
+    // Bitcoin Core's reject reason for the rule, as returned by sendrawtransaction
+    pub fn reason(&self) -> &'static str {
+        match self {
+            PolicyViolation::Version(_) => "version",
+            PolicyViolation::Overweight(_) => "tx-size",
+            PolicyViolation::TooSmall(_) => "tx-size-small",
+            PolicyViolation::ScriptSigSize { .. } => "scriptsig-size",
+            PolicyViolation::ScriptSigNotPushOnly(_) => "scriptsig-not-pushonly",
+            PolicyViolation::NonStandardOutput(_)
+            | PolicyViolation::OpReturnTooLarge { .. }
+            | PolicyViolation::BareMultisigTooLarge { .. } => "scriptpubkey",
+            PolicyViolation::MultipleOpReturns(_) => "multi-op-return",
+            PolicyViolation::Dust { .. } => "dust",
+            PolicyViolation::NonStandardInput(_) | PolicyViolation::P2shSigops { .. } => {
+                "bad-txns-nonstandard-inputs"
+            }
+            PolicyViolation::NonStandardWitness { .. } => "bad-witness-nonstandard",
+            PolicyViolation::TooManySigops(_) => "bad-txns-too-many-sigops",
+        }
+    }
+}
+
+impl fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PolicyViolation::Version(version) => write!(f, "version {}", version),
+            PolicyViolation::Overweight(weight) => {
+                write!(f, "weight {} exceeds {}", weight, MAX_STANDARD_TX_WEIGHT)
+            }
+            PolicyViolation::TooSmall(size) => write!(
+                f,
+                "{} bytes without witness, below {}",
+                size, MIN_STANDARD_TX_NONWITNESS_SIZE
+            ),
+            PolicyViolation::ScriptSigSize { input, size } => write!(
+                f,
+                "input {} scriptSig is {} bytes, more than {}",
+                input, size, MAX_STANDARD_SCRIPTSIG_SIZE
+            ),
+            PolicyViolation::ScriptSigNotPushOnly(input) => {
+                write!(f, "input {} scriptSig is not push-only", input)
+            }
+            PolicyViolation::NonStandardOutput(output) => {
+                write!(f, "output {} script is non-standard", output)
+            }
+            PolicyViolation::OpReturnTooLarge { output, size } => write!(
+                f,
+                "output {} OP_RETURN script is {} bytes, more than {}",
+                output, size, MAX_OP_RETURN_RELAY
+            ),
+            PolicyViolation::BareMultisigTooLarge { output, keys } => write!(
+                f,
+                "output {} is a bare multisig with {} keys, more than {}",
+                output, keys, MAX_BARE_MULTISIG_KEYS
+            ),
+            PolicyViolation::MultipleOpReturns(count) => {
+                write!(f, "{} OP_RETURN outputs, at most one allowed", count)
+            }
+            PolicyViolation::Dust {
+                output,
+                value,
+                threshold,
+            } => write!(
+                f,
+                "output {} pays {} sats, below the {} sat dust threshold",
+                output, value, threshold
+            ),
+            PolicyViolation::NonStandardInput(input) => {
+                write!(f, "input {} spends a non-standard output", input)
+            }
+            PolicyViolation::P2shSigops { input, sigops } => write!(
+                f,
+                "input {} redeem script has {} sigops, more than {}",
+                input, sigops, MAX_P2SH_SIGOPS
+            ),
+            PolicyViolation::NonStandardWitness { input, reason } => {
+                write!(f, "input {} witness: {}", input, reason)
+            }
+            PolicyViolation::TooManySigops(cost) => write!(
+                f,
+                "sigop cost {} exceeds {}",
+                cost, MAX_STANDARD_TX_SIGOPS_COST
+            ),
+        }
+    }
+}
+
+// Output templates as Bitcoin Core's Solver tells them apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OutputType {
+    NonStandard,
+    PubKey,
+    PubKeyHash,
+    ScriptHash,
+    Multisig { keys: u8 },
+    NullData,
+    WitnessV0KeyHash,
+    WitnessV0ScriptHash,
+    Taproot,
+    WitnessUnknown,
+}
+
+fn output_type(script: &Script) -> OutputType {
+    let bytes = script.as_bytes();
+    if interpreter::is_p2sh(bytes) {
+        return OutputType::ScriptHash;
+    }
+    if let Some((version, program)) = interpreter::witness_program(bytes) {
+        return match (version, program.len()) {
+            (0, 20) => OutputType::WitnessV0KeyHash,
+            (0, 32) => OutputType::WitnessV0ScriptHash,
+            (0, _) => OutputType::NonStandard,
+            (1, 32) => OutputType::Taproot,
+            _ => OutputType::WitnessUnknown,
+        };
+    }
+    if script.is_op_return() && interpreter::is_push_only(&bytes[1..]) {
+        return OutputType::NullData;
+    }
+    if script.is_p2pk() {
+        return OutputType::PubKey;
+    }
+    if script.is_p2pkh() {
+        return OutputType::PubKeyHash;
+    }
+    multisig_keys(script).map_or(OutputType::NonStandard, |keys| OutputType::Multisig {
+        keys,
+    })
+}
+
+// Key count of an `OP_m <keys> OP_n OP_CHECKMULTISIG` script with 1 <= m <= n
+fn multisig_keys(script: &Script) -> Option<u8> {
+    let small_integer = |opcode: u8| {
+        (OP_PUSHNUM_1.to_u8()..=OP_PUSHNUM_16.to_u8())
+            .contains(&opcode)
+            .then(|| opcode - OP_PUSHNUM_1.to_u8() + 1)
+    };
+    let (&required, rest) = script.as_bytes().split_first()?;
+    let (&checkmultisig, rest) = rest.split_last()?;
+    let (&count, keys) = rest.split_last()?;
+    if checkmultisig != OP_CHECKMULTISIG.to_u8() {
+        return None;
+    }
+    let (required, count) = (small_integer(required)?, small_integer(count)?);
+
+    let mut found = 0;
+    for instruction in Script::from_bytes(keys).instructions() {
+        match instruction.ok()? {
+            Instruction::PushBytes(key) if valid_pubkey_size(key.as_bytes()) => found += 1,
+            _ => return None,
+        }
+    }
+    (found == count && required <= count).then_some(count)
+}
+
+// Whether a public key is as long as its prefix byte says, as CPubKey::ValidSize
+fn valid_pubkey_size(key: &[u8]) -> bool {
+    match key.first() {
+        Some(0x02 | 0x03) => key.len() == 33,
+        Some(0x04 | 0x06 | 0x07) => key.len() == 65,
+        _ => false,
+    }
+}
+
+// Value below which an output costs more to spend than it is worth at the
+// dust relay fee, as Bitcoin Core's GetDustThreshold. The spend is an
+// outpoint, scriptSig length, sequence and a 107-byte signature and key,
+// witness-discounted for witness programs.
+pub fn dust_threshold(output: &TxOut) -> u64 {
+    let script = output.script_pubkey.as_bytes();
+    if output.script_pubkey.is_op_return() || script.len() > MAX_SCRIPT_SIZE {
+        return 0;
+    }
+    let spend_size = if interpreter::witness_program(script).is_some() {
+        32 + 4 + 1 + 107 / 4 + 4
+    } else {
+        32 + 4 + 1 + 107 + 4
+    };
+    (output.size() + spend_size) as u64 * DUST_RELAY_FEE_RATE / 1000
+}
+
+// Check a transaction against Bitcoin Core's default relay policy: IsStandardTx,
+// the minimum size, AreInputsStandard, IsWitnessStandard and the sigop limit.
+// `prevouts` holds the output spent by each input. Returns every rule broken;
+// an empty list means standard.
+pub fn check_standard(tx: &Transaction, prevouts: &[TxOut]) -> Vec<PolicyViolation> {
+    let mut violations = Vec::new();
+
+    if !(1..=MAX_STANDARD_VERSION).contains(&tx.version.0) {
+        violations.push(PolicyViolation::Version(tx.version.0));
+    }
+    let weight = tx.weight().to_wu();
+    if weight > MAX_STANDARD_TX_WEIGHT {
+        violations.push(PolicyViolation::Overweight(weight));
+    }
+    if tx.base_size() < MIN_STANDARD_TX_NONWITNESS_SIZE {
+        violations.push(PolicyViolation::TooSmall(tx.base_size()));
+    }
+
+    for (index, input) in tx.input.iter().enumerate() {
+        let size = input.script_sig.len();
+        if size > MAX_STANDARD_SCRIPTSIG_SIZE {
+            violations.push(PolicyViolation::ScriptSigSize { input: index, size });
+        }
+        if !interpreter::is_push_only(input.script_sig.as_bytes()) {
+            violations.push(PolicyViolation::ScriptSigNotPushOnly(index));
+        }
+    }
+
+    let mut op_returns = 0;
+    for (index, output) in tx.output.iter().enumerate() {
+        match output_type(&output.script_pubkey) {
+            OutputType::NonStandard => violations.push(PolicyViolation::NonStandardOutput(index)),
+            OutputType::NullData => {
+                op_returns += 1;
+                let size = output.script_pubkey.len();
+                if size > MAX_OP_RETURN_RELAY {
+                    violations.push(PolicyViolation::OpReturnTooLarge {
+                        output: index,
+                        size,
+                    });
+                }
+            }
+            OutputType::Multisig { keys } if keys > MAX_BARE_MULTISIG_KEYS => {
+                violations.push(PolicyViolation::BareMultisigTooLarge {
+                    output: index,
+                    keys,
+                });
+            }
+            _ => {}
+        }
+        let threshold = dust_threshold(output);
+        if output.value.to_sat() < threshold {
+            violations.push(PolicyViolation::Dust {
+                output: index,
+                value: output.value.to_sat(),
+                threshold,
+            });
+        }
+    }
+    if op_returns > 1 {
+        violations.push(PolicyViolation::MultipleOpReturns(op_returns));
+    }
+
+    for (index, (input, prevout)) in tx.input.iter().zip(prevouts).enumerate() {
+        match output_type(&prevout.script_pubkey) {
+            OutputType::NonStandard | OutputType::WitnessUnknown => {
+                violations.push(PolicyViolation::NonStandardInput(index));
+            }
+            OutputType::ScriptHash => match interpreter::last_push(input.script_sig.as_bytes()) {
+                Some(redeem_script) => {
+                    let sigops = interpreter::sigop_count(redeem_script, true);
+                    if sigops > MAX_P2SH_SIGOPS {
+                        violations.push(PolicyViolation::P2shSigops {
+                            input: index,
+                            sigops,
+                        });
+                    }
+                }
+                None => violations.push(PolicyViolation::NonStandardInput(index)),
+            },
+            _ => {}
+        }
+        if let Err(reason) = check_witness(input, prevout) {
+            violations.push(PolicyViolation::NonStandardWitness {
+                input: index,
+                reason,
+            });
+        }
+    }
+
+    let sigop_cost = sigops::transaction_sigop_cost(tx, prevouts);
+    if sigop_cost > MAX_STANDARD_TX_SIGOPS_COST {
+        violations.push(PolicyViolation::TooManySigops(sigop_cost));
+    }
+    violations
+}
+
+// Witness limits for P2WSH and tapscript spends, native or nested in P2SH
+fn check_witness(input: &TxIn, prevout: &TxOut) -> Result<(), &'static str> {
+    if input.witness.is_empty() {
+        return Ok(());
+    }
+    let mut script_pubkey = prevout.script_pubkey.as_bytes();
+    let nested = interpreter::is_p2sh(script_pubkey);
+    if nested {
+        script_pubkey = interpreter::last_push(input.script_sig.as_bytes())
+            .ok_or("P2SH scriptSig does not push a redeem script")?;
+    }
+    let (version, program) =
+        interpreter::witness_program(script_pubkey).ok_or("witness on a non-witness input")?;
+    let stack: Vec<&[u8]> = input.witness.iter().collect();
+
+    match (version, program.len()) {
+        (0, 32) => {
+            let Some((witness_script, items)) = stack.split_last() else {
+                return Ok(());
+            };
+            if witness_script.len() > MAX_STANDARD_P2WSH_SCRIPT_SIZE {
+                return Err("witness script larger than 3600 bytes");
+            }
+            if items.len() > MAX_STANDARD_P2WSH_STACK_ITEMS {
+                return Err("more than 100 stack items");
+            }
+            if items
+                .iter()
+                .any(|item| item.len() > MAX_STANDARD_P2WSH_STACK_ITEM_SIZE)
+            {
+                return Err("stack item larger than 80 bytes");
+            }
+        }
+        (1, 32) if !nested => {
+            if stack.len() >= 2 && stack[stack.len() - 1].first() == Some(&ANNEX_TAG) {
+                return Err("annex present");
+            }
+            // A script path spend: stack items, the script and the control block
+            if let [items @ .., _script, control_block] = stack.as_slice() {
+                let Some(leaf_version) = control_block.first() else {
+                    return Err("empty control block");
+                };
+                if leaf_version & TAPROOT_LEAF_MASK == TAPROOT_LEAF_TAPSCRIPT
+                    && items
+                        .iter()
+                        .any(|item| item.len() > MAX_STANDARD_TAPSCRIPT_STACK_ITEM_SIZE)
+                {
+                    return Err("tapscript stack item larger than 80 bytes");
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        Amount, OutPoint, PubkeyHash, ScriptBuf, Sequence, Txid, WPubkeyHash, Witness,
+        absolute::LockTime, hashes::Hash, transaction,
+    };
+
+    use super::*;
+
+    struct Case {
+        name: &'static str,
+        // Changes a standard P2WPKH spend and the output it spends
+        change: fn(&mut Transaction, &mut TxOut),
+        expected: Vec<PolicyViolation>,
+    }
+
+    fn p2wpkh() -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20]))
+    }
+
+    fn op_true() -> ScriptBuf {
+        ScriptBuf::from_bytes(vec![0x51])
+    }
+
+    fn output(value: u64, script_pubkey: ScriptBuf) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey,
+        }
+    }
+
+    fn op_return(data_len: u8) -> ScriptBuf {
+        let mut script = vec![0x6a, 0x4c, data_len];
+        script.extend(vec![0; data_len as usize]);
+        ScriptBuf::from_bytes(script)
+    }
+
+    fn standard_spend() -> (Transaction, TxOut) {
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::from_byte_array([1; 32]), 0),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[vec![0x30; 72], vec![2; 33]]),
+            }],
+            output: vec![output(10_000, p2wpkh())],
+        };
+        (tx, output(20_000, p2wpkh()))
+    }
+
+    #[test]
+    fn standard_cases() {
+        let cases = [
+            Case {
+                name: "P2WPKH spend",
+                change: |_, _| {},
+                expected: vec![],
+            },
+            Case {
+                name: "version 0",
+                change: |tx, _| tx.version = transaction::Version(0),
+                expected: vec![PolicyViolation::Version(0)],
+            },
+            Case {
+                name: "version 3",
+                change: |tx, _| tx.version = transaction::Version(3),
+                expected: vec![PolicyViolation::Version(3)],
+            },
+            Case {
+                name: "83-byte OP_RETURN",
+                change: |tx, _| tx.output.push(output(0, op_return(80))),
+                expected: vec![],
+            },
+            Case {
+                name: "84-byte OP_RETURN",
+                change: |tx, _| tx.output.push(output(0, op_return(81))),
+                expected: vec![PolicyViolation::OpReturnTooLarge {
+                    output: 1,
+                    size: 84,
+                }],
+            },
+            Case {
+                name: "two OP_RETURNs",
+                change: |tx, _| {
+                    tx.output.push(output(0, op_return(1)));
+                    tx.output.push(output(0, op_return(1)));
+                },
+                expected: vec![PolicyViolation::MultipleOpReturns(2)],
+            },
+            Case {
+                name: "3-key bare multisig",
+                change: |tx, _| {
+                    let mut script = vec![0x51];
+                    for _ in 0..3 {
+                        script.push(33);
+                        script.extend([2; 33]);
+                    }
+                    script.extend([0x53, 0xae]);
+                    tx.output
+                        .push(output(10_000, ScriptBuf::from_bytes(script)));
+                },
+                expected: vec![],
+            },
+            Case {
+                name: "4-key bare multisig",
+                change: |tx, _| {
+                    let mut script = vec![0x51];
+                    for _ in 0..4 {
+                        script.push(33);
+                        script.extend([2; 33]);
+                    }
+                    script.extend([0x54, 0xae]);
+                    tx.output
+                        .push(output(10_000, ScriptBuf::from_bytes(script)));
+                },
+                expected: vec![PolicyViolation::BareMultisigTooLarge { output: 1, keys: 4 }],
+            },
+            Case {
+                name: "non-push scriptSig",
+                change: |tx, _| tx.input[0].script_sig = ScriptBuf::from_bytes(vec![0x61]),
+                expected: vec![PolicyViolation::ScriptSigNotPushOnly(0)],
+            },
+            Case {
+                name: "output below the dust threshold",
+                change: |tx, _| tx.output[0].value = Amount::from_sat(293),
+                expected: vec![PolicyViolation::Dust {
+                    output: 0,
+                    value: 293,
+                    threshold: 294,
+                }],
+            },
+            Case {
+                name: "80-byte P2WSH stack item",
+                change: |tx, prevout| {
+                    prevout.script_pubkey = ScriptBuf::new_p2wsh(&op_true().wscript_hash());
+                    tx.input[0].witness = Witness::from_slice(&[vec![0; 80], op_true().to_bytes()]);
+                },
+                expected: vec![],
+            },
+            Case {
+                name: "81-byte P2WSH stack item",
+                change: |tx, prevout| {
+                    prevout.script_pubkey = ScriptBuf::new_p2wsh(&op_true().wscript_hash());
+                    tx.input[0].witness = Witness::from_slice(&[vec![0; 81], op_true().to_bytes()]);
+                },
+                expected: vec![PolicyViolation::NonStandardWitness {
+                    input: 0,
+                    reason: "stack item larger than 80 bytes",
+                }],
+            },
+            Case {
+                name: "taproot key path spend",
+                change: |tx, prevout| {
+                    let mut script = vec![0x51, 32];
+                    script.extend([1; 32]);
+                    prevout.script_pubkey = ScriptBuf::from_bytes(script);
+                    tx.input[0].witness = Witness::from_slice(&[vec![1; 64]]);
+                },
+                expected: vec![],
+            },
+            Case {
+                name: "taproot annex",
+                change: |tx, prevout| {
+                    let mut script = vec![0x51, 32];
+                    script.extend([1; 32]);
+                    prevout.script_pubkey = ScriptBuf::from_bytes(script);
+                    tx.input[0].witness = Witness::from_slice(&[vec![1; 64], vec![ANNEX_TAG, 0]]);
+                },
+                expected: vec![PolicyViolation::NonStandardWitness {
+                    input: 0,
+                    reason: "annex present",
+                }],
+            },
+        ];
+
+        for case in cases {
+            let (mut tx, mut prevout) = standard_spend();
+            (case.change)(&mut tx, &mut prevout);
+            assert_eq!(
+                check_standard(&tx, &[prevout]),
+                case.expected,
+                "{}",
+                case.name
+            );
+        }
+    }
+
+    #[test]
+    fn dust_thresholds() {
+        let p2pkh = ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([1; 20]));
+        assert_eq!(dust_threshold(&output(0, p2pkh)), 546);
+        assert_eq!(dust_threshold(&output(0, p2wpkh())), 294);
+        assert_eq!(dust_threshold(&output(0, op_return(1))), 0);
+    }
+}
@@ -0,0 +1,635 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use bitcoincore_rpc::bitcoin::{OutPoint, Transaction, TxOut, Txid, consensus};
+
+use crate::{
+    ValidTransactions,
+    interpreter::{self, ScriptError},
+    policy::{self, PolicyViolation},
+};
+
+// Inputs with a sequence at or below this opt their transaction in to
+// replacement (BIP125)
+const MAX_BIP125_RBF_SEQUENCE: u32 = 0xffff_fffd;
+
+// Most transactions a replacement may evict, counting descendants (BIP125 rule 5)
+const MAX_REPLACEMENT_CANDIDATES: usize = 100;
+
+// Bitcoin Core's default -incrementalrelayfee, in sats per virtual byte
+const INCREMENTAL_RELAY_FEE_RATE: u64 = 1;
+
+// Why an incoming transaction may not replace the transactions it conflicts with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rejection {
+    AlreadyInMempool(Txid),
+    // A conflicting transaction neither signals replaceability nor has an
+    // unconfirmed ancestor that does (rule 1)
+    NotReplaceable(Txid),
+    // The spent output is neither confirmed in the mempool data nor created by
+    // a transaction in the set
+    MissingInput(OutPoint),
+    InsufficientInputValue { input_value: u64, output_value: u64 },
+    // Breaks relay policy, which is checked before any replacement rule
+    NonStandard(PolicyViolation),
+    // Conflicts and their descendants exceed MAX_REPLACEMENT_CANDIDATES (rule 5)
+    TooManyReplacements(usize),
+    // Spends an unconfirmed output no replaced transaction spent (rule 2)
+    AddsUnconfirmed(OutPoint),
+    // Spends an output of a transaction it would evict
+    SpendsConflictingTransaction(Txid),
+    // Pays less than the transactions it evicts (rule 3)
+    LessFees { fee: u64, replaced_fee: u64 },
+    // The fee added over the evicted transactions does not pay for relaying
+    // the replacement at the incremental relay fee (rule 4)
+    LowAdditionalFee { additional_fee: u64, required: u64 },
+    BadScript { input: usize, error: ScriptError },
+}
+
+impl Rejection {
+    // Bitcoin Core's reject reason, as returned by sendrawtransaction
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::AlreadyInMempool(_) => "txn-already-in-mempool",
+            Rejection::NotReplaceable(_) => "txn-mempool-conflict",
+            Rejection::MissingInput(_) => "bad-txns-inputs-missingorspent",
+            Rejection::InsufficientInputValue { .. } => "bad-txns-in-belowout",
+            Rejection::NonStandard(violation) => violation.reason(),
+            Rejection::TooManyReplacements(_) => "too many potential replacements",
+            Rejection::AddsUnconfirmed(_) => "replacement-adds-unconfirmed",
+            Rejection::SpendsConflictingTransaction(_) => "bad-txns-spends-conflicting-tx",
+            Rejection::LessFees { .. } | Rejection::LowAdditionalFee { .. } => "insufficient fee",
+            Rejection::BadScript { .. } => "mandatory-script-verify-flag-failed",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejection::AlreadyInMempool(txid) => write!(f, "{} is already in the set", txid),
+            Rejection::NotReplaceable(txid) => {
+                write!(f, "conflicts with {}, which does not signal BIP125", txid)
+            }
+            Rejection::MissingInput(outpoint) => write!(f, "spends unknown output {}", outpoint),
+            Rejection::InsufficientInputValue {
+                input_value,
+                output_value,
+            } => write!(
+                f,
+                "outputs ({} sats) exceed inputs ({} sats)",
+                output_value, input_value
+            ),
+            Rejection::NonStandard(violation) => write!(f, "{}", violation),
+            Rejection::TooManyReplacements(count) => write!(
+                f,
+                "would evict {} transactions, more than {}",
+                count, MAX_REPLACEMENT_CANDIDATES
+            ),
+            Rejection::AddsUnconfirmed(outpoint) => {
+                write!(f, "spends new unconfirmed output {}", outpoint)
+            }
+            Rejection::SpendsConflictingTransaction(txid) => {
+                write!(f, "spends an output of {}, which it replaces", txid)
+            }
+            Rejection::LessFees { fee, replaced_fee } => write!(
+                f,
+                "less fees than the replaced transactions; {} < {} sats",
+                fee, replaced_fee
+            ),
+            Rejection::LowAdditionalFee {
+                additional_fee,
+                required,
+            } => write!(
+                f,
+                "not enough additional fees to relay; {} < {} sats",
+                additional_fee, required
+            ),
+            Rejection::BadScript { input, error } => {
+                write!(f, "script failure on input {}: {}", input, error)
+            }
+        }
+    }
+}
+
+// A replacement that passed every rule and what it would evict
+#[derive(Debug)]
+pub struct Replacement {
+    pub fee: u64,
+    pub vsize: u64,
+    // Indices into the set of the transactions it spends the inputs of
+    pub conflicts: Vec<usize>,
+    // The conflicts and all their descendants, ascending
+    pub replaced: Vec<usize>,
+    pub replaced_fee: u64,
+}
+
+// The mempool set seen as a replacement target: which transaction spends each
+// outpoint and which outputs the set creates or spends
+pub struct Pool<'a> {
+    txs: &'a [ValidTransactions],
+    index_by_txid: HashMap<Txid, usize>,
+    spenders: HashMap<OutPoint, usize>,
+    // Confirmed outputs from the mempool data plus every output of the set
+    outputs: HashMap<OutPoint, TxOut>,
+    children: Vec<Vec<usize>>,
+}
+
+impl<'a> Pool<'a> {
+    pub fn new(
+        txs: &'a [ValidTransactions],
+        prevouts: &HashMap<OutPoint, TxOut>,
+    ) -> Result<Pool<'a>, String> {
+        let mut index_by_txid = HashMap::new();
+        let mut spenders = HashMap::new();
+        let mut outputs = prevouts.clone();
+        for (index, tx_data) in txs.iter().enumerate() {
+            let tx: Transaction = hex::decode(&tx_data.hex)
+                .ok()
+                .and_then(|bytes| consensus::deserialize(&bytes).ok())
+                .ok_or_else(|| format!("Failed to decode transaction {}", tx_data.id))?;
+            let txid = tx.compute_txid();
+            for input in &tx.input {
+                spenders.insert(input.previous_output, index);
+            }
+            for (vout, output) in tx.output.into_iter().enumerate() {
+                outputs.insert(OutPoint::new(txid, vout as u32), output);
+            }
+            index_by_txid.insert(txid, index);
+        }
+
+        let mut children = vec![Vec::new(); txs.len()];
+        for (index, tx) in txs.iter().enumerate() {
+            let parents: HashSet<usize> = tx
+                .parents
+                .iter()
+                .filter_map(|parent| parent.parse().ok())
+                .filter_map(|parent: Txid| index_by_txid.get(&parent).copied())
+                .collect();
+            for parent in parents {
+                children[parent].push(index);
+            }
+        }
+
+        Ok(Pool {
+            txs,
+            index_by_txid,
+            spenders,
+            outputs,
+            children,
+        })
+    }
+
+    // Whether a transaction in the set may be replaced: one of its inputs, or
+    // of an unconfirmed ancestor's, signals. Bitcoin Core only looks at the
+    // transaction's own inputs and ignores inherited signalling.
+    fn signals_replacement(&self, index: usize) -> bool {
+        let mut seen = HashSet::from([index]);
+        let mut stack = vec![index];
+        while let Some(index) = stack.pop() {
+            let tx = &self.txs[index];
+            if tx
+                .sequences
+                .iter()
+                .any(|sequence| *sequence <= MAX_BIP125_RBF_SEQUENCE)
+            {
+                return true;
+            }
+            for parent in &tx.parents {
+                if let Some(parent) = parent
+                    .parse()
+                    .ok()
+                    .and_then(|parent: Txid| self.index_by_txid.get(&parent))
+                    && seen.insert(*parent)
+                {
+                    stack.push(*parent);
+                }
+            }
+        }
+        false
+    }
+
+    // Check `tx` against the set under the BIP125 rules, in the order Bitcoin
+    // Core applies them, and verify its scripts. A transaction without
+    // conflicts is accepted as an addition that replaces nothing.
+    pub fn check_replacement(&self, tx: &Transaction) -> Result<Replacement, Rejection> {
+        let txid = tx.compute_txid();
+        if self.index_by_txid.contains_key(&txid) {
+            return Err(Rejection::AlreadyInMempool(txid));
+        }
+
+        let mut conflicts: Vec<usize> = tx
+            .input
+            .iter()
+            .filter_map(|input| self.spenders.get(&input.previous_output).copied())
+            .collect();
+        conflicts.sort_unstable();
+        conflicts.dedup();
+        if let Some(index) = conflicts
+            .iter()
+            .find(|index| !self.signals_replacement(**index))
+        {
+            return Err(Rejection::NotReplaceable(self.txid(*index)));
+        }
+
+        let mut prevouts = Vec::with_capacity(tx.input.len());
+        for input in &tx.input {
+            let prevout = self
+                .outputs
+                .get(&input.previous_output)
+                .ok_or(Rejection::MissingInput(input.previous_output))?;
+            prevouts.push(prevout.clone());
+        }
+        let input_value: u64 = prevouts.iter().map(|prevout| prevout.value.to_sat()).sum();
+        let output_value: u64 = tx.output.iter().map(|output| output.value.to_sat()).sum();
+        let fee =
+            input_value
+                .checked_sub(output_value)
+                .ok_or(Rejection::InsufficientInputValue {
+                    input_value,
+                    output_value,
+                })?;
+
+        if let Some(violation) = policy::check_standard(tx, &prevouts).into_iter().next() {
+            return Err(Rejection::NonStandard(violation));
+        }
+
+        let mut replaced: HashSet<usize> = conflicts.iter().copied().collect();
+        let mut stack = conflicts.clone();
+        while let Some(index) = stack.pop() {
+            for child in &self.children[index] {
+                if replaced.insert(*child) {
+                    stack.push(*child);
+                }
+            }
+        }
+        if replaced.len() > MAX_REPLACEMENT_CANDIDATES {
+            return Err(Rejection::TooManyReplacements(replaced.len()));
+        }
+
+        // Unconfirmed inputs must come from transactions the conflicts
+        // already spent from
+        let conflict_parents: HashSet<&str> = conflicts
+            .iter()
+            .flat_map(|index| &self.txs[*index].parents)
+            .map(|parent| parent.as_str())
+            .collect();
+        if !conflicts.is_empty()
+            && let Some(input) = tx.input.iter().find(|input| {
+                let parent = input.previous_output.txid;
+                self.index_by_txid.contains_key(&parent)
+                    && !conflict_parents.contains(parent.to_string().as_str())
+            })
+        {
+            return Err(Rejection::AddsUnconfirmed(input.previous_output));
+        }
+
+        if let Some(parent) = tx
+            .input
+            .iter()
+            .filter_map(|input| self.index_by_txid.get(&input.previous_output.txid))
+            .find(|parent| replaced.contains(parent))
+        {
+            return Err(Rejection::SpendsConflictingTransaction(self.txid(*parent)));
+        }
+
+        let replaced_fee: u64 = replaced.iter().map(|index| self.txs[*index].fee).sum();
+        if fee < replaced_fee {
+            return Err(Rejection::LessFees { fee, replaced_fee });
+        }
+        let vsize = tx.weight().to_vbytes_ceil();
+        let required = INCREMENTAL_RELAY_FEE_RATE * vsize;
+        if !replaced.is_empty() && fee - replaced_fee < required {
+            return Err(Rejection::LowAdditionalFee {
+                additional_fee: fee - replaced_fee,
+                required,
+            });
+        }
+
+        if let Some((input, Err(error))) = interpreter::verify_transaction(tx, &prevouts)
+            .into_iter()
+            .enumerate()
+            .find(|(_, result)| result.is_err())
+        {
+            return Err(Rejection::BadScript { input, error });
+        }
+
+        let mut replaced: Vec<usize> = replaced.into_iter().collect();
+        replaced.sort_unstable();
+        Ok(Replacement {
+            fee,
+            vsize,
+            conflicts,
+            replaced,
+            replaced_fee,
+        })
+    }
+
+    pub fn txid(&self, index: usize) -> Txid {
+        self.txs[index]
+            .id
+            .parse()
+            .expect("msg: Mempool transaction has a valid txid")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        Amount, ScriptBuf, Sequence, TxIn, Witness, absolute::LockTime, hashes::Hash, transaction,
+    };
+
+    use super::*;
+
+    // Sequences that do and do not signal replaceability
+    const SIGNALS: u32 = MAX_BIP125_RBF_SEQUENCE;
+    const FINAL: u32 = 0xffff_ffff;
+
+    // Value of each confirmed output the tests spend
+    const CONFIRMED_VALUE: u64 = 100_000;
+
+    fn op_true() -> ScriptBuf {
+        ScriptBuf::from_bytes(vec![0x51])
+    }
+
+    // Confirmed outputs anyone can spend with an OP_TRUE witness script
+    fn confirmed(n: u8) -> OutPoint {
+        OutPoint::new(Txid::from_byte_array([n; 32]), 0)
+    }
+
+    fn confirmed_outputs() -> HashMap<OutPoint, TxOut> {
+        (1..=10)
+            .map(|n| {
+                let output = TxOut {
+                    value: Amount::from_sat(CONFIRMED_VALUE),
+                    script_pubkey: ScriptBuf::new_p2wsh(&op_true().wscript_hash()),
+                };
+                (confirmed(n), output)
+            })
+            .collect()
+    }
+
+    fn spend(inputs: &[(OutPoint, u32)], outputs: &[u64]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|(previous_output, sequence)| TxIn {
+                    previous_output: *previous_output,
+                    sequence: Sequence(*sequence),
+                    witness: Witness::from_slice(&[op_true().into_bytes()]),
+                    ..TxIn::default()
+                })
+                .collect(),
+            output: outputs
+                .iter()
+                .map(|value| TxOut {
+                    value: Amount::from_sat(*value),
+                    script_pubkey: ScriptBuf::new_p2wsh(&op_true().wscript_hash()),
+                })
+                .collect(),
+        }
+    }
+
+    fn entry(tx: &Transaction, fee: u64) -> ValidTransactions {
+        ValidTransactions {
+            id: tx.compute_txid().to_string(),
+            hex: consensus::encode::serialize_hex(tx),
+            weight: tx.weight().to_wu() as u32,
+            fee,
+            parents: tx
+                .input
+                .iter()
+                .map(|input| input.previous_output.txid.to_string())
+                .collect(),
+            sigop_cost: 0,
+            version: tx.version.0,
+            lock_time: tx.lock_time.to_consensus_u32(),
+            sequences: tx.input.iter().map(|input| input.sequence.0).collect(),
+            prevout_confirmations: vec![None; tx.input.len()],
+        }
+    }
+
+    fn output(tx: &Transaction, vout: u32) -> OutPoint {
+        OutPoint::new(tx.compute_txid(), vout)
+    }
+
+    // A signalling transaction spending confirmed output 1 with two outputs,
+    // a child spending its first output and an unrelated transaction
+    struct Set {
+        parent: Transaction,
+        child: Transaction,
+        unrelated: Transaction,
+        txs: Vec<ValidTransactions>,
+        outputs: HashMap<OutPoint, TxOut>,
+    }
+
+    fn set() -> Set {
+        let parent = spend(&[(confirmed(1), SIGNALS)], &[45_000, 45_000]);
+        let child = spend(&[(output(&parent, 0), FINAL)], &[40_000]);
+        let unrelated = spend(&[(confirmed(2), FINAL)], &[90_000]);
+        let txs = vec![
+            entry(&parent, 10_000),
+            entry(&child, 5_000),
+            entry(&unrelated, 10_000),
+        ];
+        Set {
+            parent,
+            child,
+            unrelated,
+            txs,
+            outputs: confirmed_outputs(),
+        }
+    }
+
+    #[test]
+    fn accepts_replacements_and_additions() {
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+
+        // The child signals through its parent
+        let tx = spend(&[(output(&set.parent, 0), FINAL)], &[30_000]);
+        let replacement = pool.check_replacement(&tx).unwrap();
+        assert_eq!(replacement.fee, 15_000);
+        assert_eq!(replacement.conflicts, vec![1]);
+        assert_eq!(replacement.replaced, vec![1]);
+        assert_eq!(replacement.replaced_fee, 5_000);
+
+        // Replacing the parent evicts the child too
+        let tx = spend(&[(confirmed(1), FINAL)], &[70_000]);
+        let replacement = pool.check_replacement(&tx).unwrap();
+        assert_eq!(replacement.conflicts, vec![0]);
+        assert_eq!(replacement.replaced, vec![0, 1]);
+        assert_eq!(replacement.replaced_fee, 15_000);
+
+        let tx = spend(&[(confirmed(3), FINAL)], &[90_000]);
+        let addition = pool.check_replacement(&tx).unwrap();
+        assert!(addition.conflicts.is_empty() && addition.replaced.is_empty());
+    }
+
+    #[test]
+    fn already_in_mempool() {
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        assert_eq!(
+            pool.check_replacement(&set.child).unwrap_err(),
+            Rejection::AlreadyInMempool(set.child.compute_txid())
+        );
+    }
+
+    #[test]
+    fn not_replaceable() {
+        // Rule 1: neither the unrelated transaction nor an ancestor signals
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        let tx = spend(&[(confirmed(2), FINAL)], &[50_000]);
+        assert_eq!(
+            pool.check_replacement(&tx).unwrap_err(),
+            Rejection::NotReplaceable(set.unrelated.compute_txid())
+        );
+    }
+
+    #[test]
+    fn missing_input() {
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        let unknown = OutPoint::new(Txid::from_byte_array([0xee; 32]), 0);
+        let tx = spend(&[(unknown, FINAL)], &[50_000]);
+        assert_eq!(
+            pool.check_replacement(&tx).unwrap_err(),
+            Rejection::MissingInput(unknown)
+        );
+    }
+
+    #[test]
+    fn insufficient_input_value() {
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        let tx = spend(&[(confirmed(3), FINAL)], &[150_000]);
+        assert_eq!(
+            pool.check_replacement(&tx).unwrap_err(),
+            Rejection::InsufficientInputValue {
+                input_value: CONFIRMED_VALUE,
+                output_value: 150_000
+            }
+        );
+    }
+
+    #[test]
+    fn non_standard() {
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        let tx = spend(&[(confirmed(1), FINAL)], &[100]);
+        let rejection = pool.check_replacement(&tx).unwrap_err();
+        assert!(
+            matches!(
+                rejection,
+                Rejection::NonStandard(PolicyViolation::Dust { output: 0, .. })
+            ),
+            "{:?}",
+            rejection
+        );
+        assert_eq!(rejection.reason(), "dust");
+    }
+
+    #[test]
+    fn too_many_replacements() {
+        // Rule 5: the parent and its 100 children are 101 evictions
+        let parent = spend(&[(confirmed(1), SIGNALS)], &[900; 100]);
+        let mut txs = vec![entry(&parent, 10_000)];
+        txs.extend(
+            (0..100).map(|vout| entry(&spend(&[(output(&parent, vout), FINAL)], &[500]), 400)),
+        );
+        let outputs = confirmed_outputs();
+        let pool = Pool::new(&txs, &outputs).unwrap();
+
+        let tx = spend(&[(confirmed(1), FINAL)], &[10_000]);
+        assert_eq!(
+            pool.check_replacement(&tx).unwrap_err(),
+            Rejection::TooManyReplacements(101)
+        );
+    }
+
+    #[test]
+    fn adds_unconfirmed() {
+        // Rule 2: the unrelated transaction's output is new to the conflict
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        let tx = spend(
+            &[(confirmed(1), FINAL), (output(&set.unrelated, 0), FINAL)],
+            &[100_000],
+        );
+        assert_eq!(
+            pool.check_replacement(&tx).unwrap_err(),
+            Rejection::AddsUnconfirmed(output(&set.unrelated, 0))
+        );
+    }
+
+    #[test]
+    fn spends_conflicting_transaction() {
+        // Evicts the parent through its input while spending the parent's output
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        let tx = spend(
+            &[(confirmed(1), FINAL), (output(&set.parent, 0), FINAL)],
+            &[100_000],
+        );
+        assert_eq!(
+            pool.check_replacement(&tx).unwrap_err(),
+            Rejection::SpendsConflictingTransaction(set.parent.compute_txid())
+        );
+    }
+
+    #[test]
+    fn less_fees() {
+        // Rule 3: the parent and child paid 15,000 sats together
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        let tx = spend(&[(confirmed(1), FINAL)], &[90_000]);
+        assert_eq!(
+            pool.check_replacement(&tx).unwrap_err(),
+            Rejection::LessFees {
+                fee: 10_000,
+                replaced_fee: 15_000
+            }
+        );
+    }
+
+    #[test]
+    fn low_additional_fee() {
+        // Rule 4: 10 sats more does not pay for relaying the replacement
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        let tx = spend(&[(confirmed(1), FINAL)], &[84_990]);
+        let vsize = tx.weight().to_vbytes_ceil();
+        assert_eq!(
+            pool.check_replacement(&tx).unwrap_err(),
+            Rejection::LowAdditionalFee {
+                additional_fee: 10,
+                required: INCREMENTAL_RELAY_FEE_RATE * vsize
+            }
+        );
+
+        // Paying exactly the incremental relay fee is enough
+        let tx = spend(&[(confirmed(1), FINAL)], &[85_000 - vsize]);
+        assert!(pool.check_replacement(&tx).is_ok());
+    }
+
+    #[test]
+    fn bad_script() {
+        let set = set();
+        let pool = Pool::new(&set.txs, &set.outputs).unwrap();
+        // OP_2 does not hash to the OP_TRUE witness program
+        let mut tx = spend(&[(confirmed(3), FINAL)], &[90_000]);
+        tx.input[0].witness = Witness::from_slice(&[vec![0x52]]);
+        let rejection = pool.check_replacement(&tx).unwrap_err();
+        assert!(
+            matches!(rejection, Rejection::BadScript { input: 0, .. }),
+            "{:?}",
+            rejection
+        );
+    }
+}
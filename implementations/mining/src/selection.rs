@@ -0,0 +1,358 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use crate::ValidTransactions;
+
+// Transactions from each end of the greedy boundary that the refinement may
+// swap: leaves at the tail of the block, and unselected transactions whose
+// parents are all in it
+const REFINE_CANDIDATES: usize = 100;
+
+// Search nodes the branch and bound may visit before settling for the best
+// solution found so far
+const MAX_SEARCH_NODES: usize = 2_000_000;
+
+// How block transactions are chosen from the validated mempool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionStrategy {
+    // Ancestor fee-rate packages, best first
+    Greedy,
+    // Greedy, then a branch-and-bound knapsack over the boundary
+    Refine,
+}
+
+impl FromStr for SelectionStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "greedy" => Ok(SelectionStrategy::Greedy),
+            "refine" => Ok(SelectionStrategy::Refine),
+            _ => Err(format!(
+                "Unknown selection strategy '{}' (expected greedy or refine)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SelectionStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelectionStrategy::Greedy => write!(f, "greedy"),
+            SelectionStrategy::Refine => write!(f, "refine"),
+        }
+    }
+}
+
+// An item the knapsack may take: a selected leaf (kept if taken) or an
+// unselected transaction (added if taken)
+struct Item {
+    index: usize,
+    fee: u64,
+    weight: u64,
+    sigop_cost: u64,
+}
+
+// Improve a greedy selection (indices into `valid_txs`, parents first) by
+// swapping low fee-rate transactions at its tail for unselected ones that fill
+// the remaining space better. Only swaps that keep every parent ahead of its
+// children are considered, only `eligible` transactions are added, and the
+// result never collects less in fees.
+pub fn refine_selection(
+    valid_txs: &[ValidTransactions],
+    eligible: &[bool],
+    greedy: &[usize],
+    max_weight: u64,
+    max_sigop_cost: u64,
+) -> Vec<usize> {
+    let index_by_id: HashMap<&str, usize> = valid_txs
+        .iter()
+        .enumerate()
+        .map(|(index, tx)| (tx.id.as_str(), index))
+        .collect();
+    let parents: Vec<Vec<usize>> = valid_txs
+        .iter()
+        .map(|tx| {
+            tx.parents
+                .iter()
+                .filter_map(|parent| index_by_id.get(parent.as_str()).copied())
+                .collect()
+        })
+        .collect();
+
+    let mut in_block = vec![false; valid_txs.len()];
+    let mut has_child_in_block = vec![false; valid_txs.len()];
+    for &index in greedy {
+        in_block[index] = true;
+        for &parent in &parents[index] {
+            has_child_in_block[parent] = true;
+        }
+    }
+
+    let item = |index: usize| Item {
+        index,
+        fee: valid_txs[index].fee,
+        weight: valid_txs[index].weight as u64,
+        sigop_cost: valid_txs[index].sigop_cost,
+    };
+
+    // Leaves from the end of the block can leave without orphaning anything
+    let removable: Vec<Item> = greedy
+        .iter()
+        .rev()
+        .filter(|index| !has_child_in_block[**index])
+        .take(REFINE_CANDIDATES)
+        .map(|index| item(*index))
+        .collect();
+    let mut is_removable = vec![false; valid_txs.len()];
+    for item in &removable {
+        is_removable[item.index] = true;
+    }
+
+    // Unselected transactions that only depend on transactions staying in the block
+    let mut addable: Vec<usize> = (0..valid_txs.len())
+        .filter(|index| {
+            !in_block[*index]
+                && eligible[*index]
+                && parents[*index]
+                    .iter()
+                    .all(|parent| in_block[*parent] && !is_removable[*parent])
+        })
+        .collect();
+    addable.sort_by(|a, b| compare_fee_rate(&item(*b), &item(*a)));
+    addable.truncate(REFINE_CANDIDATES);
+
+    let fixed_weight: u64 = greedy
+        .iter()
+        .filter(|index| !is_removable[**index])
+        .map(|index| valid_txs[*index].weight as u64)
+        .sum();
+    let fixed_sigop_cost: u64 = greedy
+        .iter()
+        .filter(|index| !is_removable[**index])
+        .map(|index| valid_txs[*index].sigop_cost)
+        .sum();
+
+    let mut items: Vec<Item> = removable;
+    items.extend(addable.into_iter().map(item));
+    items.sort_by(|a, b| compare_fee_rate(b, a));
+
+    // Start from the greedy solution: every removable leaf kept, nothing added
+    let mut search = Search {
+        items: &items,
+        max_weight: max_weight.saturating_sub(fixed_weight),
+        max_sigop_cost: max_sigop_cost.saturating_sub(fixed_sigop_cost),
+        taken: vec![false; items.len()],
+        best: items.iter().map(|item| is_removable[item.index]).collect(),
+        best_fee: 0,
+        nodes: 0,
+    };
+    search.best_fee = items
+        .iter()
+        .zip(&search.best)
+        .filter(|(_, taken)| **taken)
+        .map(|(item, _)| item.fee)
+        .sum();
+    search.explore(0, 0, 0, 0);
+
+    let taken: HashMap<usize, bool> = items
+        .iter()
+        .zip(&search.best)
+        .map(|(item, taken)| (item.index, *taken))
+        .collect();
+
+    // Keep the greedy order for what stays, then append the additions, whose
+    // parents are all already in the block
+    let mut selection: Vec<usize> = greedy
+        .iter()
+        .copied()
+        .filter(|index| taken.get(index).copied().unwrap_or(true))
+        .collect();
+    selection.extend(
+        items
+            .iter()
+            .filter(|item| !in_block[item.index] && taken[&item.index])
+            .map(|item| item.index),
+    );
+    selection
+}
+
+fn compare_fee_rate(a: &Item, b: &Item) -> std::cmp::Ordering {
+    (a.fee as u128 * b.weight as u128).cmp(&(b.fee as u128 * a.weight as u128))
+}
+
+// Depth-first branch and bound over items sorted by fee rate, bounded by the
+// fractional knapsack on weight
+struct Search<'a> {
+    items: &'a [Item],
+    max_weight: u64,
+    max_sigop_cost: u64,
+    taken: Vec<bool>,
+    best: Vec<bool>,
+    best_fee: u64,
+    nodes: usize,
+}
+
+impl Search<'_> {
+    fn explore(&mut self, depth: usize, fee: u64, weight: u64, sigop_cost: u64) {
+        self.nodes += 1;
+        if fee > self.best_fee {
+            self.best_fee = fee;
+            self.best.clone_from(&self.taken);
+        }
+        if depth == self.items.len()
+            || self.nodes > MAX_SEARCH_NODES
+            || self.upper_bound(depth, fee, weight) <= self.best_fee
+        {
+            return;
+        }
+
+        let item = &self.items[depth];
+        if weight + item.weight <= self.max_weight
+            && sigop_cost + item.sigop_cost <= self.max_sigop_cost
+        {
+            self.taken[depth] = true;
+            self.explore(
+                depth + 1,
+                fee + item.fee,
+                weight + item.weight,
+                sigop_cost + item.sigop_cost,
+            );
+            self.taken[depth] = false;
+        }
+        self.explore(depth + 1, fee, weight, sigop_cost);
+    }
+
+    // Fill the remaining weight with the next items in fee-rate order,
+    // taking a fraction of the first one that does not fit
+    fn upper_bound(&self, depth: usize, fee: u64, weight: u64) -> u64 {
+        let mut bound = fee;
+        let mut room = self.max_weight - weight;
+        for item in &self.items[depth..] {
+            if item.weight <= room {
+                bound += item.fee;
+                room -= item.weight;
+            } else {
+                bound += (item.fee as u128 * room as u128 / item.weight as u128) as u64;
+                break;
+            }
+        }
+        bound
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{MAX_BLOCK_SIGOPS_COST, select_transactions};
+
+    fn candidate(
+        id: usize,
+        fee: u64,
+        weight: u32,
+        sigop_cost: u64,
+        parents: &[usize],
+    ) -> ValidTransactions {
+        ValidTransactions {
+            id: id.to_string(),
+            hex: String::new(),
+            weight,
+            fee,
+            parents: parents.iter().map(|parent| parent.to_string()).collect(),
+            sigop_cost,
+            version: 2,
+            lock_time: 0,
+            sequences: vec![],
+            prevout_confirmations: vec![],
+        }
+    }
+
+    fn total_fee(txs: &[ValidTransactions], selection: &[usize]) -> u64 {
+        selection.iter().map(|index| txs[*index].fee).sum()
+    }
+
+    // Greedy then refined selection under the given limits
+    fn select(
+        txs: &[ValidTransactions],
+        max_weight: u32,
+        max_sigop_cost: u64,
+    ) -> (Vec<usize>, Vec<usize>) {
+        let eligible = vec![true; txs.len()];
+        let greedy = select_transactions(txs, &eligible, max_weight, max_sigop_cost);
+        let refined = refine_selection(txs, &eligible, &greedy, max_weight as u64, max_sigop_cost);
+        (greedy, refined)
+    }
+
+    #[test]
+    fn fills_space_greedy_leaves_empty() {
+        // Greedy takes the best rate first and then nothing else fits
+        let txs = [
+            candidate(0, 600, 501, 0, &[]),
+            candidate(1, 500, 500, 0, &[]),
+            candidate(2, 500, 500, 0, &[]),
+        ];
+        let (greedy, refined) = select(&txs, 1_000, MAX_BLOCK_SIGOPS_COST);
+        assert_eq!(greedy, vec![0]);
+        assert_eq!(refined, vec![1, 2]);
+    }
+
+    #[test]
+    fn refined_selection_keeps_the_block_valid() {
+        // A fixed linear congruential generator, so every run sees the same sets
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = |bound: u64| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) % bound
+        };
+
+        for _ in 0..50 {
+            let count = 10 + next(60) as usize;
+            let txs: Vec<ValidTransactions> = (0..count)
+                .map(|index| {
+                    let parents: Vec<usize> = (0..next(3))
+                        .filter(|_| index > 0)
+                        .map(|_| next(index as u64) as usize)
+                        .collect();
+                    candidate(
+                        index,
+                        100 + next(20_000),
+                        400 + next(4_000) as u32,
+                        next(40),
+                        &parents,
+                    )
+                })
+                .collect();
+            let max_weight = 5_000 + next(40_000) as u32;
+            let max_sigop_cost = 50 + next(400);
+
+            let (greedy, refined) = select(&txs, max_weight, max_sigop_cost);
+            assert!(total_fee(&txs, &refined) >= total_fee(&txs, &greedy));
+
+            for selection in [&greedy, &refined] {
+                let weight: u64 = selection
+                    .iter()
+                    .map(|index| txs[*index].weight as u64)
+                    .sum();
+                let sigop_cost: u64 = selection.iter().map(|index| txs[*index].sigop_cost).sum();
+                assert!(weight <= max_weight as u64);
+                assert!(sigop_cost <= max_sigop_cost);
+
+                // Every parent is in the block, ahead of its children
+                let position: HashMap<usize, usize> = selection
+                    .iter()
+                    .enumerate()
+                    .map(|(position, index)| (*index, position))
+                    .collect();
+                assert_eq!(position.len(), selection.len());
+                for (child, index) in selection.iter().enumerate() {
+                    for parent in &txs[*index].parents {
+                        let parent: usize = parent.parse().unwrap();
+                        assert!(position.get(&parent).is_some_and(|parent| *parent < child));
+                    }
+                }
+            }
+        }
+    }
+}
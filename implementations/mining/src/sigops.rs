@@ -0,0 +1,222 @@
+use bitcoincore_rpc::bitcoin::{Transaction, TxOut};
+
+use crate::interpreter::{p2sh_sigop_count, sigop_count, witness_sigop_count};
+
+// Consensus limit on the total signature operation cost of a block
+pub const MAX_BLOCK_SIGOPS_COST: u64 = 80_000;
+
+// Legacy and P2SH signature operations cost four times a witness one
+const WITNESS_SCALE_FACTOR: u64 = 4;
+
+// Sigop cost from scriptSigs and output scripts alone, which is all that can
+// be counted without the spent outputs
+pub fn legacy_sigop_cost(tx: &Transaction) -> u64 {
+    let inputs: u64 = tx
+        .input
+        .iter()
+        .map(|input| sigop_count(input.script_sig.as_bytes(), false))
+        .sum();
+    let outputs: u64 = tx
+        .output
+        .iter()
+        .map(|output| sigop_count(output.script_pubkey.as_bytes(), false))
+        .sum();
+    (inputs + outputs) * WITNESS_SCALE_FACTOR
+}
+
+// Total sigop cost of a transaction as Core's GetTransactionSigOpCost.
+// `prevouts` holds the outputs spent by each input and is ignored for a coinbase.
+pub fn transaction_sigop_cost(tx: &Transaction, prevouts: &[TxOut]) -> u64 {
+    let mut cost = legacy_sigop_cost(tx);
+    if tx.is_coinbase() {
+        return cost;
+    }
+
+    for (input, prevout) in tx.input.iter().zip(prevouts) {
+        let script_sig = input.script_sig.as_bytes();
+        let script_pubkey = prevout.script_pubkey.as_bytes();
+        cost += p2sh_sigop_count(script_sig, script_pubkey) * WITNESS_SCALE_FACTOR;
+        cost += witness_sigop_count(script_sig, script_pubkey, &input.witness);
+    }
+    cost
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        Amount, OutPoint, PubkeyHash, ScriptBuf, Sequence, TxIn, Txid, WPubkeyHash, Witness,
+        absolute::LockTime,
+        hashes::Hash,
+        opcodes::all::{OP_CHECKMULTISIG, OP_PUSHNUM_2, OP_PUSHNUM_3},
+        script::{Builder, PushBytes},
+        transaction,
+    };
+
+    use super::*;
+
+    struct Case {
+        name: &'static str,
+        coinbase: bool,
+        script_sig: ScriptBuf,
+        witness: Vec<Vec<u8>>,
+        // Output spent by the only input
+        prevout: ScriptBuf,
+        outputs: Vec<ScriptBuf>,
+        legacy: u64,
+        total: u64,
+    }
+
+    fn multisig_2_of_3() -> ScriptBuf {
+        Builder::new()
+            .push_opcode(OP_PUSHNUM_2)
+            .push_slice([2; 33])
+            .push_slice([3; 33])
+            .push_slice([4; 33])
+            .push_opcode(OP_PUSHNUM_3)
+            .push_opcode(OP_CHECKMULTISIG)
+            .into_script()
+    }
+
+    fn p2pkh() -> ScriptBuf {
+        ScriptBuf::new_p2pkh(&PubkeyHash::from_byte_array([1; 20]))
+    }
+
+    fn p2wpkh() -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&WPubkeyHash::from_byte_array([1; 20]))
+    }
+
+    // OP_0 <sig> <sig> <redeem script>
+    fn p2sh_multisig_script_sig() -> ScriptBuf {
+        let redeem_script = multisig_2_of_3();
+        Builder::new()
+            .push_int(0)
+            .push_slice([0x30; 72])
+            .push_slice([0x30; 72])
+            .push_slice(<&PushBytes>::try_from(redeem_script.as_bytes()).unwrap())
+            .into_script()
+    }
+
+    fn transaction(case: &Case) -> Transaction {
+        let previous_output = if case.coinbase {
+            OutPoint::null()
+        } else {
+            OutPoint::new(Txid::from_byte_array([1; 32]), 0)
+        };
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: case.script_sig.clone(),
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&case.witness),
+            }],
+            output: case
+                .outputs
+                .iter()
+                .map(|script_pubkey| TxOut {
+                    value: Amount::from_sat(1_000),
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn sigop_costs() {
+        let cases = [
+            Case {
+                name: "P2PKH output",
+                coinbase: false,
+                script_sig: ScriptBuf::new(),
+                witness: vec![vec![0x30; 72], vec![2; 33]],
+                prevout: p2wpkh(),
+                outputs: vec![p2pkh()],
+                legacy: 4,
+                total: 4 + 1,
+            },
+            Case {
+                // Counted as 20 keys, without looking at the OP_3
+                name: "bare 2-of-3 multisig output",
+                coinbase: false,
+                script_sig: ScriptBuf::new(),
+                witness: vec![vec![0x30; 72], vec![2; 33]],
+                prevout: p2wpkh(),
+                outputs: vec![multisig_2_of_3()],
+                legacy: 80,
+                total: 80 + 1,
+            },
+            Case {
+                // The redeem script counts its 3 keys accurately
+                name: "P2SH 2-of-3 multisig spend",
+                coinbase: false,
+                script_sig: p2sh_multisig_script_sig(),
+                witness: vec![],
+                prevout: ScriptBuf::new_p2sh(&multisig_2_of_3().script_hash()),
+                outputs: vec![p2wpkh()],
+                legacy: 0,
+                total: 3 * 4,
+            },
+            Case {
+                name: "P2WPKH spend",
+                coinbase: false,
+                script_sig: ScriptBuf::new(),
+                witness: vec![vec![0x30; 72], vec![2; 33]],
+                prevout: p2wpkh(),
+                outputs: vec![p2wpkh()],
+                legacy: 0,
+                total: 1,
+            },
+            Case {
+                name: "P2WSH 2-of-3 multisig spend",
+                coinbase: false,
+                script_sig: ScriptBuf::new(),
+                witness: vec![
+                    vec![],
+                    vec![0x30; 72],
+                    vec![0x30; 72],
+                    multisig_2_of_3().into_bytes(),
+                ],
+                prevout: ScriptBuf::new_p2wsh(&multisig_2_of_3().wscript_hash()),
+                outputs: vec![p2wpkh()],
+                legacy: 0,
+                total: 3,
+            },
+            Case {
+                name: "coinbase shaped like a P2SH spend",
+                coinbase: true,
+                script_sig: p2sh_multisig_script_sig(),
+                witness: vec![],
+                prevout: ScriptBuf::new_p2sh(&multisig_2_of_3().script_hash()),
+                outputs: vec![p2pkh()],
+                legacy: 4,
+                total: 4,
+            },
+            Case {
+                name: "coinbase shaped like a P2WSH spend",
+                coinbase: true,
+                script_sig: ScriptBuf::from_bytes(vec![0x01, 0xc8]),
+                witness: vec![multisig_2_of_3().into_bytes()],
+                prevout: ScriptBuf::new_p2wsh(&multisig_2_of_3().wscript_hash()),
+                outputs: vec![p2pkh()],
+                legacy: 4,
+                total: 4,
+            },
+        ];
+
+        for case in cases {
+            let tx = transaction(&case);
+            let prevouts = [TxOut {
+                value: Amount::from_sat(10_000),
+                script_pubkey: case.prevout.clone(),
+            }];
+            assert_eq!(legacy_sigop_cost(&tx), case.legacy, "{}", case.name);
+            assert_eq!(
+                transaction_sigop_cost(&tx, &prevouts),
+                case.total,
+                "{}",
+                case.name
+            );
+        }
+    }
+}
@@ -0,0 +1,730 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    io::{BufRead, BufReader, Write},
+    mem,
+    net::{TcpListener, TcpStream},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+use bitcoincore_rpc::bitcoin::{
+    Block, BlockHash, CompactTarget, Target, Transaction, Txid, VarInt, Witness,
+    block::{Header, Version},
+    consensus,
+    hashes::{Hash, sha256d},
+};
+use serde_json::{Value, json};
+
+use crate::{
+    EXTRANONCE_SIZE,
+    chain::{self, ChainState},
+    extranonce_offset, merkle,
+};
+
+// The pool's part of the coinbase extranonce, unique per connection; miners
+// roll the remaining bytes
+const EXTRANONCE1_SIZE: usize = 4;
+const EXTRANONCE2_SIZE: usize = EXTRANONCE_SIZE - EXTRANONCE1_SIZE;
+
+// Shares expected per block when no share difficulty is given
+const DEFAULT_SHARES_PER_BLOCK: f64 = 64.0;
+
+// How long a write to a miner may block before the miner is dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Stratum error codes
+const ERROR_OTHER: i64 = 20;
+const ERROR_JOB_NOT_FOUND: i64 = 21;
+const ERROR_DUPLICATE_SHARE: i64 = 22;
+const ERROR_LOW_DIFFICULTY: i64 = 23;
+const ERROR_UNAUTHORIZED: i64 = 24;
+const ERROR_NOT_SUBSCRIBED: i64 = 25;
+
+// Work handed to miners: the template's coinbase split around the extranonce
+// and the merkle branch that hashes its txid up to the root
+struct Job {
+    version: Version,
+    previous_hash: BlockHash,
+    bits: CompactTarget,
+    ntime: u32,
+    // Coinbase serialized without its witness, before and after the extranonce
+    coinbase1: Vec<u8>,
+    coinbase2: Vec<u8>,
+    merkle_branch: merkle::MerkleProof,
+    // Restored on the coinbase when a share solves the block
+    coinbase_witness: Witness,
+    transactions: Vec<Transaction>,
+    // Extranonce1, extranonce2, ntime and nonce of every share submitted for the job
+    shares: HashSet<(u32, Vec<u8>, u32, u32)>,
+}
+
+#[derive(Debug, Default)]
+struct WorkerStats {
+    accepted: u64,
+    rejected: u64,
+    // Sum of the difficulty of accepted shares
+    work: f64,
+    blocks: u64,
+}
+
+struct Client {
+    // Shared with the threads writing to it outside the pool lock
+    stream: Arc<Mutex<TcpStream>>,
+    // Whether to send this client new jobs
+    notify: bool,
+}
+
+struct Pool<'a> {
+    chain: ChainState<'a>,
+    jobs: HashMap<String, Job>,
+    current_job: String,
+    next_job_id: u64,
+    clients: HashMap<u32, Client>,
+    workers: BTreeMap<String, WorkerStats>,
+    // Messages queued under the lock and written once it is released
+    outbox: Vec<(u32, Arc<Mutex<TcpStream>>, String)>,
+}
+
+// A Stratum V1 pool over the block assembler. Every connection gets its own
+// extranonce1, shares are checked against the share target, and a share that
+// also meets the block target is assembled into a block and, if valid,
+// becomes the new tip, after which all miners are sent a fresh job.
+pub struct StratumServer<'a> {
+    share_difficulty: f64,
+    share_target: Target,
+    next_extranonce1: AtomicU32,
+    pool: Mutex<Pool<'a>>,
+}
+
+impl<'a> StratumServer<'a> {
+    // `share_difficulty` is relative to the difficulty 1 target and defaults
+    // to a fraction of the block difficulty; shares are never made harder than
+    // the block target
+    pub fn new(chain: ChainState<'a>, share_difficulty: Option<f64>) -> StratumServer<'a> {
+        let block_target = Target::from_compact(chain.params.bits);
+        let share_difficulty =
+            share_difficulty.unwrap_or(block_target.difficulty_float() / DEFAULT_SHARES_PER_BLOCK);
+        let share_target = difficulty_target(share_difficulty).max(block_target);
+        let mut pool = Pool {
+            chain,
+            jobs: HashMap::new(),
+            current_job: String::new(),
+            next_job_id: 0,
+            clients: HashMap::new(),
+            workers: BTreeMap::new(),
+            outbox: Vec::new(),
+        };
+        pool.new_job().expect("msg: Failed to build block template");
+        StratumServer {
+            share_difficulty: share_target.difficulty_float(),
+            share_target,
+            next_extranonce1: AtomicU32::new(0),
+            pool: Mutex::new(pool),
+        }
+    }
+
+    // Accept miners on `addr` until the process exits, one thread per connection
+    pub fn serve(&self, addr: &str) -> Result<(), String> {
+        let listener =
+            TcpListener::bind(addr).map_err(|e| format!("Failed to bind {}: {}", addr, e))?;
+        println!(
+            "msg: Serving Stratum on {} with share difficulty {} (target {:x})",
+            listener.local_addr().map_err(|e| e.to_string())?,
+            self.share_difficulty,
+            self.share_target
+        );
+
+        thread::scope(|scope| {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        scope.spawn(move || {
+                            if let Err(e) = self.handle_connection(stream) {
+                                println!("msg: Connection error: {}", e);
+                            }
+                        });
+                    }
+                    Err(e) => println!("msg: Failed to accept connection: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    // Answer one miner's line-delimited JSON requests until it disconnects
+    fn handle_connection(&self, stream: TcpStream) -> Result<(), String> {
+        let extranonce1 = self.next_extranonce1.fetch_add(1, Ordering::Relaxed);
+        let reader = BufReader::new(stream.try_clone().map_err(|e| e.to_string())?);
+        stream
+            .set_write_timeout(Some(WRITE_TIMEOUT))
+            .map_err(|e| e.to_string())?;
+        let stream = Arc::new(Mutex::new(stream));
+        self.with_pool(|pool| {
+            pool.clients.insert(
+                extranonce1,
+                Client {
+                    stream,
+                    notify: false,
+                },
+            )
+        });
+
+        let mut subscribed = false;
+        let mut authorized: HashSet<String> = HashSet::new();
+        for line in reader.lines() {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let request: Value = match serde_json::from_str(&line) {
+                Ok(request) => request,
+                Err(e) => {
+                    let reply = error_reply(&Value::Null, ERROR_OTHER, &e.to_string());
+                    self.with_pool(|pool| pool.send(extranonce1, &reply));
+                    continue;
+                }
+            };
+            let id = &request["id"];
+            let params = request["params"].as_array().cloned().unwrap_or_default();
+
+            self.with_pool(
+                |pool| match request["method"].as_str().unwrap_or_default() {
+                    "mining.subscribe" => {
+                        subscribed = true;
+                        let subscription = format!("{:08x}", extranonce1);
+                        let reply = json!({
+                            "id": id,
+                            "result": [
+                                [
+                                    ["mining.set_difficulty", subscription],
+                                    ["mining.notify", subscription],
+                                ],
+                                hex::encode(extranonce1.to_be_bytes()),
+                                EXTRANONCE2_SIZE,
+                            ],
+                            "error": null,
+                        });
+                        pool.send(extranonce1, &reply);
+                    }
+                    "mining.authorize" => {
+                        let worker = params.first().and_then(Value::as_str).unwrap_or_default();
+                        if !subscribed {
+                            let reply = error_reply(id, ERROR_NOT_SUBSCRIBED, "Not subscribed");
+                            pool.send(extranonce1, &reply);
+                            return;
+                        }
+                        authorized.insert(worker.to_string());
+                        pool.workers.entry(worker.to_string()).or_default();
+                        pool.send(
+                            extranonce1,
+                            &json!({ "id": id, "result": true, "error": null }),
+                        );
+
+                        // Start the miner on the current job
+                        let set_difficulty = json!({
+                            "id": null,
+                            "method": "mining.set_difficulty",
+                            "params": [self.share_difficulty],
+                        });
+                        pool.send(extranonce1, &set_difficulty);
+                        let notify = pool.notify(true);
+                        pool.send(extranonce1, &notify);
+                        if let Some(client) = pool.clients.get_mut(&extranonce1) {
+                            client.notify = true;
+                        }
+                    }
+                    "mining.submit" => {
+                        let worker = params.first().and_then(Value::as_str).unwrap_or_default();
+                        let reply = if !authorized.contains(worker) {
+                            error_reply(id, ERROR_UNAUTHORIZED, "Unauthorized worker")
+                        } else {
+                            let result = self.submit(pool, extranonce1, &params);
+                            let stats = pool.workers.entry(worker.to_string()).or_default();
+                            match &result {
+                                Ok(solved) => {
+                                    stats.accepted += 1;
+                                    stats.work += self.share_difficulty;
+                                    stats.blocks += *solved as u64;
+                                }
+                                Err(_) => stats.rejected += 1,
+                            }
+                            match result {
+                                Ok(solved) => {
+                                    if solved {
+                                        println!("msg: Block found by {}", worker);
+                                        pool.report_workers();
+                                        match pool.new_job() {
+                                            Ok(()) => pool.broadcast_job(),
+                                            Err(e) => println!(
+                                                "msg: Failed to build the next template: {}",
+                                                e
+                                            ),
+                                        }
+                                    }
+                                    json!({ "id": id, "result": true, "error": null })
+                                }
+                                Err((code, message)) => {
+                                    println!("msg: Rejected share from {}: {}", worker, message);
+                                    error_reply(id, code, &message)
+                                }
+                            }
+                        };
+                        pool.send(extranonce1, &reply);
+                    }
+                    "mining.extranonce.subscribe" => {
+                        pool.send(
+                            extranonce1,
+                            &json!({ "id": id, "result": false, "error": null }),
+                        );
+                    }
+                    method => {
+                        let reply =
+                            error_reply(id, ERROR_OTHER, &format!("Unknown method: {}", method));
+                        pool.send(extranonce1, &reply);
+                    }
+                },
+            );
+        }
+
+        self.pool.lock().unwrap().clients.remove(&extranonce1);
+        Ok(())
+    }
+
+    // Run `f` under the pool lock, then write the messages it queued after
+    // releasing it so a miner that stops reading cannot stall the others
+    fn with_pool<T>(&self, f: impl FnOnce(&mut Pool<'a>) -> T) -> T {
+        let (result, outbox) = {
+            let mut pool = self.pool.lock().unwrap();
+            let result = f(&mut pool);
+            (result, mem::take(&mut pool.outbox))
+        };
+        let failed: Vec<u32> = outbox
+            .into_iter()
+            .filter(|(_, stream, message)| writeln!(stream.lock().unwrap(), "{}", message).is_err())
+            .map(|(extranonce1, _, _)| extranonce1)
+            .collect();
+        if !failed.is_empty() {
+            let mut pool = self.pool.lock().unwrap();
+            for extranonce1 in failed {
+                pool.clients.remove(&extranonce1);
+            }
+        }
+        result
+    }
+
+    // Check a share: [worker, job id, extranonce2, ntime, nonce]. Returns
+    // whether it solved the block, which is then accepted onto the chain.
+    fn submit(
+        &self,
+        pool: &mut Pool,
+        extranonce1: u32,
+        params: &[Value],
+    ) -> Result<bool, (i64, String)> {
+        let field = |index: usize, name: &str| {
+            params
+                .get(index)
+                .and_then(Value::as_str)
+                .ok_or((ERROR_OTHER, format!("Missing {}", name)))
+        };
+        let job_id = field(1, "job id")?;
+        let extranonce2 = hex::decode(field(2, "extranonce2")?)
+            .ok()
+            .filter(|bytes| bytes.len() == EXTRANONCE2_SIZE)
+            .ok_or((ERROR_OTHER, "Invalid extranonce2".to_string()))?;
+        let ntime = parse_u32(field(3, "ntime")?)?;
+        let nonce = parse_u32(field(4, "nonce")?)?;
+        // Shares may roll ntime anywhere a block could have its time
+        let time_check = pool.chain.headers.check_time(ntime, chain::unix_time());
+
+        let job = pool
+            .jobs
+            .get_mut(job_id)
+            .ok_or((ERROR_JOB_NOT_FOUND, "Job not found".to_string()))?;
+        if let Err(reason) = time_check {
+            return Err((ERROR_OTHER, format!("ntime out of range ({})", reason)));
+        }
+        if !job
+            .shares
+            .insert((extranonce1, extranonce2.clone(), ntime, nonce))
+        {
+            return Err((ERROR_DUPLICATE_SHARE, "Duplicate share".to_string()));
+        }
+
+        let coinbase_bytes = [
+            job.coinbase1.as_slice(),
+            &extranonce1.to_be_bytes(),
+            &extranonce2,
+            &job.coinbase2,
+        ]
+        .concat();
+        let coinbase_txid = Txid::from_raw_hash(sha256d::Hash::hash(&coinbase_bytes));
+        let header = Header {
+            version: job.version,
+            prev_blockhash: job.previous_hash,
+            merkle_root: job.merkle_branch.root(coinbase_txid),
+            time: ntime,
+            bits: job.bits,
+            nonce,
+        };
+        let hash = header.block_hash();
+        if !self.share_target.is_met_by(hash) {
+            return Err((ERROR_LOW_DIFFICULTY, "Low difficulty share".to_string()));
+        }
+        if !Target::from_compact(job.bits).is_met_by(hash) {
+            return Ok(false);
+        }
+
+        let mut coinbase_tx: Transaction = consensus::deserialize(&coinbase_bytes)
+            .map_err(|e| (ERROR_OTHER, format!("Invalid coinbase: {}", e)))?;
+        coinbase_tx.input[0].witness = job.coinbase_witness.clone();
+        let mut txdata = vec![coinbase_tx];
+        txdata.extend(job.transactions.iter().cloned());
+        let block = Block { header, txdata };
+
+        match pool.chain.check_block(&block, true) {
+            Ok(()) => {
+                pool.chain.accept_block(&block);
+                Ok(true)
+            }
+            // The share still counts even though the block does not
+            Err(reason) => {
+                println!("msg: Solved block {} is invalid: {}", hash, reason);
+                Ok(false)
+            }
+        }
+    }
+}
+
+impl Pool<'_> {
+    // Replace all jobs with one for the current template
+    fn new_job(&mut self) -> Result<(), String> {
+        let template = self.chain.template()?;
+        let coinbase_tx = &template.block.txdata[0];
+        let mut stripped = coinbase_tx.clone();
+        stripped.input[0].witness = Witness::new();
+        let serialized = consensus::serialize(&stripped);
+
+        // Version, input count, outpoint and scriptSig length come before the scriptSig
+        let script_sig = &coinbase_tx.input[0].script_sig;
+        let script_start = 4 + 1 + 36 + VarInt(script_sig.len() as u64).size();
+        let extranonce_start = script_start + extranonce_offset(script_sig);
+
+        // The coinbase path never uses the coinbase txid itself, so the
+        // extranonce does not change the branch
+        let txids: Vec<Txid> = template
+            .block
+            .txdata
+            .iter()
+            .map(|tx| tx.compute_txid())
+            .collect();
+        let now = chain::unix_time();
+
+        let job = Job {
+            version: template.block.header.version,
+            previous_hash: template.block.header.prev_blockhash,
+            bits: template.block.header.bits,
+            ntime: now.max(template.block.header.time),
+            coinbase1: serialized[..extranonce_start].to_vec(),
+            coinbase2: serialized[extranonce_start + EXTRANONCE_SIZE..].to_vec(),
+            merkle_branch: merkle::merkle_proof(&txids, 0).expect("msg: Template has a coinbase"),
+            coinbase_witness: coinbase_tx.input[0].witness.clone(),
+            transactions: template.block.txdata[1..].to_vec(),
+            shares: HashSet::new(),
+        };
+
+        self.next_job_id += 1;
+        self.current_job = format!("{:x}", self.next_job_id);
+        self.jobs.clear();
+        self.jobs.insert(self.current_job.clone(), job);
+        Ok(())
+    }
+
+    // mining.notify for the current job
+    fn notify(&self, clean_jobs: bool) -> Value {
+        let job = &self.jobs[&self.current_job];
+        // Stratum sends the previous hash as eight 4-byte words, each byte-reversed
+        let previous_hash: Vec<u8> = job
+            .previous_hash
+            .to_byte_array()
+            .chunks(4)
+            .flat_map(|word| word.iter().rev().copied())
+            .collect();
+        let merkle_branch: Vec<String> = job
+            .merkle_branch
+            .siblings
+            .iter()
+            .map(|hash| hex::encode(hash.to_byte_array()))
+            .collect();
+        json!({
+            "id": null,
+            "method": "mining.notify",
+            "params": [
+                self.current_job,
+                hex::encode(previous_hash),
+                hex::encode(&job.coinbase1),
+                hex::encode(&job.coinbase2),
+                merkle_branch,
+                format!("{:08x}", job.version.to_consensus()),
+                format!("{:08x}", job.bits.to_consensus()),
+                format!("{:08x}", job.ntime),
+                clean_jobs,
+            ],
+        })
+    }
+
+    fn broadcast_job(&mut self) {
+        let notify = self.notify(true);
+        let clients: Vec<u32> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| client.notify)
+            .map(|(extranonce1, _)| *extranonce1)
+            .collect();
+        for extranonce1 in clients {
+            self.send(extranonce1, &notify);
+        }
+    }
+
+    fn send(&mut self, extranonce1: u32, message: &Value) {
+        if let Some(client) = self.clients.get(&extranonce1) {
+            self.outbox
+                .push((extranonce1, Arc::clone(&client.stream), message.to_string()));
+        }
+    }
+
+    fn report_workers(&self) {
+        for (worker, stats) in &self.workers {
+            println!(
+                "msg: Worker {}: {} accepted, {} rejected, {} work, {} blocks",
+                worker, stats.accepted, stats.rejected, stats.work, stats.blocks
+            );
+        }
+    }
+}
+
+fn error_reply(id: &Value, code: i64, message: &str) -> Value {
+    json!({ "id": id, "result": null, "error": [code, message, null] })
+}
+
+fn parse_u32(hex: &str) -> Result<u32, (i64, String)> {
+    u32::from_str_radix(hex, 16).map_err(|_| (ERROR_OTHER, format!("Invalid hex {}", hex)))
+}
+
+// Target for a share difficulty, where difficulty 1 is 0x1d00ffff
+fn difficulty_target(difficulty: f64) -> Target {
+    // Difficulty 1 is 0xffff * 256^26; scale the mantissa and renormalize it
+    // into the 3-byte compact form
+    let mut mantissa = 0xffff as f64 / difficulty;
+    let mut exponent = 29;
+    while mantissa > 0x7fffff as f64 {
+        mantissa /= 256.0;
+        exponent += 1;
+    }
+    while mantissa < 0x8000 as f64 && exponent > 3 {
+        mantissa *= 256.0;
+        exponent -= 1;
+    }
+    if exponent > 32 {
+        return Target::from_be_bytes([0xff; 32]);
+    }
+    Target::from_compact(CompactTarget::from_consensus(
+        (exponent << 24) | mantissa as u32,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoincore_rpc::bitcoin::{
+        Address, Amount, OutPoint, ScriptBuf, Sequence, TxIn, TxMerkleNode, TxOut,
+        absolute::LockTime, transaction,
+    };
+
+    use super::*;
+    use crate::{
+        BlockParams, ValidTransactions, difficulty::Network, selection::SelectionStrategy,
+    };
+
+    const HEIGHT: u32 = 200;
+
+    fn op_true() -> ScriptBuf {
+        ScriptBuf::from_bytes(vec![0x51])
+    }
+
+    // A regtest chain one block below HEIGHT whose pool holds a single
+    // transaction spending an OP_TRUE output
+    fn chain_state(next_bits: chain::NextBits<'_>) -> ChainState<'_> {
+        let now = chain::unix_time();
+        let tip = Header {
+            version: Version::from_consensus(4),
+            prev_blockhash: BlockHash::all_zeros(),
+            merkle_root: TxMerkleNode::all_zeros(),
+            time: now - 600,
+            bits: CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        };
+        let headers = chain::HeaderChain::new(HEIGHT - 1, vec![tip]);
+
+        let spent = OutPoint::new(Txid::from_byte_array([1; 32]), 0);
+        let prevout = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2wsh(&op_true().wscript_hash()),
+        };
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: spent,
+                sequence: Sequence::MAX,
+                witness: Witness::from_slice(&[op_true().into_bytes()]),
+                ..TxIn::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(90_000),
+                script_pubkey: prevout.script_pubkey.clone(),
+            }],
+        };
+        let valid_tx = ValidTransactions {
+            id: tx.compute_txid().to_string(),
+            hex: consensus::encode::serialize_hex(&tx),
+            weight: tx.weight().to_wu() as u32,
+            fee: 10_000,
+            parents: vec![spent.txid.to_string()],
+            sigop_cost: 0,
+            version: 2,
+            lock_time: 0,
+            sequences: vec![Sequence::MAX.0],
+            prevout_confirmations: vec![None],
+        };
+
+        let params = BlockParams {
+            miner_address: Address::from_str("bcrt1qw508d6qejxtdg4y5r3zarvary0c5xw7kygt080")
+                .unwrap()
+                .assume_checked(),
+            previous_hash: tip.block_hash(),
+            height: HEIGHT,
+            time: now,
+            median_time_past: tip.time,
+            bits: tip.bits,
+            miner_tag: b"test",
+            network: Network::Regtest,
+        };
+        ChainState::new(
+            vec![valid_tx],
+            HashMap::from([(spent, prevout)]),
+            params,
+            headers,
+            SelectionStrategy::Greedy,
+            next_bits,
+        )
+        .unwrap()
+    }
+
+    // The header a miner builds for a share on `job`
+    fn share_header(job: &Job, extranonce1: u32, extranonce2: &[u8], nonce: u32) -> Header {
+        let coinbase_bytes = [
+            job.coinbase1.as_slice(),
+            &extranonce1.to_be_bytes(),
+            extranonce2,
+            &job.coinbase2,
+        ]
+        .concat();
+        let coinbase_txid = Txid::from_raw_hash(sha256d::Hash::hash(&coinbase_bytes));
+        Header {
+            version: job.version,
+            prev_blockhash: job.previous_hash,
+            merkle_root: job.merkle_branch.root(coinbase_txid),
+            time: job.ntime,
+            bits: job.bits,
+            nonce,
+        }
+    }
+
+    fn share(job_id: &str, extranonce2: &[u8], ntime: u32, nonce: u32) -> Vec<Value> {
+        vec![
+            json!("worker"),
+            json!(job_id),
+            json!(hex::encode(extranonce2)),
+            json!(format!("{:08x}", ntime)),
+            json!(format!("{:08x}", nonce)),
+        ]
+    }
+
+    #[test]
+    fn difficulty_one_is_the_genesis_target() {
+        assert_eq!(
+            difficulty_target(1.0).to_compact_lossy(),
+            CompactTarget::from_consensus(0x1d00ffff)
+        );
+        assert_eq!(
+            difficulty_target(2.0).to_compact_lossy(),
+            CompactTarget::from_consensus(0x1c7fff80)
+        );
+    }
+
+    #[test]
+    fn solved_share_extends_the_chain() {
+        let bits = |_: &chain::HeaderChain, _| Ok(CompactTarget::from_consensus(0x207fffff));
+        // Shares are never harder than the block, so difficulty 1 clamps to
+        // the regtest target, which about half of all hashes meet
+        let server = StratumServer::new(chain_state(&bits), Some(1.0));
+        let mut pool = server.pool.lock().unwrap();
+        let job_id = pool.current_job.clone();
+        let extranonce1 = 7;
+        let extranonce2 = [0u8; EXTRANONCE2_SIZE];
+
+        let job = &pool.jobs[&job_id];
+        let ntime = job.ntime;
+        let target = Target::from_compact(job.bits);
+        let meets = |nonce: &u32| {
+            let header = share_header(job, extranonce1, &extranonce2, *nonce);
+            target.is_met_by(header.block_hash())
+        };
+        let low = (0..).find(|nonce| !meets(nonce)).unwrap();
+        let solved = (0..).find(meets).unwrap();
+        let header = share_header(job, extranonce1, &extranonce2, solved);
+
+        assert_eq!(
+            server.submit(
+                &mut pool,
+                extranonce1,
+                &share("ff", &extranonce2, ntime, solved)
+            ),
+            Err((ERROR_JOB_NOT_FOUND, "Job not found".to_string()))
+        );
+        assert_eq!(
+            server.submit(
+                &mut pool,
+                extranonce1,
+                &share(&job_id, &extranonce2, ntime, low)
+            ),
+            Err((ERROR_LOW_DIFFICULTY, "Low difficulty share".to_string()))
+        );
+        // Shares are deduplicated before their difficulty is checked
+        assert_eq!(
+            server.submit(
+                &mut pool,
+                extranonce1,
+                &share(&job_id, &extranonce2, ntime, low)
+            ),
+            Err((ERROR_DUPLICATE_SHARE, "Duplicate share".to_string()))
+        );
+        assert_eq!(
+            server.submit(
+                &mut pool,
+                extranonce1,
+                &share(&job_id, &extranonce2, ntime, solved)
+            ),
+            Ok(true)
+        );
+        // The block passed check_block and is now the tip, holding the pool's transaction
+        assert_eq!(pool.chain.headers.tip(), Some(&header));
+        assert_eq!(pool.chain.params.height, HEIGHT + 1);
+        assert!(pool.chain.valid_txs.is_empty());
+    }
+}
@@ -0,0 +1,629 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+use bitcoincore_rpc::bitcoin::{Amount, OutPoint, ScriptBuf, Transaction, TxOut, Txid, consensus};
+
+use crate::{
+    MempoolTransaction,
+    conflicts::{Candidate, ConflictRule, resolve_conflicts},
+    interpreter::{self, ScriptError},
+    policy::{self, PolicyViolation},
+    sigops,
+};
+
+// A transaction that passed validation, with the numbers selection relies on
+// computed from its hex and prevouts rather than taken from the JSON
+#[derive(Debug, Clone)]
+pub struct VerifiedTransaction {
+    pub tx: Transaction,
+    pub fee: u64,
+    pub weight: u64,
+    pub sigop_cost: u64,
+    // JSON fields that disagree with the computed values
+    pub discrepancies: Vec<Discrepancy>,
+    // Relay policy rules it breaks; empty for a standard transaction
+    pub policy_violations: Vec<PolicyViolation>,
+}
+
+// A declared JSON value that differs from the one derived from the hex
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    Fee { declared: u64, computed: u64 },
+    Weight { declared: u32, computed: u64 },
+}
+
+impl fmt::Display for Discrepancy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Discrepancy::Fee { declared, computed } => {
+                write!(f, "declared fee {}, computed {}", declared, computed)
+            }
+            Discrepancy::Weight { declared, computed } => {
+                write!(f, "declared weight {}, computed {}", declared, computed)
+            }
+        }
+    }
+}
+
+// Why a mempool transaction was rejected, judged against its own JSON data and
+// the rest of the mempool set
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    // The hex is missing or does not decode, or disagrees with the JSON fields
+    Malformed(String),
+    TxidMismatch { declared: String, computed: Txid },
+    // Outputs spend more than the prevouts provide
+    InsufficientInputValue { input_value: u64, output_value: u64 },
+    // An input's scriptSig/witness fails to satisfy the prevout script
+    BadSignature { input: usize, error: ScriptError },
+    // Breaks relay policy, rejected only when standardness is required
+    NonStandard(PolicyViolation),
+    // Lost a conflict over an outpoint to another transaction in the set
+    DoubleSpend { outpoint: OutPoint, spent_by: Txid },
+    // Spends an output of a transaction in the set that was itself rejected
+    InvalidParent(Txid),
+}
+
+impl Verdict {
+    // Short label used when summarising rejections
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Verdict::Malformed(_) => "malformed",
+            Verdict::TxidMismatch { .. } => "txid mismatch",
+            Verdict::InsufficientInputValue { .. } => "insufficient input value",
+            Verdict::BadSignature { .. } => "bad signature",
+            Verdict::NonStandard(_) => "non-standard",
+            Verdict::DoubleSpend { .. } => "double spend",
+            Verdict::InvalidParent(_) => "invalid parent",
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Malformed(reason) => write!(f, "malformed: {}", reason),
+            Verdict::TxidMismatch { declared, computed } => {
+                write!(
+                    f,
+                    "txid mismatch: declared {}, computed {}",
+                    declared, computed
+                )
+            }
+            Verdict::InsufficientInputValue {
+                input_value,
+                output_value,
+            } => write!(
+                f,
+                "outputs ({} sats) exceed inputs ({} sats)",
+                output_value, input_value
+            ),
+            Verdict::BadSignature { input, error } => {
+                write!(f, "script failure on input {}: {}", input, error)
+            }
+            Verdict::NonStandard(violation) => {
+                write!(f, "non-standard: {} ({})", violation.reason(), violation)
+            }
+            Verdict::DoubleSpend { outpoint, spent_by } => {
+                write!(
+                    f,
+                    "double spend of {} (conflict won by {})",
+                    outpoint, spent_by
+                )
+            }
+            Verdict::InvalidParent(parent) => write!(f, "spends rejected parent {}", parent),
+        }
+    }
+}
+
+// Validate every transaction in the set, returning one result per transaction
+// in the same order. With `require_standard`, transactions breaking relay
+// policy are rejected before conflicts are resolved; otherwise they are only
+// annotated. Double spends are resolved with `conflict_rule`, and rejections
+// propagate to in-set descendants.
+pub fn validate_transactions(
+    txs: &[MempoolTransaction],
+    conflict_rule: ConflictRule,
+    require_standard: bool,
+) -> Vec<Result<VerifiedTransaction, Verdict>> {
+    let mut results: Vec<Result<VerifiedTransaction, Verdict>> =
+        txs.iter().map(check_transaction).collect();
+    if require_standard {
+        for result in &mut results {
+            let violation = result
+                .as_ref()
+                .ok()
+                .and_then(|verified| verified.policy_violations.first().cloned());
+            if let Some(violation) = violation {
+                *result = Err(Verdict::NonStandard(violation));
+            }
+        }
+    }
+
+    // Resolve double spends among the transactions that passed on their own
+    let checked: Vec<(usize, &VerifiedTransaction)> = results
+        .iter()
+        .enumerate()
+        .filter_map(|(index, result)| result.as_ref().ok().map(|verified| (index, verified)))
+        .collect();
+    let candidates: Vec<Candidate> = checked
+        .iter()
+        .map(|(index, verified)| Candidate {
+            position: *index,
+            fee: verified.fee,
+            weight: verified.weight,
+            outpoints: verified
+                .tx
+                .input
+                .iter()
+                .map(|input| input.previous_output)
+                .collect(),
+        })
+        .collect();
+    let mut losers = Vec::new();
+    for group in resolve_conflicts(&candidates, conflict_rule) {
+        let kept: Vec<String> = group
+            .winners
+            .iter()
+            .map(|winner| txs[checked[*winner].0].txid.clone())
+            .collect();
+        println!(
+            "msg: Resolved conflict between {} transactions by {}, kept {}",
+            group.members.len(),
+            conflict_rule,
+            kept.join(", ")
+        );
+        for (loser, outpoint, winner) in group.losers {
+            losers.push((
+                checked[loser].0,
+                Verdict::DoubleSpend {
+                    outpoint,
+                    spent_by: checked[winner].1.tx.compute_txid(),
+                },
+            ));
+        }
+    }
+    for (index, verdict) in losers {
+        results[index] = Err(verdict);
+    }
+
+    // Reject descendants of rejected transactions until nothing changes
+    let index_by_txid: HashMap<&str, usize> = txs
+        .iter()
+        .enumerate()
+        .map(|(index, tx)| (tx.txid.as_str(), index))
+        .collect();
+    loop {
+        let rejected: HashSet<usize> = results
+            .iter()
+            .enumerate()
+            .filter(|(_, result)| result.is_err())
+            .map(|(index, _)| index)
+            .collect();
+        let mut changed = false;
+        for (index, tx_data) in txs.iter().enumerate() {
+            if results[index].is_err() {
+                continue;
+            }
+            let rejected_parent = tx_data.vin.iter().find(|vin| {
+                index_by_txid
+                    .get(vin.txid.as_str())
+                    .is_some_and(|parent| rejected.contains(parent))
+            });
+            if let Some(vin) = rejected_parent {
+                let parent = vin.txid.parse().expect("msg: Parent txid already decoded");
+                results[index] = Err(Verdict::InvalidParent(parent));
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    results
+}
+
+fn decode(tx_data: &MempoolTransaction) -> Result<Transaction, Verdict> {
+    let hex = tx_data
+        .hex
+        .as_ref()
+        .ok_or_else(|| Verdict::Malformed("missing hex".to_string()))?;
+    let bytes = hex::decode(hex).map_err(|e| Verdict::Malformed(format!("invalid hex: {}", e)))?;
+    consensus::deserialize(&bytes)
+        .map_err(|e| Verdict::Malformed(format!("failed to decode transaction: {}", e)))
+}
+
+// Check a single transaction in isolation, deriving its fee, weight and sigop
+// cost from the hex and prevouts
+fn check_transaction(tx_data: &MempoolTransaction) -> Result<VerifiedTransaction, Verdict> {
+    let tx = decode(tx_data)?;
+
+    let computed = tx.compute_txid();
+    if computed.to_string() != tx_data.txid {
+        return Err(Verdict::TxidMismatch {
+            declared: tx_data.txid.clone(),
+            computed,
+        });
+    }
+
+    if tx.input.is_empty() || tx.output.is_empty() {
+        return Err(Verdict::Malformed("no inputs or no outputs".to_string()));
+    }
+    if tx.is_coinbase() {
+        return Err(Verdict::Malformed("coinbase outside a block".to_string()));
+    }
+
+    let mut outpoints = HashSet::new();
+    if !tx
+        .input
+        .iter()
+        .all(|input| outpoints.insert(input.previous_output))
+    {
+        return Err(Verdict::Malformed("duplicate inputs".to_string()));
+    }
+
+    let prevouts = prevouts(&tx, tx_data)?;
+
+    for (index, (output, vout)) in tx.output.iter().zip(&tx_data.vout).enumerate() {
+        if output.value.to_sat() != vout.value
+            || output.script_pubkey.to_hex_string() != vout.scriptpubkey
+        {
+            return Err(Verdict::Malformed(format!(
+                "output {} disagrees with the JSON vout",
+                index
+            )));
+        }
+    }
+    if tx.output.len() != tx_data.vout.len() {
+        return Err(Verdict::Malformed("vout count mismatch".to_string()));
+    }
+
+    let input_value = prevouts
+        .iter()
+        .try_fold(Amount::ZERO, |total, prevout| {
+            total.checked_add(prevout.value)
+        })
+        .ok_or_else(|| Verdict::Malformed("input value overflow".to_string()))?;
+    let output_value = tx
+        .output
+        .iter()
+        .try_fold(Amount::ZERO, |total, output| {
+            total.checked_add(output.value)
+        })
+        .filter(|total| *total <= Amount::MAX_MONEY)
+        .ok_or_else(|| Verdict::Malformed("output value out of range".to_string()))?;
+    if output_value > input_value {
+        return Err(Verdict::InsufficientInputValue {
+            input_value: input_value.to_sat(),
+            output_value: output_value.to_sat(),
+        });
+    }
+
+    // Weight per BIP141: base size times three plus total size
+    let fee = (input_value - output_value).to_sat();
+    let weight = (tx.base_size() * 3 + tx.total_size()) as u64;
+    let mut discrepancies = Vec::new();
+    if fee != tx_data.fee {
+        discrepancies.push(Discrepancy::Fee {
+            declared: tx_data.fee,
+            computed: fee,
+        });
+    }
+    if weight != tx_data.weight as u64 {
+        discrepancies.push(Discrepancy::Weight {
+            declared: tx_data.weight,
+            computed: weight,
+        });
+    }
+
+    let results = interpreter::verify_transaction(&tx, &prevouts);
+    if let Some((input, error)) = results
+        .into_iter()
+        .enumerate()
+        .find_map(|(input, result)| result.err().map(|error| (input, error)))
+    {
+        return Err(Verdict::BadSignature { input, error });
+    }
+
+    let sigop_cost = sigops::transaction_sigop_cost(&tx, &prevouts);
+    let policy_violations = policy::check_standard(&tx, &prevouts);
+    Ok(VerifiedTransaction {
+        tx,
+        fee,
+        weight,
+        sigop_cost,
+        discrepancies,
+        policy_violations,
+    })
+}
+
+// Build the spent outputs from the JSON prevouts, checking they line up with
+// the decoded inputs
+fn prevouts(tx: &Transaction, tx_data: &MempoolTransaction) -> Result<Vec<TxOut>, Verdict> {
+    if tx.input.len() != tx_data.vin.len() {
+        return Err(Verdict::Malformed("vin count mismatch".to_string()));
+    }
+
+    let mut prevouts = Vec::with_capacity(tx.input.len());
+    for (index, (input, vin)) in tx.input.iter().zip(&tx_data.vin).enumerate() {
+        if input.previous_output.txid.to_string() != vin.txid
+            || input.previous_output.vout != vin.vout
+        {
+            return Err(Verdict::Malformed(format!(
+                "input {} outpoint disagrees with the JSON vin",
+                index
+            )));
+        }
+        let prevout = vin
+            .prevout
+            .as_ref()
+            .ok_or_else(|| Verdict::Malformed(format!("input {} has no prevout", index)))?;
+        let script_pubkey = ScriptBuf::from_hex(&prevout.scriptpubkey)
+            .map_err(|e| Verdict::Malformed(format!("input {} prevout script: {}", index, e)))?;
+        prevouts.push(TxOut {
+            value: Amount::from_sat(prevout.value),
+            script_pubkey,
+        });
+    }
+
+    Ok(prevouts)
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoincore_rpc::bitcoin::{
+        Sequence, TxIn, Witness, absolute::LockTime, hashes::Hash, transaction,
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    fn op_true() -> ScriptBuf {
+        ScriptBuf::from_bytes(vec![0x51])
+    }
+
+    fn op_true_output(value: u64) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: ScriptBuf::new_p2wsh(&op_true().wscript_hash()),
+        }
+    }
+
+    fn confirmed(n: u8) -> OutPoint {
+        OutPoint::new(Txid::from_byte_array([n; 32]), 0)
+    }
+
+    // Spend OP_TRUE outputs into OP_TRUE outputs
+    fn spend(inputs: &[OutPoint], outputs: &[u64]) -> Transaction {
+        Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: inputs
+                .iter()
+                .map(|previous_output| TxIn {
+                    previous_output: *previous_output,
+                    sequence: Sequence::MAX,
+                    witness: Witness::from_slice(&[op_true().into_bytes()]),
+                    ..TxIn::default()
+                })
+                .collect(),
+            output: outputs.iter().map(|value| op_true_output(*value)).collect(),
+        }
+    }
+
+    // The mempool JSON for `tx`, whose inputs spend `prevouts`, with the fee
+    // and weight it really has
+    fn entry(tx: &Transaction, prevouts: &[TxOut]) -> MempoolTransaction {
+        let vin: Vec<_> = tx
+            .input
+            .iter()
+            .zip(prevouts)
+            .map(|(input, prevout)| {
+                json!({
+                    "txid": input.previous_output.txid.to_string(),
+                    "vout": input.previous_output.vout,
+                    "prevout": {
+                        "scriptpubkey": prevout.script_pubkey.to_hex_string(),
+                        "scriptpubkey_asm": "",
+                        "scriptpubkey_type": "v0_p2wsh",
+                        "value": prevout.value.to_sat(),
+                    },
+                    "sequence": input.sequence.0,
+                })
+            })
+            .collect();
+        let vout: Vec<_> = tx
+            .output
+            .iter()
+            .map(|output| {
+                json!({
+                    "scriptpubkey": output.script_pubkey.to_hex_string(),
+                    "scriptpubkey_asm": "",
+                    "scriptpubkey_type": "v0_p2wsh",
+                    "value": output.value.to_sat(),
+                })
+            })
+            .collect();
+        let input_value: u64 = prevouts.iter().map(|prevout| prevout.value.to_sat()).sum();
+        let output_value: u64 = tx.output.iter().map(|output| output.value.to_sat()).sum();
+        serde_json::from_value(json!({
+            "txid": tx.compute_txid().to_string(),
+            "version": tx.version.0,
+            "locktime": 0,
+            "vin": vin,
+            "vout": vout,
+            "size": tx.total_size(),
+            "weight": tx.weight().to_wu(),
+            "fee": input_value.saturating_sub(output_value),
+            "hex": consensus::encode::serialize_hex(tx),
+        }))
+        .unwrap()
+    }
+
+    // A transaction spending `value` from a confirmed OP_TRUE output
+    fn confirmed_spend(n: u8, value: u64, outputs: &[u64]) -> (Transaction, MempoolTransaction) {
+        let tx = spend(&[confirmed(n)], outputs);
+        let tx_data = entry(&tx, &[op_true_output(value)]);
+        (tx, tx_data)
+    }
+
+    fn child_of(parent: &Transaction, outputs: &[u64]) -> (Transaction, MempoolTransaction) {
+        let tx = spend(&[OutPoint::new(parent.compute_txid(), 0)], outputs);
+        let tx_data = entry(&tx, &[parent.output[0].clone()]);
+        (tx, tx_data)
+    }
+
+    fn verdicts(txs: &[MempoolTransaction], require_standard: bool) -> Vec<Result<(), Verdict>> {
+        validate_transactions(txs, ConflictRule::FirstSeen, require_standard)
+            .into_iter()
+            .map(|result| result.map(|_| ()))
+            .collect()
+    }
+
+    #[test]
+    fn derives_fee_weight_and_sigops() {
+        let (tx, tx_data) = confirmed_spend(1, 100_000, &[90_000]);
+        let results = validate_transactions(&[tx_data], ConflictRule::FirstSeen, true);
+        let verified = results[0].as_ref().unwrap();
+        assert_eq!(verified.tx, tx);
+        assert_eq!(verified.fee, 10_000);
+        assert_eq!(verified.weight, tx.weight().to_wu());
+        assert_eq!(verified.sigop_cost, 0);
+        assert_eq!(verified.discrepancies, vec![]);
+        assert_eq!(verified.policy_violations, vec![]);
+    }
+
+    #[test]
+    fn flags_declared_fee_and_weight_that_disagree() {
+        let (tx, mut tx_data) = confirmed_spend(1, 100_000, &[90_000]);
+        tx_data.fee = 9_000;
+        tx_data.weight = 1;
+
+        let results = validate_transactions(&[tx_data], ConflictRule::FirstSeen, false);
+        let verified = results[0].as_ref().unwrap();
+        // The computed values are kept
+        assert_eq!(verified.fee, 10_000);
+        assert_eq!(
+            verified.discrepancies,
+            vec![
+                Discrepancy::Fee {
+                    declared: 9_000,
+                    computed: 10_000
+                },
+                Discrepancy::Weight {
+                    declared: 1,
+                    computed: tx.weight().to_wu()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_a_declared_txid_that_does_not_match() {
+        let (tx, mut tx_data) = confirmed_spend(1, 100_000, &[90_000]);
+        tx_data.txid = "00".repeat(32);
+
+        assert_eq!(
+            verdicts(&[tx_data], false),
+            vec![Err(Verdict::TxidMismatch {
+                declared: "00".repeat(32),
+                computed: tx.compute_txid(),
+            })]
+        );
+    }
+
+    #[test]
+    fn rejects_outputs_above_inputs() {
+        let (_, tx_data) = confirmed_spend(1, 80_000, &[90_000]);
+
+        assert_eq!(
+            verdicts(&[tx_data], false),
+            vec![Err(Verdict::InsufficientInputValue {
+                input_value: 80_000,
+                output_value: 90_000,
+            })]
+        );
+    }
+
+    #[test]
+    fn rejects_a_failing_script() {
+        // The witness script leaves false on the stack
+        let op_false = ScriptBuf::from_bytes(vec![0x00]);
+        let mut tx = spend(&[confirmed(1)], &[90_000]);
+        tx.input[0].witness = Witness::from_slice(&[op_false.to_bytes()]);
+        let prevout = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2wsh(&op_false.wscript_hash()),
+        };
+
+        let results = verdicts(&[entry(&tx, &[prevout])], false);
+        assert!(
+            matches!(results[0], Err(Verdict::BadSignature { input: 0, .. })),
+            "{:?}",
+            results
+        );
+    }
+
+    #[test]
+    fn rejects_non_standard_only_when_required() {
+        let mut tx = spend(&[confirmed(1)], &[90_000]);
+        tx.version = transaction::Version(3);
+        let tx_data = entry(&tx, &[op_true_output(100_000)]);
+
+        assert_eq!(
+            verdicts(std::slice::from_ref(&tx_data), false),
+            vec![Ok(())]
+        );
+        assert_eq!(
+            verdicts(&[tx_data], true),
+            vec![Err(Verdict::NonStandard(PolicyViolation::Version(3)))]
+        );
+    }
+
+    #[test]
+    fn double_spend_loser_and_its_descendants_are_rejected() {
+        let (first, first_data) = confirmed_spend(1, 100_000, &[90_000]);
+        let (second, second_data) = confirmed_spend(1, 100_000, &[80_000]);
+        let (_, child_data) = child_of(&second, &[70_000]);
+        let (_, first_child_data) = child_of(&first, &[80_000]);
+
+        assert_eq!(
+            verdicts(
+                &[first_data, second_data, child_data, first_child_data],
+                false
+            ),
+            vec![
+                Ok(()),
+                Err(Verdict::DoubleSpend {
+                    outpoint: confirmed(1),
+                    spent_by: first.compute_txid(),
+                }),
+                Err(Verdict::InvalidParent(second.compute_txid())),
+                Ok(()),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejections_reach_every_descendant() {
+        let (parent, parent_data) = confirmed_spend(1, 80_000, &[90_000]);
+        let (child, child_data) = child_of(&parent, &[85_000]);
+        let (_, grandchild_data) = child_of(&child, &[80_000]);
+
+        // Listed before their ancestors, so the rejection needs more than one pass
+        assert_eq!(
+            verdicts(&[grandchild_data, child_data, parent_data], false),
+            vec![
+                Err(Verdict::InvalidParent(child.compute_txid())),
+                Err(Verdict::InvalidParent(parent.compute_txid())),
+                Err(Verdict::InsufficientInputValue {
+                    input_value: 80_000,
+                    output_value: 90_000,
+                }),
+            ]
+        );
+    }
+}